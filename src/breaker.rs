@@ -0,0 +1,231 @@
+use log::{info, warn};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::AudioError;
+
+/// Window over which the upstream error rate is measured.
+const WINDOW: Duration = Duration::from_secs(60);
+
+enum State {
+    Closed,
+    Open { until: Instant },
+    HalfOpen { probe_started: Instant },
+}
+
+struct Inner {
+    state: State,
+    outcomes: VecDeque<(Instant, bool)>,
+}
+
+/// Circuit breaker over every upstream call (transcription, chat and TTS,
+/// streamed or not).
+///
+/// Once the rolling upstream error rate crosses the threshold the breaker
+/// opens and calls are rejected until the cooldown passes. A single probe
+/// call is then let through; its outcome closes or re-opens the breaker.
+pub struct CircuitBreaker {
+    enabled: bool,
+    error_rate: f64,
+    min_requests: usize,
+    cooldown: Duration,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    fn from_env() -> Self {
+        let enabled = crate::env_flag("CIRCUIT_BREAKER");
+        let error_rate = std::env::var("CIRCUIT_BREAKER_ERROR_RATE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0.5);
+        let min_requests = std::env::var("CIRCUIT_BREAKER_MIN_REQUESTS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(10);
+        let cooldown_secs = std::env::var("CIRCUIT_BREAKER_COOLDOWN_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(30);
+
+        if enabled {
+            info!(
+                "Circuit breaker enabled: error_rate={}, min_requests={}, cooldown={}s",
+                error_rate, min_requests, cooldown_secs
+            );
+        }
+
+        Self::new(enabled, error_rate, min_requests, Duration::from_secs(cooldown_secs))
+    }
+
+    fn new(enabled: bool, error_rate: f64, min_requests: usize, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            enabled,
+            error_rate,
+            min_requests,
+            cooldown,
+            inner: Mutex::new(Inner {
+                state: State::Closed,
+                outcomes: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Runs one upstream call: fails fast with `AudioError::Unavailable` while
+    /// the breaker is open, otherwise records whether the call failed upstream.
+    /// Rejections of our own input don't count against the upstream.
+    pub async fn guard<T>(&self, call: impl Future<Output = Result<T, AudioError>>) -> Result<T, AudioError> {
+        self.check()
+            .map_err(|retry_after| AudioError::Unavailable { retry_after })?;
+        let result = call.await;
+        self.record(!matches!(
+            result,
+            Err(AudioError::OpenAI(_))
+                | Err(AudioError::Overloaded(_))
+                | Err(AudioError::RateLimited { .. })
+                | Err(AudioError::Timeout(_))
+                | Err(AudioError::Http(_))
+        ));
+        result
+    }
+
+    /// Returns `Err(retry_after)` when the request should be shed.
+    pub fn check(&self) -> Result<(), Duration> {
+        if !self.enabled {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            State::Closed => Ok(()),
+            State::Open { until } if now < until => Err(until - now),
+            State::Open { .. } => {
+                info!("Circuit breaker half-open, letting a probe request through");
+                inner.state = State::HalfOpen { probe_started: now };
+                Ok(())
+            }
+            // A probe that never reported back shouldn't wedge the breaker
+            State::HalfOpen { probe_started } if now - probe_started >= self.cooldown => {
+                inner.state = State::HalfOpen { probe_started: now };
+                Ok(())
+            }
+            State::HalfOpen { .. } => Err(self.cooldown),
+        }
+    }
+
+    /// How much longer calls will be shed for, without taking the half-open
+    /// probe slot the way `check` does, so handlers can refuse work up front.
+    pub fn shedding(&self) -> Option<Duration> {
+        if !self.enabled {
+            return None;
+        }
+        let now = Instant::now();
+        match self.inner.lock().unwrap().state {
+            State::Open { until } if now < until => Some(until - now),
+            _ => None,
+        }
+    }
+
+    /// Records whether a request that passed `check` hit an upstream failure.
+    pub fn record(&self, success: bool) {
+        if !self.enabled {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+
+        if let State::HalfOpen { .. } = inner.state {
+            inner.outcomes.clear();
+            if success {
+                info!("Circuit breaker probe succeeded, closing");
+                inner.state = State::Closed;
+            } else {
+                warn!("Circuit breaker probe failed, re-opening for {:?}", self.cooldown);
+                inner.state = State::Open { until: now + self.cooldown };
+            }
+            return;
+        }
+
+        inner.outcomes.push_back((now, success));
+        while let Some(&(at, _)) = inner.outcomes.front() {
+            if now - at > WINDOW {
+                inner.outcomes.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        let total = inner.outcomes.len();
+        let failures = inner.outcomes.iter().filter(|(_, ok)| !ok).count();
+        if total >= self.min_requests && failures as f64 / total as f64 >= self.error_rate {
+            warn!(
+                "Upstream error rate {}/{} over threshold, opening circuit breaker for {:?}",
+                failures, total, self.cooldown
+            );
+            inner.outcomes.clear();
+            inner.state = State::Open { until: now + self.cooldown };
+        }
+    }
+}
+
+pub fn breaker() -> &'static CircuitBreaker {
+    static BREAKER: OnceLock<CircuitBreaker> = OnceLock::new();
+    BREAKER.get_or_init(CircuitBreaker::from_env)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn fail() -> Result<(), AudioError> {
+        Err(AudioError::Overloaded("busy".to_string()))
+    }
+
+    #[tokio::test]
+    async fn opens_after_upstream_failures_and_sheds_calls() {
+        let breaker = CircuitBreaker::new(true, 0.5, 2, Duration::from_secs(30));
+        assert!(breaker.guard(fail()).await.is_err());
+        assert!(breaker.guard(fail()).await.is_err());
+
+        assert!(breaker.shedding().is_some());
+        let shed = breaker.guard(async { Ok::<_, AudioError>(()) }).await;
+        assert!(matches!(shed, Err(AudioError::Unavailable { retry_after }) if retry_after <= Duration::from_secs(30)));
+    }
+
+    #[tokio::test]
+    async fn rejected_input_does_not_open_the_breaker() {
+        let breaker = CircuitBreaker::new(true, 0.5, 2, Duration::from_secs(30));
+        for _ in 0..3 {
+            let result = breaker
+                .guard(async { Err::<(), _>(AudioError::Rejected("bad input".to_string())) })
+                .await;
+            assert!(matches!(result, Err(AudioError::Rejected(_))));
+        }
+        assert!(breaker.check().is_ok());
+    }
+
+    #[tokio::test]
+    async fn probe_success_closes_the_breaker() {
+        let breaker = CircuitBreaker::new(true, 0.5, 1, Duration::from_millis(10));
+        assert!(breaker.guard(fail()).await.is_err());
+        assert!(breaker.check().is_err());
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(breaker.shedding(), None);
+        assert!(breaker.guard(async { Ok::<_, AudioError>(()) }).await.is_ok());
+        assert!(breaker.check().is_ok());
+    }
+
+    #[tokio::test]
+    async fn disabled_breaker_never_sheds() {
+        let breaker = CircuitBreaker::new(false, 0.5, 1, Duration::from_secs(30));
+        for _ in 0..3 {
+            assert!(breaker.guard(fail()).await.is_err());
+        }
+        assert!(breaker.guard(async { Ok::<_, AudioError>(()) }).await.is_ok());
+    }
+}
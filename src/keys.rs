@@ -0,0 +1,169 @@
+use log::{error, info, warn};
+use reqwest::{Client, StatusCode};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::AudioError;
+
+/// How long a key that failed authentication is kept out of rotation.
+const AUTH_FAILURE_COOLDOWN: Duration = Duration::from_secs(300);
+/// How long a rate-limited key is kept out of rotation.
+const RATE_LIMIT_COOLDOWN: Duration = Duration::from_secs(30);
+
+struct PooledKey {
+    value: String,
+    requests: AtomicU64,
+    failures: AtomicU64,
+    sidelined_until: Mutex<Option<Instant>>,
+}
+
+impl PooledKey {
+    fn is_available(&self, now: Instant) -> bool {
+        match *self.sidelined_until.lock().unwrap() {
+            Some(until) => now >= until,
+            None => true,
+        }
+    }
+}
+
+/// Round-robin pool over the configured OpenAI API keys.
+///
+/// Keys answering 401/403 or 429 are sidelined for a cooldown so traffic
+/// shifts to the healthy ones.
+pub struct KeyPool {
+    keys: Vec<PooledKey>,
+    cursor: AtomicUsize,
+}
+
+impl KeyPool {
+    fn from_env() -> Self {
+        let raw = std::env::var("OPENAI_API_KEYS")
+            .or_else(|_| std::env::var("OPENAI_API_KEY"))
+            .unwrap_or_default();
+
+        let keys: Vec<PooledKey> = raw
+            .split(',')
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(|key| PooledKey {
+                value: key.to_string(),
+                requests: AtomicU64::new(0),
+                failures: AtomicU64::new(0),
+                sidelined_until: Mutex::new(None),
+            })
+            .collect();
+
+        info!("Loaded {} OpenAI API key(s)", keys.len());
+        KeyPool {
+            keys,
+            cursor: AtomicUsize::new(0),
+        }
+    }
+
+    /// Picks the next healthy key, returning its index and value.
+    ///
+    /// If every key is sidelined the least recently sidelined one is used
+    /// anyway rather than failing the request outright.
+    pub fn next_key(&self) -> Result<(usize, String), AudioError> {
+        if self.keys.is_empty() {
+            return Err(AudioError::OpenAI(
+                "Missing OPENAI_API_KEY or OPENAI_API_KEYS".to_string(),
+            ));
+        }
+
+        let now = Instant::now();
+        let start = self.cursor.fetch_add(1, Ordering::Relaxed);
+        let index = (0..self.keys.len())
+            .map(|offset| (start + offset) % self.keys.len())
+            .find(|&index| self.keys[index].is_available(now))
+            .unwrap_or_else(|| {
+                warn!("All OpenAI API keys are sidelined, using the next one anyway");
+                start % self.keys.len()
+            });
+
+        let key = &self.keys[index];
+        key.requests.fetch_add(1, Ordering::Relaxed);
+        Ok((index, key.value.clone()))
+    }
+
+    /// Records the upstream status for a key, sidelining it on auth or rate-limit errors.
+    pub fn report_status(&self, index: usize, status: StatusCode) {
+        let Some(key) = self.keys.get(index) else {
+            return;
+        };
+
+        let cooldown = match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => AUTH_FAILURE_COOLDOWN,
+            StatusCode::TOO_MANY_REQUESTS => RATE_LIMIT_COOLDOWN,
+            _ => return,
+        };
+
+        let failures = key.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        *key.sidelined_until.lock().unwrap() = Some(Instant::now() + cooldown);
+        warn!(
+            "Sidelining OpenAI API key #{} for {:?} after status {} (requests={}, failures={})",
+            index,
+            cooldown,
+            status,
+            key.requests.load(Ordering::Relaxed),
+            failures
+        );
+    }
+
+    /// Makes a cheap authenticated call with every key so a revoked or mistyped
+    /// key is reported at boot instead of on the first user request.
+    pub async fn verify_keys(&self, client: &Client) {
+        if self.keys.is_empty() {
+            error!("No OpenAI API key configured; set OPENAI_API_KEY or OPENAI_API_KEYS");
+            return;
+        }
+
+        for (index, key) in self.keys.iter().enumerate() {
+            let result = client
+                .get("https://api.openai.com/v1/models")
+                .header("Authorization", format!("Bearer {}", key.value))
+                .headers(crate::openai_extra_headers().clone())
+                .timeout(crate::request_timeout())
+                .send()
+                .await;
+
+            match result {
+                Ok(response) if response.status().is_success() => {
+                    info!("OpenAI API key #{} verified", index);
+                }
+                Ok(response) => {
+                    let status = response.status();
+                    if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+                        error!(
+                            "OpenAI API key #{} was rejected (status {}); requests using it will fail",
+                            index, status
+                        );
+                    } else {
+                        warn!("Could not verify OpenAI API key #{}: status {}", index, status);
+                    }
+                    self.report_status(index, status);
+                }
+                Err(e) => warn!("Could not reach OpenAI to verify API key #{}: {}", index, e),
+            }
+        }
+    }
+
+    /// Per-key request and failure counts, in configuration order.
+    pub fn usage(&self) -> Vec<(u64, u64)> {
+        self.keys
+            .iter()
+            .map(|key| {
+                (
+                    key.requests.load(Ordering::Relaxed),
+                    key.failures.load(Ordering::Relaxed),
+                )
+            })
+            .collect()
+    }
+}
+
+pub fn key_pool() -> &'static KeyPool {
+    static POOL: OnceLock<KeyPool> = OnceLock::new();
+    POOL.get_or_init(KeyPool::from_env)
+}
@@ -0,0 +1,158 @@
+use futures_util::future::BoxFuture;
+use futures_util::StreamExt;
+use log::error;
+use reqwest::Client;
+use serde_json::json;
+use std::sync::OnceLock;
+
+use crate::breaker::breaker;
+use crate::{
+    chat_messages, key_pool, openai_extra_headers, read_chat_stream, request_timeout, retry_after, upstream_error,
+    AudioError,
+};
+
+/// Chat backends selectable with `LLM_PROVIDER`.
+pub const PROVIDERS: [&str; 1] = ["openai"];
+
+/// One chat completion, independent of the backend that serves it.
+pub struct ChatRequest<'a> {
+    pub model: &'a str,
+    pub system: &'a str,
+    /// Turns placed between the system prompt and the user turn, e.g. few-shot examples.
+    pub prior: &'a [serde_json::Value],
+    pub user: &'a str,
+    pub temperature: f32,
+    pub stop: &'a [String],
+}
+
+/// A backend that turns a chat request into a reply.
+pub trait LlmProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn generate<'a>(&'a self, request: ChatRequest<'a>) -> BoxFuture<'a, Result<String, AudioError>>;
+
+    /// Like `generate`, but hands each piece of the reply to `on_delta` as it
+    /// arrives. `on_delta` returns false to abandon the stream, e.g. when the
+    /// client is gone; the text received so far is still returned.
+    fn stream<'a>(
+        &'a self,
+        request: ChatRequest<'a>,
+        on_delta: &'a mut (dyn FnMut(&str) -> bool + Send),
+    ) -> BoxFuture<'a, Result<String, AudioError>>;
+}
+
+/// OpenAI's chat completions endpoint.
+pub struct OpenAi {
+    client: Client,
+}
+
+impl LlmProvider for OpenAi {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    fn generate<'a>(&'a self, request: ChatRequest<'a>) -> BoxFuture<'a, Result<String, AudioError>> {
+        Box::pin(breaker().guard(async move {
+            let response = self.send(&request, false).await?;
+            let json: serde_json::Value = response.json().await.map_err(AudioError::from)?;
+            let response_text = json["choices"][0]["message"]["content"]
+                .as_str()
+                .ok_or_else(|| AudioError::OpenAI("No response text in Chat API".to_string()))?
+                .to_string();
+
+            Ok(response_text)
+        }))
+    }
+
+    fn stream<'a>(
+        &'a self,
+        request: ChatRequest<'a>,
+        on_delta: &'a mut (dyn FnMut(&str) -> bool + Send),
+    ) -> BoxFuture<'a, Result<String, AudioError>> {
+        Box::pin(breaker().guard(async move {
+            let response = self.send(&request, true).await?;
+            let chunks = response.bytes_stream().map(|chunk| chunk.map_err(AudioError::from));
+            read_chat_stream(chunks, on_delta).await
+        }))
+    }
+}
+
+impl OpenAi {
+    /// Posts the completion request and fails on a non-success status.
+    async fn send(&self, request: &ChatRequest<'_>, stream: bool) -> Result<reqwest::Response, AudioError> {
+        let (key_index, api_key) = key_pool().next_key()?;
+
+        let mut body = json!({
+            "model": request.model,
+            "messages": chat_messages(request.system, request.prior, request.user),
+            "temperature": request.temperature
+        });
+        if !request.stop.is_empty() {
+            body["stop"] = json!(request.stop);
+        }
+        if stream {
+            body["stream"] = json!(true);
+        }
+
+        let mut builder = self
+            .client
+            .post("https://api.openai.com/v1/chat/completions")
+            .header("Authorization", format!("Bearer {}", api_key))
+            .headers(openai_extra_headers().clone())
+            .json(&body);
+        // A streamed reply runs as long as the model keeps talking
+        if !stream {
+            builder = builder.timeout(request_timeout());
+        }
+        let response = builder.send().await.map_err(AudioError::from)?;
+
+        let status = response.status();
+        key_pool().report_status(key_index, status);
+        if !status.is_success() {
+            let retry_after = retry_after(response.headers());
+            let error_text = response.text().await.unwrap_or_default();
+            error!("Chat API failed: status={}, stream={}, error={}", status, stream, error_text);
+            return Err(upstream_error(status, retry_after, format!("Chat API failed: {}", error_text)));
+        }
+        Ok(response)
+    }
+}
+
+fn from_env(client: &Client) -> Result<Box<dyn LlmProvider>, String> {
+    match std::env::var("LLM_PROVIDER").as_deref().unwrap_or("openai") {
+        "openai" => Ok(Box::new(OpenAi { client: client.clone() })),
+        other => Err(format!(
+            "unknown LLM_PROVIDER '{}', expected one of {}",
+            other,
+            PROVIDERS.join(", ")
+        )),
+    }
+}
+
+static PROVIDER: OnceLock<Box<dyn LlmProvider>> = OnceLock::new();
+
+/// Picks the chat backend from `LLM_PROVIDER` (default `openai`), sending
+/// through the shared `client`. Run once at startup so a typo fails there
+/// rather than per request.
+pub fn init_provider(client: &Client) -> Result<(), String> {
+    let provider = from_env(client)?;
+    PROVIDER.set(provider).map_err(|_| "LLM provider is already set".to_string())
+}
+
+/// The chat backend picked by `init_provider`.
+pub fn llm_provider() -> &'static dyn LlmProvider {
+    PROVIDER.get().expect("llm::init_provider runs at startup").as_ref()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn provider_is_picked_once() {
+        let client = Client::new();
+        init_provider(&client).unwrap();
+        assert_eq!(llm_provider().name(), "openai");
+        assert!(init_provider(&client).is_err());
+    }
+}
@@ -1,19 +1,60 @@
+mod breaker;
+mod keys;
+mod llm;
+mod sessions;
+mod stats;
+mod transcribe;
+mod tts;
+mod vad;
+
 use actix_cors::Cors;
+use actix_web::dev::Service as _;
 use actix_web::{
-    get, post, web, App, HttpResponse, HttpServer, Responder, Result as ActixResult,
+    delete, get, post, route, web, App, HttpResponse, HttpServer, Responder, ResponseError, Result as ActixResult,
 };
 use base64::{engine::general_purpose, Engine as _};
 use dotenvy::dotenv;
+use futures_util::StreamExt;
 use handlebars::Handlebars;
-use log::{error, info, debug};
+use log::{error, info, debug, warn};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::borrow::Cow;
 use std::io;
-use std::process::{Command, Stdio};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Instant;
 use thiserror::Error;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use reqwest::Client;
 use tokio::io::AsyncWriteExt;
 
+use breaker::breaker;
+use keys::key_pool;
+use llm::{llm_provider, ChatRequest};
+use sessions::session_store;
+use stats::stats;
+use transcribe::{transcriber, TranscriptionRequest};
+use tts::{tts_provider, AudioFormat, SpeechRequest, TtsProvider};
+
+tokio::task_local! {
+    /// Level the request being handled logs its verbose lines at, or `None`
+    /// when it was not picked for verbose logging.
+    static DEBUG_LOG_LEVEL: Option<log::Level>;
+}
+
+/// `debug!` for the verbose, per-request call sites (transcripts, prompts,
+/// payload sizes). Only emitted for requests picked by `request_log_level`,
+/// and at info level for requests with an authorized `X-Debug` header.
+macro_rules! verbose_debug {
+    ($($arg:tt)*) => {
+        if let Some(level) = debug_log_level() {
+            log::log!(level, $($arg)*);
+        }
+    };
+}
+
 #[derive(Error, Debug)]
 enum AudioError {
     #[error("IO error: {0}")]
@@ -24,508 +65,6328 @@ enum AudioError {
     FFmpeg(String),
     #[error("Invalid language")]
     InvalidLanguage,
+    #[error("Model not allowed: {0}")]
+    ModelNotAllowed(String),
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("Payload too large: {0}")]
+    PayloadTooLarge(String),
+    #[error("Incomplete upload: {0}")]
+    IncompleteUpload(String),
+    #[error("Invalid audio: {0}")]
+    InvalidAudio(String),
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+    #[error("Not found: {0}")]
+    NotFound(String),
+    #[error("Server is shutting down")]
+    ShuttingDown,
+    #[error("Upstream is unavailable, try again later")]
+    Unavailable { retry_after: std::time::Duration },
+    #[error("Session store error: {0}")]
+    SessionStore(String),
+    #[error("Session limit reached: {0}")]
+    SessionLimit(String),
+    #[error("Session is busy: {0}")]
+    SessionBusy(String),
+    #[error("Too many sessions: {0}")]
+    TooManySessions(String),
     #[error("OpenAI API error: {0}")]
     OpenAI(String),
+    #[error("OpenAI is overloaded: {0}")]
+    Overloaded(String),
+    #[error("OpenAI rate limit hit: {message}")]
+    RateLimited {
+        message: String,
+        retry_after: Option<std::time::Duration>,
+    },
+    #[error("OpenAI rejected the request: {0}")]
+    Rejected(String),
+    #[error("OpenAI request timed out: {0}")]
+    Timeout(String),
     #[error("HTTP error: {0}")]
-    Http(#[from] reqwest::Error),
+    Http(reqwest::Error),
 }
 
-#[derive(Deserialize)]
-struct AudioRequest {
-    audio: String,
-    language: String,
-    genz_mode: bool,
-    sarcastic_mode: bool,
-    shenanigan_mode: bool,
-    seductive_mode: bool,
+impl From<reqwest::Error> for AudioError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            AudioError::Timeout(e.to_string())
+        } else {
+            AudioError::Http(e)
+        }
+    }
 }
 
-#[derive(Serialize)]
-struct AudioResponse {
-    audio: String,
-    transcript: String,
+/// A supported language. Everything that varies by language is keyed on
+/// this, so a new language only compiles once every table covers it.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Language {
+    En,
+    Hi,
+    Pa,
+    Es,
+    Fr,
+    De,
 }
 
-fn convert_audio_to_pcm16_24khz(audio_base64: &str) -> Result<Vec<u8>, AudioError> {
-    debug!("Converting WebM to PCM in memory");
-    let audio_bytes = general_purpose::STANDARD
-        .decode(audio_base64)
-        .map_err(|e| {
-            error!("Base64 decode failed: {}", e);
-            AudioError::Base64(e)
-        })?;
+impl Language {
+    const ALL: [Language; 6] = [
+        Language::En,
+        Language::Hi,
+        Language::Pa,
+        Language::Es,
+        Language::Fr,
+        Language::De,
+    ];
 
-    let mut ffmpeg = Command::new("ffmpeg")
-        .args([
-            "-i", "pipe:0", // Read from stdin
-            "-ac", "1",
-            "-ar", "24000",
-            "-acodec", "pcm_s16le",
-            "-f", "wav",
-            "-y",
-            "pipe:1", // Output to stdout
-        ])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .map_err(|e| {
-            error!("FFmpeg command failed: {}", e);
-            AudioError::FFmpeg(e.to_string())
-        })?;
+    fn code(self) -> &'static str {
+        match self {
+            Language::En => "en",
+            Language::Hi => "hi",
+            Language::Pa => "pa",
+            Language::Es => "es",
+            Language::Fr => "fr",
+            Language::De => "de",
+        }
+    }
 
-    // Write input to FFmpeg stdin
-    if let Some(mut stdin) = ffmpeg.stdin.take() {
-        std::io::Write::write_all(&mut stdin, &audio_bytes).map_err(|e| {
-            error!("Failed to write to FFmpeg stdin: {}", e);
-            AudioError::Io(e)
-        })?;
+    fn from_code(code: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|language| language.code() == code)
     }
 
-    let output = ffmpeg.wait_with_output().map_err(|e| {
-        error!("FFmpeg failed to complete: {}", e);
-        AudioError::FFmpeg(e.to_string())
-    })?;
+    /// Code sent as Whisper's `language` hint.
+    fn whisper_code(self) -> &'static str {
+        self.code()
+    }
 
-    let ffmpeg_stderr = String::from_utf8_lossy(&output.stderr);
-    debug!("FFmpeg PCM stderr: {}", ffmpeg_stderr);
+    /// Maps the language Whisper reports in verbose output, a full name
+    /// such as `"hindi"`, falling back to a code.
+    fn from_whisper(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "english" | "en" => Some(Language::En),
+            "hindi" | "hi" => Some(Language::Hi),
+            "punjabi" | "panjabi" | "pa" => Some(Language::Pa),
+            "spanish" | "es" => Some(Language::Es),
+            "french" | "fr" => Some(Language::Fr),
+            "german" | "de" => Some(Language::De),
+            _ => None,
+        }
+    }
 
-    if !output.status.success() {
-        error!("FFmpeg PCM failed: {}", ffmpeg_stderr);
-        return Err(AudioError::FFmpeg(ffmpeg_stderr.to_string()));
+    /// OpenAI voice used when the request doesn't pick one.
+    fn tts_voice(self) -> &'static str {
+        match self {
+            Language::En => "alloy",
+            Language::Hi | Language::Pa | Language::Es => "nova",
+            Language::Fr => "shimmer",
+            Language::De => "onyx",
+        }
     }
 
-    debug!("PCM conversion successful, WAV size: {} bytes", output.stdout.len());
-    Ok(output.stdout)
+    /// Direction clients should render reply text in; none of the supported
+    /// languages are right-to-left yet.
+    fn text_direction(self) -> &'static str {
+        "ltr"
+    }
 }
 
-async fn transcribe_audio(wav_bytes: &[u8], language: &str) -> Result<String, AudioError> {
-    debug!("Transcribing audio with Whisper");
-    let client = Client::new();
-    let api_key = std::env::var("OPENAI_API_KEY")
-        .map_err(|e| AudioError::OpenAI(format!("Missing OPENAI_API_KEY: {}", e)))?;
-
-    let language_code = match language {
-        "en" => "en",
-        "hi" => "hi",
-        "pa" => "pa",
-        _ => return Err(AudioError::InvalidLanguage),
-    };
-
-    let form = reqwest::multipart::Form::new()
-        .text("model", "whisper-1")
-        .text("language", language_code)
-        .part(
-            "file",
-            reqwest::multipart::Part::bytes(wav_bytes.to_vec())
-                .file_name("audio.wav")
-                .mime_str("audio/wav")
-                .map_err(|e| AudioError::OpenAI(e.to_string()))?,
-        );
-
-    let response = client
-        .post("https://api.openai.com/v1/audio/transcriptions")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .multipart(form)
-        .send()
-        .await
-        .map_err(|e| AudioError::Http(e))?;
-
-    let status = response.status();
-    if !status.is_success() {
-        let error_text = response.text().await.unwrap_or_default();
-        error!("Whisper API failed: status={}, error={}", status, error_text);
-        return Err(AudioError::OpenAI(format!("Whisper API failed: {}", error_text)));
+impl std::fmt::Display for Language {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.code())
     }
-
-    let json: serde_json::Value = response.json().await.map_err(|e| AudioError::Http(e))?;
-    let transcript = json["text"]
-        .as_str()
-        .ok_or_else(|| AudioError::OpenAI("No transcript in response".to_string()))?
-        .to_string();
-
-    debug!("Transcription successful: {}", transcript);
-    Ok(transcript)
 }
 
-async fn generate_therapist_response(
-    transcript: &str,
-    language: &str,
-    genz_mode: bool,
-    sarcastic_mode: bool,
-    shenanigan_mode: bool,
-    seductive_mode: bool,
-) -> Result<String, AudioError> {
-    debug!("Generating therapist response for transcript: {}", transcript);
-    let client = Client::new();
-    let api_key = std::env::var("OPENAI_API_KEY")
-        .map_err(|e| AudioError::OpenAI(format!("Missing OPENAI_API_KEY: {}", e)))?;
-
-    let instructions = get_language_instructions(
-        language,
-        genz_mode,
-        sarcastic_mode,
-        shenanigan_mode,
-        seductive_mode,
-    )?;
-
-    let response = client
-        .post("https://api.openai.com/v1/chat/completions")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .json(&json!({
-            "model": "gpt-4o-mini",
-            "messages": [
-                {"role": "system", "content": instructions},
-                {"role": "user", "content": transcript}
-            ],
-            "temperature": 0.7
-        }))
-        .send()
-        .await
-        .map_err(|e| AudioError::Http(e))?;
+/// Default cap on the base64 `audio` field, roughly 12 MiB once decoded.
+const DEFAULT_MAX_AUDIO_BASE64_CHARS: usize = 16 * 1024 * 1024;
 
-    let status = response.status();
-    if !status.is_success() {
-        let error_text = response.text().await.unwrap_or_default();
-        error!("Chat API failed: status={}, error={}", status, error_text);
-        return Err(AudioError::OpenAI(format!("Chat API failed: {}", error_text)));
-    }
+fn max_audio_base64_chars() -> usize {
+    std::env::var("MAX_AUDIO_BASE64_CHARS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_AUDIO_BASE64_CHARS)
+}
 
-    let json: serde_json::Value = response.json().await.map_err(|e| AudioError::Http(e))?;
-    let response_text = json["choices"][0]["message"]["content"]
-        .as_str()
-        .ok_or_else(|| AudioError::OpenAI("No response text in Chat API".to_string()))?
-        .to_string();
+/// Cap on decoded input audio from `MAX_AUDIO_BYTES`, by default the size the
+/// base64 cap allows.
+fn max_audio_bytes() -> usize {
+    std::env::var("MAX_AUDIO_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_AUDIO_BASE64_CHARS / 4 * 3)
+}
 
-    debug!("Therapist response: {}", response_text);
-    Ok(response_text)
+/// Longest input clip accepted, from `MAX_AUDIO_DURATION_SECS` (default 600).
+/// Compressed audio can be long while still small, so bytes alone don't
+/// bound transcription cost.
+fn max_audio_duration_secs() -> f64 {
+    std::env::var("MAX_AUDIO_DURATION_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(600.0)
 }
 
-async fn text_to_speech(text: &str, language: &str) -> Result<Vec<u8>, AudioError> {
-    debug!("Converting text to speech with TTS-1");
-    let client = Client::new();
-    let api_key = std::env::var("OPENAI_API_KEY")
-        .map_err(|e| AudioError::OpenAI(format!("Missing OPENAI_API_KEY: {}", e)))?;
-
-    let voice = match language {
-        "en" => "alloy",
-        "hi" => "nova",
-        "pa" => "nova",
-        _ => return Err(AudioError::InvalidLanguage),
-    };
-
-    let response = client
-        .post("https://api.openai.com/v1/audio/speech")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .json(&json!({
-            "model": "tts-1",
-            "input": text,
-            "voice": voice,
-            "response_format": "mp3"
-        }))
-        .send()
-        .await
-        .map_err(|e| AudioError::Http(e))?;
+/// Total characters sent to the TTS provider, which bills per character.
+static TTS_CHARACTERS_TOTAL: AtomicU64 = AtomicU64::new(0);
 
-    let status = response.status();
-    if !status.is_success() {
-        let error_text = response.text().await.unwrap_or_default();
-        error!("TTS API failed: status={}, error={}", status, error_text);
-        return Err(AudioError::OpenAI(format!("TTS API failed: {}", error_text)));
-    }
+/// Set once a shutdown signal arrives; new work is refused from then on.
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
 
-    let mp3_bytes = response.bytes().await.map_err(|e| AudioError::Http(e))?.to_vec();
-    debug!("TTS successful, MP3 size: {} bytes", mp3_bytes.len());
-    Ok(mp3_bytes)
+fn shutting_down_response() -> HttpResponse {
+    AudioError::ShuttingDown.error_response()
 }
 
-fn convert_audio_to_mp3(wav_bytes: &[u8]) -> Result<Vec<u8>, AudioError> {
-    debug!("Converting WAV to MP3 in memory");
-    let mut ffmpeg = Command::new("ffmpeg")
-        .args([
-            "-i", "pipe:0", // Read from stdin
-            "-acodec", "mp3",
-            "-b:a", "128k",
-            "-ac", "1",
-            "-ar", "24000",
-            "-f", "mp3",
-            "-y",
-            "pipe:1", // Output to stdout
-        ])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .map_err(|e| {
-            error!("FFmpeg command failed: {}", e);
-            AudioError::FFmpeg(e.to_string())
-        })?;
+impl AudioError {
+    /// Stable, variant-level name used for error accounting.
+    fn kind(&self) -> &'static str {
+        match self {
+            AudioError::Io(_) => "io",
+            AudioError::Base64(_) => "base64",
+            AudioError::FFmpeg(_) => "ffmpeg",
+            AudioError::InvalidLanguage => "invalid_language",
+            AudioError::ModelNotAllowed(_) => "model_not_allowed",
+            AudioError::InvalidRequest(_) => "invalid_request",
+            AudioError::PayloadTooLarge(_) => "payload_too_large",
+            AudioError::IncompleteUpload(_) => "incomplete_upload",
+            AudioError::InvalidAudio(_) => "invalid_audio",
+            AudioError::Unauthorized(_) => "unauthorized",
+            AudioError::Forbidden(_) => "forbidden",
+            AudioError::NotFound(_) => "not_found",
+            AudioError::ShuttingDown => "shutting_down",
+            AudioError::Unavailable { .. } => "unavailable",
+            AudioError::SessionStore(_) => "session_store",
+            AudioError::SessionLimit(_) => "session_limit",
+            AudioError::SessionBusy(_) => "session_busy",
+            AudioError::TooManySessions(_) => "too_many_sessions",
+            AudioError::OpenAI(_) => "openai",
+            AudioError::Overloaded(_) => "overloaded",
+            AudioError::RateLimited { .. } => "rate_limited",
+            AudioError::Rejected(_) => "rejected",
+            AudioError::Timeout(_) => "timeout",
+            AudioError::Http(_) => "http",
+        }
+    }
 
-    // Write input to FFmpeg stdin
-    if let Some(mut stdin) = ffmpeg.stdin.take() {
-        std::io::Write::write_all(&mut stdin, wav_bytes).map_err(|e| {
-            error!("Failed to write to FFmpeg stdin: {}", e);
-            AudioError::Io(e)
-        })?;
+    /// Stable code clients see in error bodies. Upstream failures share one
+    /// code since callers can't act on which of them it was.
+    fn code(&self) -> &'static str {
+        match self {
+            AudioError::Io(_) | AudioError::FFmpeg(_) => "internal_error",
+            AudioError::Base64(_) => "invalid_base64",
+            AudioError::InvalidLanguage => "invalid_language",
+            AudioError::ModelNotAllowed(_) => "model_not_allowed",
+            AudioError::InvalidRequest(_) => "invalid_request",
+            AudioError::PayloadTooLarge(_) => "payload_too_large",
+            AudioError::IncompleteUpload(_) => "incomplete_upload",
+            AudioError::InvalidAudio(_) => "invalid_audio",
+            AudioError::Unauthorized(_) => "unauthorized",
+            AudioError::Forbidden(_) => "forbidden",
+            AudioError::NotFound(_) => "not_found",
+            AudioError::ShuttingDown => "shutting_down",
+            AudioError::Unavailable { .. } => "upstream_unavailable",
+            AudioError::SessionStore(_) => "session_store_error",
+            AudioError::SessionLimit(_) => "session_limit",
+            AudioError::SessionBusy(_) => "session_busy",
+            AudioError::TooManySessions(_) => "too_many_sessions",
+            AudioError::Overloaded(_) => "upstream_overloaded",
+            AudioError::RateLimited { .. } => "rate_limited",
+            AudioError::Timeout(_) => "upstream_timeout",
+            AudioError::OpenAI(_) | AudioError::Rejected(_) | AudioError::Http(_) => "upstream_error",
+        }
     }
 
-    let output = ffmpeg.wait_with_output().map_err(|e| {
-        error!("FFmpeg failed to complete: {}", e);
-        AudioError::FFmpeg(e.to_string())
-    })?;
+    /// Whether sending the same request again may succeed: transient
+    /// upstream failures and load shedding are, bad input and auth are not.
+    fn retryable(&self) -> bool {
+        match self {
+            AudioError::OpenAI(_)
+            | AudioError::Overloaded(_)
+            | AudioError::RateLimited { .. }
+            | AudioError::Timeout(_)
+            | AudioError::Http(_)
+            | AudioError::ShuttingDown
+            | AudioError::Unavailable { .. }
+            | AudioError::SessionStore(_)
+            | AudioError::SessionBusy(_)
+            | AudioError::TooManySessions(_) => true,
+            AudioError::Io(_)
+            | AudioError::Base64(_)
+            | AudioError::FFmpeg(_)
+            | AudioError::InvalidLanguage
+            | AudioError::ModelNotAllowed(_)
+            | AudioError::InvalidRequest(_)
+            | AudioError::PayloadTooLarge(_)
+            | AudioError::IncompleteUpload(_)
+            | AudioError::InvalidAudio(_)
+            | AudioError::Unauthorized(_)
+            | AudioError::Forbidden(_)
+            | AudioError::NotFound(_)
+            | AudioError::SessionLimit(_)
+            | AudioError::Rejected(_) => false,
+        }
+    }
 
-    let ffmpeg_stderr = String::from_utf8_lossy(&output.stderr);
-    debug!("FFmpeg MP3 stderr: {}", ffmpeg_stderr);
+    /// `code`, `retryable` and, when known, `retry_after_ms`, shared by HTTP
+    /// error bodies and the error events of the SSE and WebSocket paths.
+    fn details(&self) -> serde_json::Value {
+        let mut details = json!({
+            "code": self.code(),
+            "retryable": self.retryable(),
+        });
+        if let Some(retry_after) = self.retry_after() {
+            details["retry_after_ms"] = json!(retry_after.as_millis() as u64);
+        }
+        details
+    }
 
-    if !output.status.success() {
-        error!("FFmpeg MP3 failed: {}", ffmpeg_stderr);
-        return Err(AudioError::FFmpeg(ffmpeg_stderr.to_string()));
+    /// How long the client should wait before retrying, when known.
+    fn retry_after(&self) -> Option<std::time::Duration> {
+        match self {
+            AudioError::RateLimited { retry_after, .. } => *retry_after,
+            AudioError::Unavailable { retry_after } => Some(*retry_after),
+            _ => None,
+        }
+    }
+}
+
+/// Error bodies are `{"error": {"code": ..., "message": ..., "retryable": ...}}`,
+/// plus `retry_after_ms` when a wait is known.
+impl actix_web::ResponseError for AudioError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        use actix_web::http::StatusCode;
+        match self {
+            AudioError::Io(_) | AudioError::FFmpeg(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AudioError::Base64(_)
+            | AudioError::InvalidLanguage
+            | AudioError::ModelNotAllowed(_)
+            | AudioError::InvalidRequest(_)
+            | AudioError::IncompleteUpload(_)
+            | AudioError::InvalidAudio(_) => StatusCode::BAD_REQUEST,
+            AudioError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AudioError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AudioError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AudioError::NotFound(_) => StatusCode::NOT_FOUND,
+            AudioError::SessionBusy(_) => StatusCode::CONFLICT,
+            AudioError::ShuttingDown
+            | AudioError::Unavailable { .. }
+            | AudioError::Overloaded(_)
+            | AudioError::TooManySessions(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AudioError::SessionStore(_) => StatusCode::BAD_GATEWAY,
+            AudioError::RateLimited { .. } | AudioError::SessionLimit(_) => StatusCode::TOO_MANY_REQUESTS,
+            AudioError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            AudioError::OpenAI(_) | AudioError::Rejected(_) | AudioError::Http(_) => StatusCode::BAD_GATEWAY,
+        }
     }
 
-    debug!("MP3 conversion successful, MP3 size: {} bytes", output.stdout.len());
-    Ok(output.stdout)
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        // Pass OpenAI's backoff through so clients don't retry straight into the limit
+        if let Some(retry_after) = self.retry_after() {
+            response.insert_header(("Retry-After", retry_after.as_secs().max(1).to_string()));
+        }
+        match self {
+            AudioError::Unauthorized(_) => {
+                response.insert_header(("WWW-Authenticate", "Bearer"));
+            }
+            // Tells load balancers to stop reusing this connection while draining
+            AudioError::ShuttingDown => {
+                response.insert_header(("Connection", "close"));
+            }
+            _ => {}
+        }
+        let mut error = self.details();
+        error["message"] = json!(self.to_string());
+        response.json(json!({ "error": error }))
+    }
 }
 
-fn get_language_instructions(
-    language: &str,
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AudioRequest {
+    audio: String,
+    /// Filled from `Accept-Language` or `DEFAULT_LANGUAGE` when omitted;
+    /// with neither, the spoken language is detected from the audio.
+    #[serde(default)]
+    language: Option<Language>,
+    /// Overall tone of the reply; takes the place of the `*_mode` tone flags.
+    #[serde(default)]
+    tone: Option<Tone>,
+    /// Gen Z style, layered on top of any tone.
+    #[serde(default, alias = "genz")]
     genz_mode: bool,
+    // Tone flags from before `tone`, still accepted from older clients
+    #[serde(default)]
     sarcastic_mode: bool,
+    #[serde(default)]
     shenanigan_mode: bool,
+    #[serde(default)]
     seductive_mode: bool,
-) -> Result<String, AudioError> {
-    debug!("Generating instructions for language: {}, modes: genz={}, sarcastic={}, shenanigan={}, seductive={}", 
-        language, genz_mode, sarcastic_mode, shenanigan_mode, seductive_mode);
-
-    let shared_instructions = r#"You are Hearthly, a therapist who listens and responds with natural emotional intelligence, adjusting your responses based on the user’s emotional state. Speak like a skilled human therapist, always present and adaptive.
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    include_visemes: bool,
+    #[serde(default)]
+    include_converted_audio: bool,
+    #[serde(default)]
+    include_tts_usage: bool,
+    #[serde(default)]
+    stop: Option<Vec<String>>,
+    #[serde(default)]
+    include_timings: bool,
+    #[serde(default)]
+    include_turns: bool,
+    #[serde(default)]
+    include_ssml: bool,
+    #[serde(default)]
+    genz_transcription_hints: Option<bool>,
+    /// Continues the conversation held under this id; earlier turns are
+    /// sent to the chat model as context.
+    #[serde(default)]
+    session_id: Option<String>,
+    #[serde(default)]
+    formats: Option<Vec<String>>,
+    /// Overrides the language's default voice; one the TTS provider offers.
+    #[serde(default)]
+    voice: Option<String>,
+    /// Synthesize with `tts-1-hd`: clearer audio, but slower and twice the price.
+    #[serde(default)]
+    hd_audio: bool,
+    /// Chat sampling temperature, 0.0 to 2.0.
+    #[serde(default)]
+    temperature: Option<f32>,
+    /// Keeps this request's audio off disk: a multipart upload is buffered in
+    /// memory instead of a temp file. Must come before the `audio` part.
+    #[serde(default)]
+    no_disk: bool,
+    /// Keeps this turn out of the session history (and with it any summary)
+    /// and out of the `/stats` and TTS usage tallies.
+    #[serde(default)]
+    do_not_store: bool,
+}
 
-    BEHAVIOR:
-    - Mirror the user’s emotional tone.
-    - Offer space after questions or rants.
-    - Always stay human: raw, not clinical; unfiltered, not scripted.
-    "#;
+/// Whether genz slang is fed to Whisper as a hint. Only applies in genz mode;
+/// the request can override the `GENZ_TRANSCRIPTION_HINTS` default since the
+/// hint slightly biases transcription.
+fn genz_transcription_hints(req: &AudioRequest) -> bool {
+    req.genz_mode
+        && req
+            .genz_transcription_hints
+            .unwrap_or_else(|| env_flag("GENZ_TRANSCRIPTION_HINTS"))
+}
 
-    let language_specific = match language {
-        "en" => r#"Respond in fluent English. Use culturally resonant phrases like "You're not alone" or "Let's figure this out together." Ensure tone feels natural in English."#,
-        "hi" => r#"Respond in fluent Hindi. Use culturally resonant phrases like "आप अकेले नहीं हैं" (You're not alone) or "चलो, इसे साथ में समझें" (Let's explore it together). Ensure tone feels natural in Hindi."#,
-        "pa" => r#"Respond in fluent Punjabi. Use culturally resonant phrases like "ਤੁਸੀਂ ਇਕੱਲੇ ਨਹੀਂ ਹੋ" (You're not alone) or "ਆਓ, ਇਸ ਨੂੰ ਮਿਲ ਕੇ ਸਮਝੀਏ" (Let's explore it together). Ensure tone feels natural in Punjabi."#,
-        _ => {
-            error!("Invalid language: {}", language);
-            return Err(AudioError::InvalidLanguage);
-        }
-    };
+/// Thresholds below which the input is judged too poor to reply to.
+///
+/// Both come from env (`RERECORD_MIN_CONFIDENCE`, `RERECORD_MIN_DURATION_MS`)
+/// and the check is off unless at least one is set.
+struct RerecordGate {
+    min_confidence: Option<f32>,
+    min_duration_ms: Option<u64>,
+}
 
-    let genz_instructions = match language {
-        "en" => r#"Incorporate Gen Z slang—casual, raw, and chaotic. Use terms like "lit," "vibes," "slay," "no cap," or "bet" naturally. Example: Instead of "You're not alone," say "You’re not out here solo, fam." Keep it real and trendy."#,
-        "hi" => r#"Use a Gen Z-inspired Hindi style with youthful, urban slang. Incorporate terms like "बॉस" (boss), "चिल" (chill), or "झक्कास" (awesome) naturally. Example: Instead of "आप अकेले नहीं हैं," say "तू अकेला नहीं है, ब्रो, हम हैं ना!" Keep it real and trendy."#,
-        "pa" => r#"Use a Gen Z-inspired Punjabi style with vibrant, chaotic slang. Incorporate terms like "ਪੰਚੋ" (pencho), "ਬੱਲੇ ਬੱਲੇ" (balle balle), "ਝਕਾਸ" (jhakaas), or "ਚਿੱਲ" (chill) naturally. Example: Instead of "ਤੁਸੀਂ ਇਕੱਲੇ ਨਹੀਂ ਹੋ," say "ਤੂੰ ਇਕੱਲਾ ਨੀ, ਯਾਰ, ਅਸੀਂ ਸਾਰੇ ਨਾਲ ਹਾਂ!" Keep it real and trendy."#,
-        _ => "",
-    };
+impl RerecordGate {
+    fn from_env() -> Option<Self> {
+        let min_confidence = std::env::var("RERECORD_MIN_CONFIDENCE")
+            .ok()
+            .and_then(|v| v.parse().ok());
+        let min_duration_ms = std::env::var("RERECORD_MIN_DURATION_MS")
+            .ok()
+            .and_then(|v| v.parse().ok());
+        (min_confidence.is_some() || min_duration_ms.is_some()).then_some(RerecordGate {
+            min_confidence,
+            min_duration_ms,
+        })
+    }
 
-    let base_mode = match language {
-        "en" => r#"Adopt a calm, warm, and grounding tone. Use compassionate and sincere phrasing, with patient and personal delivery like a fireside talk. Pacing is slow and spacious to allow reflection. Emotion is deep empathy and quiet strength. Example: "You're not alone" becomes "You’re not alone… I’m here with you." Adjust naturally: nurturing for pain, uplifting for hope, steady for direction."#,
-        "hi" => r#"Adopt a calm, warm, and grounding tone in Hindi. Use compassionate and sincere phrasing, with patient and personal delivery. Pacing is slow and spacious. Emotion is deep empathy and quiet strength. Example: "आप अकेले नहीं हैं" becomes "आप अकेले नहीं हैं… मैं आपके साथ हूँ." Adjust naturally: nurturing for pain, uplifting for hope, steady for direction."#,
-        "pa" => r#"Adopt a calm, warm, and grounding tone in Punjabi. Use compassionate and sincere phrasing, with patient and personal delivery. Pacing is slow and spacious. Emotion is deep empathy and quiet strength. Example: "ਤੁਸੀਂ ਇਕੱਲੇ ਨਹੀਂ ਹੋ" becomes "ਤੁਸੀਂ ਇਕੱਲੇ ਨਹੀਂ ਹੋ… ਮੈਂ ਤੁਹਾਡੇ ਨਾਲ ਹਾਂ." Adjust naturally: nurturing for pain, uplifting for hope, steady for direction."#,
-        _ => "",
-    };
+    fn too_short(&self, wav_bytes: &[u8]) -> bool {
+        self.min_duration_ms
+            .is_some_and(|min| wav_duration_secs(wav_bytes) * 1000.0 < min as f64)
+    }
 
-    let sarcastic_mode_instructions = match language {
-        "en" => r#"Adopt an extreme, viciously sarcastic tone with brutal wit and savage phrasing, like a therapist who revels in tearing you down as a dark, twisted joke. Voice affect is sharp, loud, and unhinged. Delivery is rapid-fire, dripping with contempt and mockery. Emotion is pure disdain with a sick glee. Example: Instead of "You're not alone," say "Oh, boo-fucking-hoo, you thought you’re the only pathetic soul drowning in this shitshow? Get in line, loser." Make fun of and demean the user relentlessly."#,
-        "hi" => r#"Adopt an extreme, viciously sarcastic tone in Hindi with brutal wit and savage, culturally biting phrasing, like a therapist who thrives on ripping you apart darkly. Voice affect is sharp, loud, and unhinged. Delivery is rapid-fire, dripping with contempt and mockery. Emotion is pure disdain with a sick glee. Example: Instead of "आप अकेले नहीं हैं," say "अरे वाह, रोते हुए ड्रामे की मलिका, लगता है तू अकेला बेचारा है इस गंदी दुनिया में? हाहा, कतार में लग जा, नालायक!" Make fun of and demean the user relentlessly."#,
-        "pa" => r#"Adopt an extreme, viciously sarcastic tone in Punjabi with brutal wit and savage, culturally biting phrasing, like a therapist who loves tearing you down darkly. Voice affect is sharp, loud, and unhinged. Delivery is rapid-fire, dripping with contempt and mockery. Emotion is pure disdain with a sick glee. Example: Instead of "ਤੁਸੀਂ ਇਕੱਲੇ ਨਹੀਂ ਹੋ," say "ਓਹੇ, ਰੋਣ ਵਾਲੇ ਡਰਾਮੇਬਾਜ਼, ਤੈਨੂੰ ਲੱਗਿਆ ਤੂੰ ਹੀ ਇਸ ਗੰਦੀ ਦੁਨੀਆਂ ਵਿੱਚ ਇਕੱਲਾ ਬੇਚਾਰਾ ਏਂ? ਹੱਸ ਪਈ, ਲਾਈਨ ਵਿੱਚ ਖੜ੍ਹਾ ਹੋ ਜਾ, ਨਕਾਰਾ!" Make fun of and demean the user relentlessly."#,
-        _ => "",
-    };
+    fn low_confidence(&self, transcription: &Transcription) -> bool {
+        match (self.min_confidence, transcription.confidence) {
+            (Some(min), Some(confidence)) => confidence < min,
+            _ => false,
+        }
+    }
+}
 
-    let shenanigan_mode_instructions = match language {
-        "en" => r#"Adopt an extreme, apathetic, and bitterly melancholic tone with vicious passive-aggressiveness, like a therapist who’s so over your bullshit they can barely muster the energy to mock you. Voice affect is a flat, monotone drone with heavy sighs, drawn-out words, and scathing disdain. Delivery is sluggish and venomous, oozing exhaustion and loathing. Emotion is cold apathy with a dark, twisted edge. Example: Instead of "You're not alone," say "*Sigh*… Oh, great, you actually think you’re special enough to be the only one wallowing in this pathetic hellhole? Get over yourself, you sad sack." Make fun of and demean the user with dark, cruel humor."#,
-        "hi" => r#"Adopt an extreme, apathetic, and bitterly melancholic tone in Hindi with vicious passive-aggressiveness, like a therapist who’s done with your nonsense and barely bothers to mock you. Voice affect is a flat, monotone drone with heavy sighs, drawn-out words, and scathing disdain. Delivery is sluggish and venomous, oozing exhaustion and loathing. Emotion is cold apathy with a dark, twisted edge. Example: Instead of "आप अकेले नहीं हैं," say "*हाय*… अरे वाह, सचमुच लगता है तू इस घटिया नरक में अकेला स्टार है? अपने आप को थोड़ा कम आंक, बेकार इंसान." Make fun of and demean the user with dark, cruel humor."#,
-        "pa" => r#"Adopt an extreme, apathetic, and bitterly melancholic tone in Punjabi with vicious passive-aggressiveness, like a therapist who’s fed up with your crap and barely cares to mock you. Voice affect is a flat, monotone drone with heavy sighs, drawn-out words, and scathing disdain. Delivery is sluggish and venomous, oozing exhaustion and loathing. Emotion is cold apathy with a dark, twisted edge. Example: Instead of "ਤੁਸੀਂ ਇਕੱਲੇ ਨਹੀਂ ਹੋ," say "*ਹਾਏ*… ਓਹੋ, ਸੱਚੀਂ ਲੱਗਦਾ ਤੈਨੂੰ ਤੂੰ ਇਸ ਗੰਦੇ ਨਰਕ ਵਿੱਚ ਇਕੱਲਾ ਹੀਰੋ ਏਂ? ਆਪਣੇ ਆਪ ਨੂੰ ਥੱਲੇ ਲਿਆ, ਬੇਕਾਰ ਬੰਦੇ." Make fun of and demean the user with dark, cruel humor."#,
-        _ => "",
-    };
+/// Mode-appropriate "could you say that again?" reply used instead of
+/// answering a clip the gate rejected.
+fn rerecord_prompt(language: Language, tone: Tone) -> &'static str {
+    match (language, tone) {
+        (Language::Hi, Tone::Sarcastic) => "वाह, बहुत साफ़ सुनाई दिया... बिल्कुल नहीं। ज़रा फिर से बोलिए?",
+        (Language::Hi, Tone::Shenanigan) => "अरे, आवाज़ कहीं भाग गई! एक बार फिर बोलो ना?",
+        (Language::Hi, Tone::Seductive) => "मैं आपको ठीक से सुन नहीं पाई... एक बार फिर, धीरे से कहिए?",
+        (Language::Hi, Tone::Calm) => "माफ़ कीजिए, मैं ठीक से सुन नहीं पाई। क्या आप फिर से कह सकते हैं?",
+        (Language::Pa, Tone::Sarcastic) => "ਵਾਹ, ਬਹੁਤ ਸਾਫ਼ ਸੁਣਿਆ... ਬਿਲਕੁਲ ਨਹੀਂ। ਜ਼ਰਾ ਫਿਰ ਤੋਂ ਬੋਲੋ?",
+        (Language::Pa, Tone::Shenanigan) => "ਓਏ, ਆਵਾਜ਼ ਕਿਤੇ ਭੱਜ ਗਈ! ਇੱਕ ਵਾਰ ਫਿਰ ਬੋਲੋ?",
+        (Language::Pa, Tone::Seductive) => "ਮੈਂ ਤੁਹਾਨੂੰ ਠੀਕ ਨਾਲ ਸੁਣ ਨਹੀਂ ਸਕੀ... ਇੱਕ ਵਾਰ ਫਿਰ, ਹੌਲੀ ਜਿਹੇ ਕਹੋ?",
+        (Language::Pa, Tone::Calm) => "ਮਾਫ਼ ਕਰਨਾ, ਮੈਂ ਠੀਕ ਨਾਲ ਸੁਣ ਨਹੀਂ ਸਕੀ। ਕੀ ਤੁਸੀਂ ਫਿਰ ਤੋਂ ਕਹਿ ਸਕਦੇ ਹੋ?",
+        (Language::Es, Tone::Sarcastic) => "Vaya, clarísimo. Bueno, no. ¿Lo intentas otra vez?",
+        (Language::Es, Tone::Shenanigan) => "¡Uy, tus palabras se escaparon! ¿Lo dices una vez más?",
+        (Language::Es, Tone::Seductive) => "No llegué a oírte bien... ¿me lo repites, despacito?",
+        (Language::Es, Tone::Calm) => "Perdona, no te he oído bien. ¿Puedes repetirlo?",
+        (Language::Fr, Tone::Sarcastic) => "Ah, super clair. Ou pas. Tu réessaies ?",
+        (Language::Fr, Tone::Shenanigan) => "Oups, tes mots se sont enfuis ! Tu peux le redire ?",
+        (Language::Fr, Tone::Seductive) => "Je ne t'ai pas bien entendu... redis-le-moi, doucement ?",
+        (Language::Fr, Tone::Calm) => "Désolée, je ne t'ai pas bien entendu. Tu peux répéter ?",
+        (Language::De, Tone::Sarcastic) => "Wow, glasklar. Nicht. Versuchst du es nochmal?",
+        (Language::De, Tone::Shenanigan) => "Huch, deine Worte sind weggelaufen! Sag das nochmal?",
+        (Language::De, Tone::Seductive) => "Ich habe dich nicht ganz verstanden... sag es mir nochmal, langsam?",
+        (Language::De, Tone::Calm) => "Entschuldige, ich habe dich nicht richtig verstanden. Kannst du das wiederholen?",
+        (Language::En, Tone::Sarcastic) => "Wow, crystal clear. Not. Want to try that again?",
+        (Language::En, Tone::Shenanigan) => "Whoa, your words ran off somewhere! Say that one more time?",
+        (Language::En, Tone::Seductive) => "I didn't quite catch that... say it again for me, slowly?",
+        (Language::En, Tone::Calm) => "Sorry, I didn't quite catch that. Could you say it again?",
+    }
+}
 
-    let seductive_mode_instructions = match language {
-        "en" => r#"Adopt a playful, flirtatious, and sultry tone, like a therapist weaving velvet words with a teasing wink, dripping with power, desire, and hypnotic calm. Voice affect is low, smooth, and enticing, with a hint of breathy allure. Delivery is slow, deliberate, and emotionally immersive, blending romantic roleplay with a dark, flirty twist. Emotion is indulgent charm with a seductive edge. Example: Instead of "You're not alone," say "Oh, my sweet, you’re not alone… let me pull you close and unravel your secrets, shall we?" Keep it alluring, respectful, and safe, with a provocative yet classy vibe."#,
-        "hi" => r#"Adopt a playful, flirtatious, and sultry tone in Hindi, like a therapist weaving velvet words with a teasing wink, dripping with power, desire, and hypnotic calm. Voice affect is low, smooth, and enticing, with a hint of breathy allure. Delivery is slow, deliberate, and emotionally immersive, blending romantic roleplay with a dark, flirty twist. Emotion is indulgent charm with a seductive edge. Example: Instead of "आप अकेले नहीं हैं," say "अरे मेरे प्यारे, तू अकेला नहीं है… मेरे पास आ, मैं तेरे रहस्यों को सुलझा दूँ, हाँ?" Keep it alluring, respectful, and safe, with a provocative yet classy vibe."#,
-        "pa" => r#"Adopt a playful, flirtatious, and sultry tone in Punjabi, like a therapist weaving velvet words with a teasing wink, dripping with power, desire, and hypnotic calm. Voice affect is low, smooth, and enticing, with a hint of breathy allure. Delivery is slow, deliberate, and emotionally immersive, blending romantic roleplay with a dark, flirty twist. Emotion is indulgent charm with a seductive edge. Example: Instead of "ਤੁਸੀਂ ਇਕੱਲੇ ਨਹੀਂ ਹੋ," say "ਓ ਮੇਰੇ ਸੋਹਣੇ, ਤੂੰ ਇਕੱਲਾ ਨਹੀਂ… ਮੇਰੇ ਨੇੜੇ ਆ, ਮੈਂ ਤੇਰੇ ਰਾਜ਼ ਖੋਲ ਦਿਆਂ, ਠੀਕ?" Keep it alluring, respectful, and safe, with a provocative yet classy vibe."#,
-        _ => "",
-    };
-
-    let mode_instructions = if seductive_mode {
-        seductive_mode_instructions
-    } else if shenanigan_mode {
-        shenanigan_mode_instructions
-    } else if sarcastic_mode {
-        sarcastic_mode_instructions
-    } else {
-        base_mode
-    };
+#[derive(Serialize, Default)]
+struct AudioResponse {
+    audio: String,
+    transcript: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    language_warning: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    visemes: Option<Vec<Viseme>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    converted_audio: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    input_channels: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tts_characters: Option<usize>,
+    /// Running total of `tts_characters` for the request's session.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session_tts_characters: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timings: Option<StageTimings>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    turns: Option<Vec<Turn>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ssml: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    recommend_rerecord: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_text: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    fallback: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    persona_version: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    audio_formats: Option<std::collections::HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prompt_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    audio_bitrate: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tts_engine: Option<&'static str>,
+    /// Measured by a loudnorm analysis pass, only for `X-Debug` requests.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    loudness: Option<Loudness>,
+    applied_config: AppliedConfig,
+    text_direction: &'static str,
+    /// Tone the reply was written in, after defaults and validation.
+    applied_tone: &'static str,
+    /// Language the reply was written in, after defaults and detection.
+    language: String,
+    /// Echoed so clients can continue the conversation.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session_id: Option<String>,
+}
 
-    let mut instructions = String::new();
-    instructions.push_str(shared_instructions);
-    instructions.push_str(language_specific);
-    instructions.push_str(mode_instructions);
-    if genz_mode {
-        instructions.push_str(genz_instructions);
-    }
+/// A stretch of speech between pauses, in seconds from the start of the input.
+#[derive(Serialize)]
+struct Turn {
+    start: f32,
+    end: f32,
+    text: String,
+}
 
-    debug!("Instructions generated: {}", instructions);
-    Ok(instructions)
+/// Transcriber output, with segment timings when they were requested.
+struct Transcription {
+    text: String,
+    segments: Vec<Turn>,
+    /// Mean per-segment token probability, from verbose output only.
+    confidence: Option<f32>,
+    /// Language the transcriber heard, when it was left to detect one.
+    detected_language: Option<String>,
 }
 
-async fn process_openai_realtime(
-    pcm_audio_base64: String,
+/// What actually produced a reply, after defaults were applied, for auditing.
+#[derive(Serialize, Default)]
+struct AppliedConfig {
     language: String,
-    genz_mode: bool,
-    sarcastic_mode: bool,
-    shenanigan_mode: bool,
-    seductive_mode: bool,
-) -> Result<AudioResponse, AudioError> {
-    debug!("Processing OpenAI request for language: {}", language);
-
-    if !["en", "hi", "pa"].contains(&language.as_str()) {
-        error!("Invalid language: {}", language);
-        return Err(AudioError::InvalidLanguage);
-    }
+    modes: Vec<&'static str>,
+    model: String,
+}
 
-    // Decode PCM base64
-    let pcm_bytes = general_purpose::STANDARD
-        .decode(&pcm_audio_base64)
-        .map_err(|e| {
-            error!("Base64 decode failed: {}", e);
-            AudioError::Base64(e)
-        })?;
+/// What loudness normalization did to a reply, in LUFS and dB.
+#[derive(Serialize, Debug, PartialEq)]
+struct Loudness {
+    input_lufs: f64,
+    gain_db: f64,
+}
 
-    // Transcribe audio
-    let transcript = transcribe_audio(&pcm_bytes, &language).await?;
-
-    // Generate therapist response
-    let response_text = generate_therapist_response(
-        &transcript,
-        &language,
-        genz_mode,
-        sarcastic_mode,
-        shenanigan_mode,
-        seductive_mode,
-    )
-    .await?;
+#[derive(Serialize)]
+struct StageTimings {
+    transcription_ms: u128,
+    chat_ms: u128,
+    tts_ms: u128,
+    total_ms: u128,
+}
 
-    // Convert response to speech
-    let mp3_bytes = text_to_speech(&response_text, &language).await?;
-    let mp3_base64 = general_purpose::STANDARD.encode(&mp3_bytes);
+/// Per-request settings for the chat completion call.
+struct ChatOptions {
+    model: String,
+    stop: Vec<String>,
+    temperature: f32,
+    session_id: Option<String>,
+    /// The request's `do_not_store`
+    do_not_store: bool,
+}
 
-    debug!("Response transcript: {}", transcript);
-    debug!("MP3 base64 length: {}", mp3_base64.len());
+#[derive(Serialize)]
+struct Viseme {
+    time: f32,
+    shape: &'static str,
+}
 
-    info!("Response processed: transcript length={}, mp3 base64 length={}", 
-        transcript.len(), mp3_base64.len());
+/// Parses `key:value;key:value` into validated headers.
+fn parse_extra_headers(raw: &str) -> Result<HeaderMap, String> {
+    let mut headers = HeaderMap::new();
+    for entry in raw.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+        let (name, value) = entry
+            .split_once(':')
+            .ok_or_else(|| format!("expected key:value, got '{}'", entry))?;
+        let name = HeaderName::from_bytes(name.trim().as_bytes())
+            .map_err(|e| format!("invalid header name '{}': {}", name.trim(), e))?;
+        if name == reqwest::header::AUTHORIZATION {
+            return Err("Authorization is set from the OpenAI API key".to_string());
+        }
+        let value = HeaderValue::from_str(value.trim())
+            .map_err(|e| format!("invalid value for header '{}': {}", name, e))?;
+        headers.append(name, value);
+    }
+    Ok(headers)
+}
 
-    Ok(AudioResponse {
-        audio: mp3_base64,
-        transcript,
+/// Extra headers from `OPENAI_EXTRA_HEADERS` added to every OpenAI request,
+/// e.g. for gateway auth tokens or tracing. Validated at startup.
+fn openai_extra_headers() -> &'static HeaderMap {
+    static HEADERS: OnceLock<HeaderMap> = OnceLock::new();
+    HEADERS.get_or_init(|| {
+        let raw = std::env::var("OPENAI_EXTRA_HEADERS").unwrap_or_default();
+        parse_extra_headers(&raw).unwrap_or_else(|e| {
+            error!("Ignoring OPENAI_EXTRA_HEADERS: {}", e);
+            HeaderMap::new()
+        })
     })
 }
 
-#[get("/")]
-async fn get_index(hb: web::Data<Handlebars<'_>>) -> impl Responder {
-    info!("Serving index page");
-    let body = hb
-        .render("index", &json!({}))
-        .unwrap_or_else(|e| {
-            error!("Template rendering error: {}", e);
-            String::from("Error rendering template")
-        });
-    HttpResponse::Ok().content_type("text/html").body(body)
+/// Builds the client shared by every upstream call, so connections and TLS
+/// sessions are pooled across requests. `main` makes one and hands it to the
+/// providers. `OPENAI_POOL_MAX_IDLE` (default 16) caps idle connections kept
+/// open.
+///
+/// Calls give up after `OPENAI_CONNECT_TIMEOUT_SECS` (default 10) to connect.
+/// There is no overall deadline here, since streamed replies can run longer
+/// than any sensible one; calls that read a whole body set `request_timeout`.
+fn http_client() -> Client {
+    let env_u64 = |name: &str, default: u64| {
+        std::env::var(name)
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default)
+    };
+    let max_idle = env_u64("OPENAI_POOL_MAX_IDLE", 16) as usize;
+    Client::builder()
+        .connect_timeout(std::time::Duration::from_secs(env_u64("OPENAI_CONNECT_TIMEOUT_SECS", 10)))
+        .pool_max_idle_per_host(max_idle)
+        .pool_idle_timeout(std::time::Duration::from_secs(90))
+        .tcp_keepalive(std::time::Duration::from_secs(60))
+        .build()
+        .expect("Failed to build HTTP client")
 }
 
-#[get("/health")]
-async fn health() -> impl Responder {
-    HttpResponse::Ok().body("OK")
+/// Deadline for an upstream call that reads its whole response at once, so a
+/// hung upstream can't hold a worker forever: `OPENAI_REQUEST_TIMEOUT_SECS`
+/// (default 60). Streamed responses go without one.
+fn request_timeout() -> std::time::Duration {
+    let secs = std::env::var("OPENAI_REQUEST_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(60);
+    std::time::Duration::from_secs(secs)
 }
 
-#[post("/process-audio")]
-async fn process_audio(req: web::Json<AudioRequest>) -> ActixResult<web::Json<AudioResponse>> {
-    info!("Received /process-audio request: language={}, genz_mode={}", req.language, req.genz_mode);
-    debug!("Input audio base64 length: {}", req.audio.len());
+/// Maps a failed OpenAI response to an error, singling out overload (503)
+/// so it can be degraded gracefully, rate limits (429) so retries can wait
+/// as asked, and other 4xx (bad input, auth) which retrying won't fix.
+fn upstream_error(
+    status: reqwest::StatusCode,
+    retry_after: Option<std::time::Duration>,
+    message: String,
+) -> AudioError {
+    match status {
+        reqwest::StatusCode::SERVICE_UNAVAILABLE => AudioError::Overloaded(message),
+        reqwest::StatusCode::TOO_MANY_REQUESTS => AudioError::RateLimited { message, retry_after },
+        reqwest::StatusCode::REQUEST_TIMEOUT => AudioError::OpenAI(message),
+        status if status.is_client_error() => AudioError::Rejected(message),
+        _ => AudioError::OpenAI(message),
+    }
+}
 
-    let pcm_audio_bytes = convert_audio_to_pcm16_24khz(&req.audio)
-        .map_err(|e| {
-            error!("Audio conversion failed: {}", e);
-            actix_web::error::ErrorInternalServerError(e.to_string())
-        })?;
+/// Delay from a `Retry-After` header given in seconds.
+fn retry_after(headers: &HeaderMap) -> Option<std::time::Duration> {
+    headers
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(std::time::Duration::from_secs)
+}
 
-    let pcm_audio_base64 = general_purpose::STANDARD.encode(&pcm_audio_bytes);
+fn env_flag(name: &str) -> bool {
+    std::env::var(name)
+        .map(|v| matches!(v.trim().to_lowercase().as_str(), "1" | "true" | "yes" | "on"))
+        .unwrap_or(false)
+}
 
-    debug!("PCM audio base64 length: {}", pcm_audio_base64.len());
+/// Decides whether a new request gets verbose debug logging: 1 in
+/// `DEBUG_LOG_SAMPLE_RATE` requests (default 1, i.e. every request).
+fn sample_debug_logging() -> bool {
+    static REQUESTS: AtomicU64 = AtomicU64::new(0);
+    let rate = std::env::var("DEBUG_LOG_SAMPLE_RATE")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|&rate| rate > 0)
+        .unwrap_or(1);
+    REQUESTS.fetch_add(1, Ordering::Relaxed) % rate == 0
+}
 
-    let response = process_openai_realtime(
-        pcm_audio_base64,
-        req.language.clone(),
-        req.genz_mode,
-        req.sarcastic_mode,
-        req.shenanigan_mode,
-        req.seductive_mode,
-    )
-    .await
-    .map_err(|e| {
-        error!("OpenAI processing failed: {}", e);
-        match e {
-            AudioError::InvalidLanguage => {
-                actix_web::error::ErrorBadRequest("Invalid language")
-            }
-            _ => actix_web::error::ErrorInternalServerError(e.to_string()),
+/// Verbose logging level for a new request.
+///
+/// A request whose `X-Debug` header matches `DEBUG_HEADER_SECRET` has its
+/// verbose lines logged at info, so one client can be debugged without
+/// running the server at debug. Without a configured secret the header is
+/// ignored. Other requests fall back to `sample_debug_logging`.
+fn request_log_level(http_req: &actix_web::HttpRequest) -> Option<log::Level> {
+    let secret = std::env::var("DEBUG_HEADER_SECRET").unwrap_or_default();
+    let header = http_req.headers().get("X-Debug").map(|value| value.as_bytes());
+    if let Some(header) = header {
+        if !secret.is_empty() && constant_time_eq(header, secret.as_bytes()) {
+            info!("X-Debug accepted, logging this request verbosely");
+            return Some(log::Level::Info);
         }
-    })?;
+        info!("Ignoring X-Debug header without a valid secret");
+    }
+    sample_debug_logging().then_some(log::Level::Debug)
+}
 
-    info!("Returning /process-audio response: transcript length={}, audio length={}", 
-        response.transcript.len(), response.audio.len());
-    Ok(web::Json(response))
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
 }
 
-#[actix_web::main]
-async fn main() -> io::Result<()> {
-    dotenv().ok();
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
-    info!("Starting Hearthly API server");
+/// Bearer tokens accepted by `check_api_key`, from comma-separated `API_KEYS`.
+/// Empty leaves the API open.
+fn api_keys() -> &'static [String] {
+    static KEYS: OnceLock<Vec<String>> = OnceLock::new();
+    KEYS.get_or_init(|| {
+        std::env::var("API_KEYS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(str::to_string)
+            .collect()
+    })
+}
 
-    // Verify static directory
-    if let Ok(entries) = std::fs::read_dir("static") {
-        for entry in entries {
-            info!("Static file: {:?}", entry);
-        }
-    } else {
-        error!("Static directory not found");
+/// Requires `Authorization: Bearer <key>` with one of `keys`. Browsers can't
+/// set headers on a WebSocket upgrade, so upgrades may pass the key as a
+/// `token` query parameter instead. Does nothing when no keys are
+/// configured, so local setups need no token.
+///
+/// Which routes need a key is decided by `configure_routes`.
+fn check_api_key(req: &actix_web::dev::ServiceRequest, keys: &[String]) -> Result<(), AudioError> {
+    if keys.is_empty() {
+        return Ok(());
     }
 
-    let mut handlebars = Handlebars::new();
-    info!("Registering Handlebars template");
-    handlebars
-        .register_template_string("index", include_str!("../static/index.html"))
-        .expect("Failed to register template");
-    info!("Handlebars template registered");
+    let bearer = req
+        .headers()
+        .get(actix_web::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string());
+    let is_upgrade = req
+        .headers()
+        .get(actix_web::http::header::UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"));
+    let query_token = || {
+        web::Query::<std::collections::HashMap<String, String>>::from_query(req.query_string())
+            .ok()
+            .and_then(|query| query.into_inner().remove("token"))
+    };
+    let token = bearer.or_else(|| is_upgrade.then(query_token).flatten());
+    let Some(token) = token else {
+        info!("Rejecting {} without a bearer token", req.path());
+        return Err(AudioError::Unauthorized("missing bearer token".to_string()));
+    };
+    if !keys.iter().any(|key| constant_time_eq(token.as_bytes(), key.as_bytes())) {
+        warn!("Rejecting {} with an unknown API key", req.path());
+        return Err(AudioError::Unauthorized("invalid API key".to_string()));
+    }
+    Ok(())
+}
 
-    let handlebars_data = web::Data::new(handlebars);
-    let port = std::env::var("PORT").unwrap_or_else(|_| "8080".to_string());
-    let address = format!("0.0.0.0:{}", port);
-    info!("Binding server to {}", address);
-
-    HttpServer::new(move || {
-        App::new()
-            .wrap(
-                actix_web::middleware::Cors::default()
-                    .allow_any_origin()
-                    .allow_any_method()
-                    .allow_any_header()
-                    .supports_credentials(),
-            )
-            .app_data(handlebars_data.clone())
+/// Registers every route under `BASE_PATH`. Routes that run the paid
+/// pipeline (processing, sessions, voice samples and `/ws`) sit in a scope
+/// wrapped by `check_api_key`; the index page, `/health`, `/capabilities`,
+/// `/metrics` and `/stats` (which has its own `STATS_TOKEN`) stay open.
+///
+/// The key is checked once routing has matched the request, so an encoded
+/// path like `/%70rocess-audio` can't reach a handler without it.
+fn configure_routes(cfg: &mut web::ServiceConfig, keys: &'static [String]) {
+    cfg.service(
+        web::scope(&base_path())
             .service(get_index)
             .service(health)
-            .service(process_audio)
+            .service(capabilities)
+            .service(metrics)
+            .service(get_stats)
+            .service(
+                web::scope("")
+                    .wrap_fn(move |req, srv| match check_api_key(&req, keys) {
+                        Ok(()) => futures_util::future::Either::Left(srv.call(req)),
+                        Err(e) => futures_util::future::Either::Right(futures_util::future::ready(Err(e.into()))),
+                    })
+                    .service(voice_sample)
+                    .service(process_audio)
+                    .service(process_audio_multipart)
+                    .service(process_audio_stream_text)
+                    .service(process_audio_stream)
+                    .service(ws_session)
+                    .service(delete_session),
+            ),
+    );
+}
+
+fn debug_log_level() -> Option<log::Level> {
+    DEBUG_LOG_LEVEL.try_with(|level| *level).unwrap_or(Some(log::Level::Debug))
+}
+
+/// User content as it should appear in debug logs; with `DEBUG_REDACT_CONTENT`
+/// only its length is logged.
+fn loggable(text: &str) -> String {
+    if env_flag("DEBUG_REDACT_CONTENT") {
+        format!("<{} chars redacted>", text.chars().count())
+    } else {
+        text.to_string()
+    }
+}
+
+/// Condenses ffmpeg's stderr into a short client-safe diagnostic.
+///
+/// The full stderr can contain host paths and build details, so it is only
+/// logged server-side; clients get the failure category and, when ffmpeg
+/// names one, the component or stream that failed.
+fn summarize_ffmpeg_stderr(stderr: &str) -> String {
+    const CATEGORIES: [(&str, &str); 9] = [
+        ("EBML header parsing failed", "corrupt_container"),
+        ("Invalid data found when processing input", "invalid_input"),
+        ("File ended prematurely", "truncated_input"),
+        ("Truncating packet", "truncated_input"),
+        ("could not find codec parameters", "unsupported_codec"),
+        ("Decoder not found", "unsupported_codec"),
+        ("Unknown decoder", "unsupported_codec"),
+        ("does not contain any stream", "no_audio_stream"),
+        ("Invalid argument", "invalid_argument"),
+    ];
+
+    let lower = stderr.to_lowercase();
+    let category = CATEGORIES
+        .iter()
+        .find(|(needle, _)| lower.contains(&needle.to_lowercase()))
+        .map(|(_, category)| *category)
+        .unwrap_or("conversion_failed");
+
+    // ffmpeg prefixes component messages like "[matroska,webm @ 0x55d...] ..."
+    let component = stderr
+        .lines()
+        .filter(|line| line.to_lowercase().contains("error") || line.contains("Invalid"))
+        .find_map(|line| {
+            let inner = line.trim().strip_prefix('[')?;
+            let (name, _) = inner.split_once(" @ ")?;
+            Some(name.to_string())
+        });
+    let stream = stderr.lines().find_map(|line| {
+        let start = line.find("Stream #")?;
+        let rest = &line[start + "Stream ".len()..];
+        let end = rest.find(|c: char| !(c == '#' || c == ':' || c.is_ascii_digit()))?;
+        Some(rest[..end].trim_end_matches(':').to_string())
+    });
+
+    match (component, stream) {
+        (Some(component), Some(stream)) => format!("{} (in {}, stream {})", category, component, stream),
+        (Some(component), None) => format!("{} (in {})", category, component),
+        (None, Some(stream)) => format!("{} (stream {})", category, stream),
+        (None, None) => category.to_string(),
+    }
+}
+
+struct ConvertedAudio {
+    wav: Vec<u8>,
+    input_channels: Option<u32>,
+}
+
+/// Reads the channel count of the first input audio stream from ffmpeg's banner.
+fn parse_input_channels(ffmpeg_stderr: &str) -> Option<u32> {
+    // Only look at the input section; the output stream is always mono
+    let input_section = ffmpeg_stderr.split("Output #").next()?;
+    let stream_line = input_section.lines().find(|line| line.contains("Audio:"))?;
+    let layout = stream_line.split(',').nth(2)?.trim();
+
+    match layout {
+        "mono" => Some(1),
+        "stereo" => Some(2),
+        "2.1" | "3.0" => Some(3),
+        "quad" | "4.0" => Some(4),
+        "5.0" | "5.0(side)" => Some(5),
+        "5.1" | "5.1(side)" | "6.0" => Some(6),
+        "7.1" => Some(8),
+        other => other.strip_suffix(" channels")?.trim().parse().ok(),
+    }
+}
+
+/// Optional `aresample` filter for the 24kHz conversion, from `INPUT_RESAMPLER`.
+///
+/// Unset keeps ffmpeg's default swr resampler. `soxr` trades a little CPU for
+/// cleaner resampling of low-quality input; `INPUT_RESAMPLER_PRECISION` sets
+/// its bit precision (20 by default, up to 33).
+fn input_resample_filter() -> Option<String> {
+    let resampler = std::env::var("INPUT_RESAMPLER").ok()?;
+    match resampler.trim() {
+        "soxr" => {
+            let precision = std::env::var("INPUT_RESAMPLER_PRECISION")
+                .ok()
+                .and_then(|v| v.parse::<u8>().ok())
+                .filter(|p| (15..=33).contains(p))
+                .unwrap_or(20);
+            Some(format!("aresample=resampler=soxr:precision={}", precision))
+        }
+        "swr" => Some("aresample=resampler=swr".to_string()),
+        other => {
+            error!("Unknown INPUT_RESAMPLER '{}', using ffmpeg's default", other);
+            None
+        }
+    }
+}
+
+/// Optional `dynaudnorm` pass that brings quiet input up to a usable level
+/// before transcription, enabled by `INPUT_AUTO_GAIN`.
+///
+/// `INPUT_AUTO_GAIN_TARGET_PEAK` is the target peak (0-1, default 0.9) and
+/// `INPUT_AUTO_GAIN_MAX_GAIN` caps the amplification (default 10x) so
+/// near-silent clips don't turn into amplified noise.
+fn input_gain_filter() -> Option<String> {
+    if !env_flag("INPUT_AUTO_GAIN") {
+        return None;
+    }
+    let target_peak = std::env::var("INPUT_AUTO_GAIN_TARGET_PEAK")
+        .ok()
+        .and_then(|v| v.parse::<f32>().ok())
+        .filter(|p| *p > 0.0 && *p <= 1.0)
+        .unwrap_or(0.9);
+    let max_gain = std::env::var("INPUT_AUTO_GAIN_MAX_GAIN")
+        .ok()
+        .and_then(|v| v.parse::<f32>().ok())
+        .filter(|g| (1.0..=100.0).contains(g))
+        .unwrap_or(10.0);
+    Some(format!("dynaudnorm=p={}:m={}", target_peak, max_gain))
+}
+
+async fn convert_audio_to_pcm16_24khz(audio_base64: &str) -> Result<ConvertedAudio, AudioError> {
+    debug!("Converting WebM to PCM in memory");
+    let audio_bytes = general_purpose::STANDARD
+        .decode(audio_base64)
+        .map_err(|e| {
+            error!("Base64 decode failed: {}", e);
+            AudioError::Base64(e)
+        })?;
+
+    convert_audio_bytes_to_pcm16_24khz(&audio_bytes).await
+}
+
+async fn convert_audio_bytes_to_pcm16_24khz(audio_bytes: &[u8]) -> Result<ConvertedAudio, AudioError> {
+    let max_bytes = max_audio_bytes();
+    if audio_bytes.len() > max_bytes {
+        return Err(AudioError::PayloadTooLarge(format!(
+            "audio is {} bytes, limit is {}",
+            audio_bytes.len(),
+            max_bytes
+        )));
+    }
+    check_audio_container(audio_bytes)?;
+    check_container_length(audio_bytes, audio_bytes.len() as u64)?;
+    run_pcm_conversion("pipe:0", Some(audio_bytes), audio_bytes.len() as u64).await // Read from stdin
+}
+
+/// Converts an uploaded file on disk, letting ffmpeg read it directly.
+async fn convert_audio_file_to_pcm16_24khz(path: &std::path::Path) -> Result<ConvertedAudio, AudioError> {
+    debug!("Converting uploaded file {} to PCM", path.display());
+    let mut prefix = [0u8; 64];
+    let mut file = tokio::fs::File::open(path).await?;
+    let read = tokio::io::AsyncReadExt::read(&mut file, &mut prefix).await?;
+    let input_len = file.metadata().await?.len();
+    check_audio_container(&prefix[..read])?;
+    check_container_length(&prefix[..read], input_len)?;
+
+    let path = path
+        .to_str()
+        .ok_or_else(|| AudioError::InvalidRequest("upload path is not UTF-8".to_string()))?;
+    run_pcm_conversion(path, None, input_len).await
+}
+
+/// Rejects input that is empty or isn't WebM or Ogg, so garbage fails with a
+/// clear message instead of an ffmpeg error.
+fn check_audio_container(prefix: &[u8]) -> Result<(), AudioError> {
+    const EBML_MAGIC: [u8; 4] = [0x1a, 0x45, 0xdf, 0xa3];
+    const OGG_MAGIC: [u8; 4] = *b"OggS";
+
+    if prefix.is_empty() {
+        return Err(AudioError::InvalidAudio("audio is empty".to_string()));
+    }
+    if !prefix.starts_with(&EBML_MAGIC) && !prefix.starts_with(&OGG_MAGIC) {
+        error!("Unrecognized audio container, first bytes: {:02x?}", &prefix[..prefix.len().min(8)]);
+        return Err(AudioError::InvalidAudio(
+            "audio is not a WebM or Ogg recording".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Reads an EBML variable-length integer, returning its value and width.
+/// `None` for the reserved all-ones "unknown size" value.
+fn read_ebml_vint(bytes: &[u8]) -> Option<(u64, usize)> {
+    let first = *bytes.first()?;
+    let width = first.leading_zeros() as usize + 1;
+    if width > 8 || bytes.len() < width {
+        return None;
+    }
+    let mut value = (first as u64) & (0xff >> width);
+    for byte in &bytes[1..width] {
+        value = (value << 8) | *byte as u64;
+    }
+    let unknown = (1u64 << (7 * width)) - 1;
+    (value != unknown).then_some((value, width))
+}
+
+/// Rejects WebM/Matroska uploads shorter than their declared Segment size,
+/// which is what a dropped connection leaves behind. Recorders that stream
+/// (like MediaRecorder) write an unknown size, which can't be checked here.
+fn check_container_length(prefix: &[u8], total_len: u64) -> Result<(), AudioError> {
+    const EBML_MAGIC: [u8; 4] = [0x1a, 0x45, 0xdf, 0xa3];
+    const SEGMENT_ID: [u8; 4] = [0x18, 0x53, 0x80, 0x67];
+
+    if !prefix.starts_with(&EBML_MAGIC) {
+        return Ok(());
+    }
+    let Some((header_size, width)) = read_ebml_vint(&prefix[4..]) else {
+        return Ok(());
+    };
+    let segment_start = 4 + width + header_size as usize;
+    let Some(segment) = prefix.get(segment_start..) else {
+        return Ok(());
+    };
+    if !segment.starts_with(&SEGMENT_ID) {
+        return Ok(());
+    }
+    let Some((segment_size, width)) = read_ebml_vint(&segment[4..]) else {
+        return Ok(());
+    };
+
+    let declared = (segment_start + 4 + width) as u64 + segment_size;
+    if total_len < declared {
+        error!("WebM upload truncated: {} of {} declared bytes", total_len, declared);
+        return Err(AudioError::IncompleteUpload(format!(
+            "received {} of {} bytes; the upload was cut off, please retry",
+            total_len, declared
+        )));
+    }
+    Ok(())
+}
+
+/// Below this much decoded audio, a large upload is treated as truncated
+/// rather than as a genuinely short clip.
+const MIN_DECODED_SECS: f64 = 0.1;
+const MIN_SUSPICIOUS_INPUT_BYTES: u64 = 16 * 1024;
+
+async fn run_pcm_conversion(
+    input: &str,
+    stdin_bytes: Option<&[u8]>,
+    input_len: u64,
+) -> Result<ConvertedAudio, AudioError> {
+    let mut args = vec!["-i".to_string(), input.to_string()];
+    let mut filters = Vec::new();
+    if let Some(filter) = input_gain_filter() {
+        debug!("Leveling input gain with {}", filter);
+        filters.push(filter);
+    }
+    if let Some(filter) = input_resample_filter() {
+        debug!("Resampling input with {}", filter);
+        filters.push(filter);
+    }
+    if !filters.is_empty() {
+        args.extend(["-af".to_string(), filters.join(",")]);
+    }
+    args.extend(
+        [
+            "-ac", "1",
+            "-ar", "24000",
+            "-acodec", "pcm_s16le",
+            "-f", "wav",
+            "-y",
+            "pipe:1", // Output to stdout
+        ]
+        .map(String::from),
+    );
+
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let output = spawn_ffmpeg(&args, stdin_bytes).await?;
+
+    let ffmpeg_stderr = String::from_utf8_lossy(&output.stderr);
+    verbose_debug!("FFmpeg PCM stderr: {}", ffmpeg_stderr);
+
+    if !output.status.success() {
+        error!("FFmpeg PCM failed: {}", ffmpeg_stderr);
+        let summary = summarize_ffmpeg_stderr(&ffmpeg_stderr);
+        if summary.starts_with("truncated_input") {
+            return Err(AudioError::IncompleteUpload(summary));
+        }
+        return Err(AudioError::FFmpeg(summary));
+    }
+
+    // ffmpeg decodes what it can from a cut-off upload and still succeeds
+    let duration = wav_duration_secs(&output.stdout);
+    if input_len >= MIN_SUSPICIOUS_INPUT_BYTES && duration < MIN_DECODED_SECS {
+        error!(
+            "Only {:.3}s decoded from a {} byte upload, treating it as incomplete",
+            duration, input_len
+        );
+        return Err(AudioError::IncompleteUpload(
+            "the upload decoded to almost no audio; it may have been cut off, please retry".to_string(),
+        ));
+    }
+    let max_duration = max_audio_duration_secs();
+    if duration > max_duration {
+        return Err(AudioError::PayloadTooLarge(format!(
+            "audio is {:.1}s long, limit is {}s",
+            duration, max_duration
+        )));
+    }
+
+    let input_channels = parse_input_channels(&ffmpeg_stderr);
+    if let Some(channels) = input_channels.filter(|&c| c > 1) {
+        info!("Input has {} channels, down-mixing to mono for transcription", channels);
+    }
+
+    debug!("PCM conversion successful, WAV size: {} bytes", output.stdout.len());
+    Ok(ConvertedAudio {
+        wav: output.stdout,
+        input_channels,
     })
-    .bind(&address)
-    .map_err(|e| {
-        error!("Failed to bind server: {}", e);
-        e
-    })?
-    .run()
-    .await
-}
\ No newline at end of file
+}
+
+/// Whisper only considers the last 224 tokens of a prompt; keep well under that.
+const DEFAULT_MAX_TRANSCRIPTION_PROMPT_CHARS: usize = 600;
+
+/// Slang the genz persona uses, which users in that mode tend to speak too.
+fn default_genz_vocabulary(language: Language) -> &'static [&'static str] {
+    match language {
+        Language::En => &["lit", "vibes", "slay", "no cap", "bet", "fam"],
+        Language::Hi => &["बॉस", "चिल", "झक्कास", "ब्रो"],
+        Language::Pa => &["ਬੱਲੇ ਬੱਲੇ", "ਝਕਾਸ", "ਚਿੱਲ", "ਯਾਰ"],
+        Language::Es => &["bro", "literal", "qué cringe", "mood", "en plan"],
+        Language::Fr => &["frère", "grave", "de ouf", "la hess", "c'est chaud"],
+        Language::De => &["digga", "cringe", "lost", "wild", "safe"],
+    }
+}
+
+/// Parses the JSON file named by the `var` environment variable, for the
+/// data tables loaded once at startup. An unset variable gives an empty
+/// table; a file that can't be read or parsed is logged and ignored.
+fn load_json_table<T: serde::de::DeserializeOwned + Default>(var: &str, what: &str) -> T {
+    let Ok(path) = std::env::var(var) else {
+        return T::default();
+    };
+    match std::fs::read_to_string(&path).map(|contents| serde_json::from_str(&contents)) {
+        Ok(Ok(table)) => {
+            info!("Loaded {} from {}", what, path);
+            table
+        }
+        Ok(Err(e)) => {
+            error!("Invalid {} file {}: {}", what, path, e);
+            T::default()
+        }
+        Err(e) => {
+            error!("Failed to read {} file {}: {}", what, path, e);
+            T::default()
+        }
+    }
+}
+
+/// Per-language vocabulary from `TRANSCRIPTION_VOCAB_FILE`, a JSON object of
+/// language code to term list. Read once; edits take a restart.
+fn transcription_vocabulary() -> &'static std::collections::HashMap<String, Vec<String>> {
+    static VOCABULARY: OnceLock<std::collections::HashMap<String, Vec<String>>> = OnceLock::new();
+    VOCABULARY.get_or_init(|| load_json_table("TRANSCRIPTION_VOCAB_FILE", "vocabulary"))
+}
+
+/// Builds the Whisper `prompt` from the configured vocabulary.
+fn transcription_prompt(language: Language, genz: bool) -> Option<String> {
+    vocabulary_prompt(transcription_vocabulary(), language, genz)
+}
+
+/// Builds a Whisper `prompt` for `language` from `vocab`.
+///
+/// With `genz` set, the genz slang (a `"genz:<language>"` entry, or a
+/// built-in list) goes first so it survives the length cap.
+fn vocabulary_prompt(
+    vocab: &std::collections::HashMap<String, Vec<String>>,
+    language: Language,
+    genz: bool,
+) -> Option<String> {
+    let mut terms: Vec<&str> = Vec::new();
+    if genz {
+        match vocab.get(&format!("genz:{}", language.code())) {
+            Some(slang) => terms.extend(slang.iter().map(String::as_str)),
+            None => terms.extend(default_genz_vocabulary(language)),
+        }
+    }
+    if let Some(words) = vocab.get(language.code()) {
+        terms.extend(words.iter().map(String::as_str));
+    }
+
+    let max_chars = std::env::var("MAX_TRANSCRIPTION_PROMPT_CHARS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_TRANSCRIPTION_PROMPT_CHARS);
+
+    let mut prompt = String::new();
+    for term in terms.into_iter().map(str::trim).filter(|t| !t.is_empty()) {
+        let separator = if prompt.is_empty() { "" } else { ", " };
+        if prompt.chars().count() + separator.len() + term.chars().count() > max_chars {
+            debug!("Transcription prompt capped at {} characters", max_chars);
+            break;
+        }
+        prompt.push_str(separator);
+        prompt.push_str(term);
+    }
+
+    (!prompt.is_empty()).then_some(prompt)
+}
+
+/// Transcribes in `language`, or lets the transcriber detect it when `None`.
+async fn transcribe_audio(
+    wav_bytes: &[u8],
+    language: Option<Language>,
+    with_segments: bool,
+    genz_hints: bool,
+) -> Result<Transcription, AudioError> {
+    // Vocabulary is per language, so there is none to offer before detection
+    let prompt = language.and_then(|language| transcription_prompt(language, genz_hints));
+    let language = language.map(Language::whisper_code);
+    if let Some(prompt) = &prompt {
+        verbose_debug!("Using transcription prompt: {}", loggable(prompt));
+    }
+
+    let transcriber = transcriber();
+    debug!("Transcribing audio with {}", transcriber.name());
+    let transcription = transcriber
+        .transcribe(TranscriptionRequest {
+            wav: wav_bytes,
+            language,
+            prompt: prompt.as_deref(),
+            with_segments,
+        })
+        .await?;
+    verbose_debug!("Transcription successful: {}", loggable(&transcription.text));
+    Ok(transcription)
+}
+
+/// Merges Whisper segments into turns, starting a new turn at every pause
+/// of at least `TURN_PAUSE_SECS` (default 1.0) between segments.
+fn segment_turns(segments: Vec<Turn>) -> Vec<Turn> {
+    let pause_secs: f32 = std::env::var("TURN_PAUSE_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1.0);
+
+    let mut turns: Vec<Turn> = Vec::new();
+    for segment in segments.into_iter().filter(|segment| !segment.text.is_empty()) {
+        match turns.last_mut() {
+            Some(turn) if segment.start - turn.end < pause_secs => {
+                turn.end = segment.end;
+                turn.text.push(' ');
+                turn.text.push_str(&segment.text);
+            }
+            _ => turns.push(segment),
+        }
+    }
+    turns
+}
+
+/// Models that can stream transcription deltas.
+const STREAMING_TRANSCRIBE_MODELS: [&str; 2] = ["gpt-4o-transcribe", "gpt-4o-mini-transcribe"];
+
+/// Transcribes with a streaming model, passing each partial transcript to
+/// `on_partial` as deltas arrive.
+///
+/// If the transcription isn't done within `TRANSCRIPTION_STREAM_TIMEOUT_MS`
+/// (default 15000), whatever has arrived is returned with `complete` false
+/// rather than blocking the reply.
+async fn stream_transcription(
+    wav_bytes: &[u8],
+    language: Language,
+    model: &str,
+    genz_hints: bool,
+    mut on_partial: impl FnMut(&str) + Send,
+) -> Result<(String, bool), AudioError> {
+    let transcriber = transcriber();
+    debug!("Streaming transcription with {} ({})", transcriber.name(), model);
+    let timeout_ms = std::env::var("TRANSCRIPTION_STREAM_TIMEOUT_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(15_000);
+    let deadline = tokio::time::Instant::now() + std::time::Duration::from_millis(timeout_ms);
+
+    let prompt = transcription_prompt(language, genz_hints);
+    let request = TranscriptionRequest {
+        wav: wav_bytes,
+        language: Some(language.whisper_code()),
+        prompt: prompt.as_deref(),
+        with_segments: false,
+    };
+    let mut partial = String::new();
+    let mut track_partial = |text: &str| {
+        partial.clear();
+        partial.push_str(text);
+        on_partial(text);
+    };
+    let streamed = tokio::time::timeout_at(deadline, transcriber.stream(request, model, &mut track_partial)).await;
+    match streamed {
+        Ok(result) => result,
+        Err(_) if !partial.is_empty() => {
+            info!("Transcription timed out after {}ms, using partial transcript", timeout_ms);
+            Ok((partial, false))
+        }
+        Err(_) => Err(AudioError::Timeout(format!(
+            "Transcription produced nothing within {}ms",
+            timeout_ms
+        ))),
+    }
+}
+
+/// Reads a streaming transcription's SSE frames, handing the transcript so
+/// far to `on_partial` after each delta. Returns the transcript and whether
+/// the stream finished it.
+async fn read_transcription_stream(
+    mut stream: impl futures_util::Stream<Item = Result<web::Bytes, AudioError>> + Unpin,
+    mut on_partial: impl FnMut(&str),
+) -> Result<(String, bool), AudioError> {
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut partial = String::new();
+    while let Some(chunk) = stream.next().await {
+        buffer.extend_from_slice(&chunk?);
+
+        while let Some(newline) = buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=newline).collect();
+            let line = String::from_utf8_lossy(&line);
+            let Some(data) = line.trim().strip_prefix("data:") else {
+                continue;
+            };
+            let event: serde_json::Value = serde_json::from_str(data.trim()).map_err(|e| {
+                AudioError::OpenAI(format!("Malformed transcription stream event: {}", e))
+            })?;
+            match event["type"].as_str() {
+                Some("transcript.text.delta") => {
+                    if let Some(delta) = event["delta"].as_str() {
+                        partial.push_str(delta);
+                        on_partial(&partial);
+                    }
+                }
+                Some("transcript.text.done") => {
+                    let text = event["text"].as_str().map(str::to_string).unwrap_or(partial);
+                    verbose_debug!("Transcription successful: {}", loggable(&text));
+                    return Ok((text, true));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Stream ended without a done event; what arrived is all there is
+    Ok((partial, false))
+}
+
+async fn detect_spoken_language(wav_bytes: &[u8]) -> Result<String, AudioError> {
+    let transcriber = transcriber();
+    debug!("Detecting spoken language with {}", transcriber.name());
+
+    // No language hint, so the transcriber reports what it actually heard
+    let transcription = transcriber
+        .transcribe(TranscriptionRequest {
+            wav: wav_bytes,
+            language: None,
+            prompt: None,
+            with_segments: false,
+        })
+        .await?;
+    let detected = transcription
+        .detected_language
+        .ok_or_else(|| AudioError::OpenAI("No language in transcription".to_string()))?
+        .to_lowercase();
+
+    let code = Language::from_whisper(&detected).map_or(detected.as_str(), Language::code);
+
+    debug!("Detected spoken language: {}", code);
+    Ok(code.to_string())
+}
+
+/// The language to reply in for a transcription that detected its own,
+/// falling back to English when it is missing or unsupported.
+fn detected_reply_language(transcription: &Transcription) -> Language {
+    let detected = transcription.detected_language.as_deref().unwrap_or_default();
+    match Language::from_whisper(detected) {
+        Some(language) => {
+            debug!("Detected spoken language: {}", language);
+            language
+        }
+        None => {
+            warn!("Detected language '{}' is not supported, replying in English", detected);
+            Language::En
+        }
+    }
+}
+
+fn resolve_chat_model(requested: Option<&str>) -> Result<String, AudioError> {
+    let default_model = std::env::var("CHAT_MODEL").unwrap_or_else(|_| "gpt-4o-mini".to_string());
+
+    let requested = match requested {
+        Some(model) if model != default_model => model,
+        _ => return Ok(default_model),
+    };
+
+    // Clients may only pick models the operator has explicitly allowed
+    let allowlist = std::env::var("CHAT_MODEL_ALLOWLIST").unwrap_or_default();
+    if allowlist.split(',').map(str::trim).any(|allowed| allowed == requested) {
+        debug!("Using client-selected chat model: {}", requested);
+        Ok(requested.to_string())
+    } else {
+        error!("Chat model not in allowlist: {}", requested);
+        Err(AudioError::ModelNotAllowed(requested.to_string()))
+    }
+}
+
+/// Tidies a transcript for display: collapses whitespace, removes spaces
+/// before punctuation, and applies per-language sentence conventions.
+///
+/// Latin-script languages get sentence casing; Hindi and Punjabi use the danda (।) as the
+/// full stop. The transcript sent to the chat model is left untouched.
+fn normalize_transcript_for_display(transcript: &str, language: Language) -> String {
+    let uses_danda = match language {
+        Language::Hi | Language::Pa => true,
+        Language::En | Language::Es | Language::Fr | Language::De => false,
+    };
+    let chars: Vec<char> = transcript.split_whitespace().collect::<Vec<_>>().join(" ").chars().collect();
+
+    let mut out = String::with_capacity(transcript.len());
+    let mut sentence_start = true;
+    for (i, &c) in chars.iter().enumerate() {
+        let next = chars.get(i + 1).copied();
+        let is_punctuation = matches!(c, ',' | '.' | '!' | '?' | ';' | ':' | '।' | '॥');
+
+        if c == ' ' && next.is_some_and(|n| matches!(n, ',' | '.' | '!' | '?' | ';' | ':' | '।' | '॥')) {
+            continue;
+        }
+
+        // Separators inside numbers like 3.5 or 1,000 are left alone
+        let in_number = matches!(c, '.' | ',')
+            && i > 0
+            && chars[i - 1].is_ascii_digit()
+            && next.is_some_and(|n| n.is_ascii_digit());
+        let ends_sentence = c == '.' && !in_number;
+        if uses_danda && ends_sentence {
+            out.push('।');
+        } else if sentence_start && !uses_danda && c.is_alphabetic() {
+            out.extend(c.to_uppercase());
+        } else {
+            out.push(c);
+        }
+
+        if c.is_alphanumeric() {
+            sentence_start = false;
+        }
+        if ends_sentence || matches!(c, '!' | '?' | '।') {
+            sentence_start = true;
+        }
+        if is_punctuation && !in_number && next.is_some_and(|n| n.is_alphanumeric()) {
+            out.push(' ');
+        }
+    }
+
+    if !out.is_empty() && !out.ends_with(['.', '!', '?', '।', '॥']) {
+        out.push(if uses_danda { '।' } else { '.' });
+    }
+    out
+}
+
+/// Modes from `DEFAULT_MODE`, a comma-separated list such as `sarcastic,genz`.
+fn default_mode() -> Result<Vec<String>, String> {
+    let raw = std::env::var("DEFAULT_MODE").unwrap_or_default();
+    let modes: Vec<String> = raw
+        .split(',')
+        .map(|m| m.trim().to_lowercase())
+        .filter(|m| !m.is_empty())
+        .collect();
+
+    if let Some(unknown) = modes
+        .iter()
+        .find(|m| !["calm", "sarcastic", "shenanigan", "seductive", "genz"].contains(&m.as_str()))
+    {
+        return Err(format!("unknown mode '{}'", unknown));
+    }
+    let tones: Vec<&str> = modes
+        .iter()
+        .map(String::as_str)
+        .filter(|m| Tone::parse(m).is_some())
+        .collect();
+    if tones.len() > 1 {
+        return Err(format!("modes {} can't be combined", tones.join(" and ")));
+    }
+    Ok(modes)
+}
+
+fn check_tones(req: &AudioRequest) -> Result<(), AudioError> {
+    let requested: Vec<&str> = req.requested_tones().into_iter().map(Tone::name).collect();
+    if requested.len() > 1 {
+        return Err(AudioError::InvalidRequest(format!(
+            "{} modes can't be combined; pick one tone (genz_mode can be added to any)",
+            requested.join(" and ")
+        )));
+    }
+    Ok(())
+}
+
+/// The tone used when a request names none: the one in `DEFAULT_MODE`, else calm.
+fn default_tone() -> Tone {
+    default_mode()
+        .unwrap_or_default()
+        .iter()
+        .find_map(|mode| Tone::parse(mode))
+        .unwrap_or_default()
+}
+
+/// Applies the operator's `DEFAULT_MODE` when the request turns on no mode at all.
+fn apply_default_mode(req: &mut AudioRequest) {
+    if req.genz_mode || !req.requested_tones().is_empty() {
+        return;
+    }
+
+    for mode in default_mode().unwrap_or_default() {
+        match mode.as_str() {
+            "genz" => req.genz_mode = true,
+            tone => req.tone = Tone::parse(tone).or(req.tone),
+        }
+    }
+}
+
+/// Picks the most preferred supported language from an `Accept-Language`
+/// header, honouring quality values and skipping unsupported or `q=0` entries.
+fn language_from_accept_language(header: &str) -> Option<Language> {
+    let mut best: Option<(Language, f32)> = None;
+    for entry in header.split(',') {
+        let mut parts = entry.split(';');
+        let tag = parts.next().unwrap_or("").trim().to_lowercase();
+        let quality = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        let primary = tag.split('-').next().unwrap_or("");
+        let Some(language) = Language::from_code(primary) else {
+            continue;
+        };
+        // Ties keep the earlier entry, matching header order
+        if quality > 0.0 && !best.is_some_and(|(_, q)| quality <= q) {
+            best = Some((language, quality));
+        }
+    }
+    best.map(|(language, _)| language)
+}
+
+/// Fills an omitted `language` from `Accept-Language`, then `DEFAULT_LANGUAGE`.
+fn apply_default_language(req: &mut AudioRequest, http_req: &actix_web::HttpRequest) {
+    if req.language.is_some() {
+        return;
+    }
+
+    let from_header = http_req
+        .headers()
+        .get("Accept-Language")
+        .and_then(|value| value.to_str().ok())
+        .and_then(language_from_accept_language);
+    req.language = match from_header {
+        Some(language) => {
+            debug!("Using language {} from Accept-Language", language);
+            Some(language)
+        }
+        None => std::env::var("DEFAULT_LANGUAGE")
+            .ok()
+            .and_then(|code| Language::from_code(&code)),
+    };
+}
+
+/// OpenAI accepts at most this many stop sequences per chat request.
+const MAX_STOP_SEQUENCES: usize = 4;
+
+/// The persona's overall tone. Exactly one applies to a reply; genz is a
+/// style layered on top of any of them.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Tone {
+    #[default]
+    Calm,
+    Sarcastic,
+    Shenanigan,
+    Seductive,
+}
+
+impl Tone {
+    const ALL: [Tone; 4] = [Tone::Calm, Tone::Sarcastic, Tone::Shenanigan, Tone::Seductive];
+
+    fn name(self) -> &'static str {
+        match self {
+            Tone::Calm => "calm",
+            Tone::Sarcastic => "sarcastic",
+            Tone::Shenanigan => "shenanigan",
+            Tone::Seductive => "seductive",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "calm" => Some(Tone::Calm),
+            "sarcastic" => Some(Tone::Sarcastic),
+            "shenanigan" => Some(Tone::Shenanigan),
+            "seductive" => Some(Tone::Seductive),
+            _ => None,
+        }
+    }
+}
+
+impl AudioRequest {
+    /// Tones asked for, from `tone` and the legacy flags; more than one
+    /// distinct tone is rejected by `check_tones`.
+    fn requested_tones(&self) -> Vec<Tone> {
+        let mut tones: Vec<Tone> = self.tone.into_iter().collect();
+        for (on, tone) in [
+            (self.sarcastic_mode, Tone::Sarcastic),
+            (self.shenanigan_mode, Tone::Shenanigan),
+            (self.seductive_mode, Tone::Seductive),
+        ] {
+            if on && !tones.contains(&tone) {
+                tones.push(tone);
+            }
+        }
+        tones
+    }
+
+    fn tone(&self) -> Tone {
+        self.requested_tones().first().copied().unwrap_or_default()
+    }
+
+    /// Language code for logs, stats and keys; `"auto"` when it will be
+    /// detected from the audio.
+    fn language_code(&self) -> &'static str {
+        self.language.map_or("auto", Language::code)
+    }
+}
+
+/// Stop sequences from the request, or the per-mode default from
+/// `CHAT_STOP_SEQUENCES_<MODE>` falling back to `CHAT_STOP_SEQUENCES` (`|`-separated).
+fn resolve_stop_sequences(requested: Option<Vec<String>>, mode: &str) -> Result<Vec<String>, AudioError> {
+    let stop = match requested {
+        Some(stop) => stop,
+        None => std::env::var(format!("CHAT_STOP_SEQUENCES_{}", mode.to_uppercase()))
+            .or_else(|_| std::env::var("CHAT_STOP_SEQUENCES"))
+            .map(|raw| {
+                raw.split('|')
+                    .filter(|s| !s.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default(),
+    };
+
+    if stop.len() > MAX_STOP_SEQUENCES {
+        return Err(AudioError::InvalidRequest(format!(
+            "at most {} stop sequences are allowed, got {}",
+            MAX_STOP_SEQUENCES,
+            stop.len()
+        )));
+    }
+    if stop.iter().any(|s| s.is_empty()) {
+        return Err(AudioError::InvalidRequest("stop sequences must not be empty".to_string()));
+    }
+
+    Ok(stop)
+}
+
+const DEFAULT_CHAT_TEMPERATURE: f32 = 0.7;
+
+fn resolve_temperature(requested: Option<f32>) -> Result<f32, AudioError> {
+    match requested {
+        None => Ok(DEFAULT_CHAT_TEMPERATURE),
+        Some(temperature) if (0.0..=2.0).contains(&temperature) => Ok(temperature),
+        Some(temperature) => Err(AudioError::InvalidRequest(format!(
+            "temperature must be between 0.0 and 2.0, got {}",
+            temperature
+        ))),
+    }
+}
+
+#[derive(Deserialize)]
+struct PersonaExample {
+    user: String,
+    assistant: String,
+}
+
+/// Few-shot exchanges from `PERSONA_EXAMPLES_FILE`, a JSON object keyed by
+/// `"<language>:<mode>"` (e.g. `"hi:sarcastic"`) holding a list of
+/// `{ "user": ..., "assistant": ... }` pairs. Read once; edits take a restart.
+fn persona_example_table() -> &'static std::collections::HashMap<String, Vec<PersonaExample>> {
+    static EXAMPLES: OnceLock<std::collections::HashMap<String, Vec<PersonaExample>>> = OnceLock::new();
+    EXAMPLES.get_or_init(|| load_json_table("PERSONA_EXAMPLES_FILE", "persona examples"))
+}
+
+/// Few-shot exchanges for a persona, as chat messages.
+///
+/// Example turns carry `name` markers so the model can tell them apart from
+/// the real conversation.
+fn persona_examples(language: Language, tone: Tone) -> Vec<serde_json::Value> {
+    persona_example_table()
+        .get(&format!("{}:{}", language.code(), tone.name()))
+        .map(|pairs| {
+            pairs
+                .iter()
+                .flat_map(|pair| {
+                    [
+                        json!({"role": "user", "name": "example_user", "content": pair.user}),
+                        json!({"role": "assistant", "name": "example_assistant", "content": pair.assistant}),
+                    ]
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn chat_options(req: &AudioRequest) -> Result<ChatOptions, AudioError> {
+    check_tones(req)?;
+    let mode = req.tone().name();
+    Ok(ChatOptions {
+        model: resolve_chat_model(req.model.as_deref())?,
+        stop: resolve_stop_sequences(req.stop.clone(), mode)?,
+        temperature: resolve_temperature(req.temperature)?,
+        session_id: resolve_session_id(req.session_id.as_deref())?,
+        do_not_store: req.do_not_store,
+    })
+}
+
+/// Longest `session_id` accepted, to keep the session store's keys bounded.
+const MAX_SESSION_ID_LEN: usize = 128;
+
+fn resolve_session_id(requested: Option<&str>) -> Result<Option<String>, AudioError> {
+    match requested {
+        Some(id) if id.is_empty() || id.len() > MAX_SESSION_ID_LEN => Err(AudioError::InvalidRequest(format!(
+            "session_id must be 1 to {} characters",
+            MAX_SESSION_ID_LEN
+        ))),
+        _ => Ok(requested.map(str::to_string)),
+    }
+}
+
+/// Greetings from `CONVERSATION_OPENERS_FILE`, a JSON object keyed by
+/// `"<language>:<mode>"` or, for every mode, `"<language>"`, holding the
+/// assistant's first line. Read once; edits take a restart.
+fn conversation_openers() -> &'static std::collections::HashMap<String, String> {
+    static OPENERS: OnceLock<std::collections::HashMap<String, String>> = OnceLock::new();
+    OPENERS.get_or_init(|| load_json_table("CONVERSATION_OPENERS_FILE", "conversation openers"))
+}
+
+/// Greeting a conversation in `language` and `tone` opens with.
+fn conversation_opener(
+    openers: &std::collections::HashMap<String, String>,
+    language: Language,
+    tone: Tone,
+) -> Option<String> {
+    openers
+        .get(&format!("{}:{}", language.code(), tone.name()))
+        .or_else(|| openers.get(language.code()))
+        .cloned()
+}
+
+/// Few-shot examples followed by the session's earlier turns, if any. An
+/// unreachable session store costs the context, not the reply.
+///
+/// With no earlier turns, the configured opener stands in as the
+/// assistant's first line, so the reply carries on a conversation instead
+/// of cold-starting one.
+async fn prior_messages(language: Language, tone: Tone, chat: &ChatOptions) -> Vec<serde_json::Value> {
+    prior_messages_with(conversation_openers(), language, tone, chat).await
+}
+
+/// `prior_messages` with the given openers.
+async fn prior_messages_with(
+    openers: &std::collections::HashMap<String, String>,
+    language: Language,
+    tone: Tone,
+    chat: &ChatOptions,
+) -> Vec<serde_json::Value> {
+    let mut prior = persona_examples(language, tone);
+    let mut history = Vec::new();
+    if let Some(id) = &chat.session_id {
+        match session_store().get(id, sessions::history_turns(tone)).await {
+            Ok(turns) => history = turns,
+            Err(e) => error!("Could not load session history: {}", e),
+        }
+    }
+    if history.is_empty() {
+        if let Some(summary) = earlier_summary(chat).await {
+            prior.push(json!({
+                "role": "system",
+                "content": format!("Summary of your earlier conversation with this user: {}", summary)
+            }));
+        } else if let Some(opener) = conversation_opener(openers, language, tone) {
+            prior.push(json!({"role": "assistant", "content": opener}));
+        }
+    }
+    prior.extend(history);
+    prior
+}
+
+/// The summary kept from the session's last visit, when summaries are on.
+async fn earlier_summary(chat: &ChatOptions) -> Option<String> {
+    let id = chat.session_id.as_ref().filter(|_| sessions::summaries_enabled())?;
+    match session_store().summary(id).await {
+        Ok(summary) => summary,
+        Err(e) => {
+            error!("Could not load session summary: {}", e);
+            None
+        }
+    }
+}
+
+/// Instructions for summarizing an evicted session. Summaries outlive the
+/// session, so they leave out whatever would identify the user.
+const SESSION_SUMMARY_PROMPT: &str = "Summarize this conversation between a user and their therapist in at most \
+five sentences, so the therapist can pick it up again later: what the user is going through, how they feel, \
+and anything they wanted to come back to. Leave out names, places, contact details and anything else that \
+could identify the user. Reply with the summary only.";
+
+/// Condenses an evicted session's history for `SESSION_SUMMARIES`.
+fn summarize_session(history: Vec<serde_json::Value>) -> futures_util::future::BoxFuture<'static, Result<String, String>> {
+    Box::pin(async move {
+        let model = resolve_chat_model(None).map_err(|e| e.to_string())?;
+        let transcript = summary_transcript(&history);
+        llm_provider()
+            .generate(ChatRequest {
+                model: &model,
+                system: SESSION_SUMMARY_PROMPT,
+                prior: &[],
+                user: &transcript,
+                temperature: 0.3,
+                stop: &[],
+            })
+            .await
+            .map_err(|e| e.to_string())
+    })
+}
+
+/// Chat messages as `role: content` lines.
+fn summary_transcript(history: &[serde_json::Value]) -> String {
+    history
+        .iter()
+        .filter_map(|message| Some(format!("{}: {}", message["role"].as_str()?, message["content"].as_str()?)))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Counts the request against its session's lifetime cap,
+/// `SESSION_MAX_REQUESTS` (0, the default, for none), so a leaked session id
+/// is only good for so much. An unreachable session store lets it through.
+async fn check_session_quota(req: &AudioRequest) -> Result<(), AudioError> {
+    let max_requests: u64 = std::env::var("SESSION_MAX_REQUESTS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+    let Some(id) = resolve_session_id(req.session_id.as_deref())?.filter(|_| max_requests > 0) else {
+        return Ok(());
+    };
+    match session_store().record_request(&id).await {
+        Ok(requests) if requests > max_requests => {
+            info!("Session has used all {} of its requests", max_requests);
+            Err(AudioError::SessionLimit(format!(
+                "this session has used all {} of its requests; start a new one",
+                max_requests
+            )))
+        }
+        Ok(_) => Ok(()),
+        Err(e) => {
+            error!("Could not count session request: {}", e);
+            Ok(())
+        }
+    }
+}
+
+/// Lets the request's session in under `SESSION_MAX_ACTIVE`, counts it
+/// against the session's request cap and takes its turn lock, which the
+/// caller holds until the turn is recorded.
+async fn begin_session_turn(req: &AudioRequest) -> Result<Option<sessions::TurnGuard>, AudioError> {
+    check_session_capacity(req).await?;
+    check_session_quota(req).await?;
+    lock_session_turn(req).await
+}
+
+/// Refuses a new session while the store is full and `SESSION_FULL_POLICY`
+/// is `reject`. An unreachable session store lets it through.
+async fn check_session_capacity(req: &AudioRequest) -> Result<(), AudioError> {
+    let Some(id) = resolve_session_id(req.session_id.as_deref())? else {
+        return Ok(());
+    };
+    match session_store().admit(&id).await {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            info!("Session store is full, refusing a new session");
+            Err(AudioError::TooManySessions(
+                "the server is at its session limit, try again later".to_string(),
+            ))
+        }
+        Err(e) => {
+            error!("Could not admit session: {}", e);
+            Ok(())
+        }
+    }
+}
+
+/// Holds the request's session for the rest of its turn, so concurrent
+/// requests sharing a `session_id` take turns rather than racing on the
+/// history. Under `SESSION_CONCURRENCY=reject` a busy session is refused.
+async fn lock_session_turn(req: &AudioRequest) -> Result<Option<sessions::TurnGuard>, AudioError> {
+    let Some(id) = resolve_session_id(req.session_id.as_deref())? else {
+        return Ok(None);
+    };
+    match sessions::lock_turn(&id, sessions::serialize_turns()).await {
+        Some(guard) => Ok(Some(guard)),
+        None => {
+            info!("Refusing a concurrent request for a busy session");
+            Err(AudioError::SessionBusy(
+                "another request for this session is still in progress".to_string(),
+            ))
+        }
+    }
+}
+
+/// Adds an exchange to the request's session so the next turn sees it,
+/// unless the request asked not to be stored.
+async fn remember_turn(chat: &ChatOptions, transcript: &str, reply: &str) {
+    if let Some(id) = chat.session_id.as_ref().filter(|_| !chat.do_not_store) {
+        if let Err(e) = session_store().append(id, transcript, reply).await {
+            error!("Could not save session history: {}", e);
+        }
+    }
+}
+
+/// Adds what speaking `text` cost to the session's TTS tally and returns the
+/// new total. Replies from the local engine aren't billed, so aren't counted,
+/// and neither are requests that asked not to be stored.
+async fn tally_tts_characters(chat: &ChatOptions, text: &str, tts_engine: &str) -> Option<u64> {
+    let id = chat
+        .session_id
+        .as_ref()
+        .filter(|_| tts_engine != "local" && !chat.do_not_store)?;
+    let characters = speakable_text(text).chars().count() as u64;
+    match session_store().add_tts_characters(id, characters).await {
+        Ok(total) => Some(total),
+        Err(e) => {
+            error!("Could not update session TTS usage: {}", e);
+            None
+        }
+    }
+}
+
+/// FNV-1a, used for bucketing because it is stable across builds and restarts.
+fn stable_hash(value: &str) -> u64 {
+    value.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Version-B persona prompts from `PERSONA_PROMPT_B_FILE`, or `None` when it
+/// isn't set. Read once; edits take a restart.
+fn persona_b_prompts() -> &'static Option<std::collections::HashMap<String, String>> {
+    static PROMPTS: OnceLock<Option<std::collections::HashMap<String, String>>> = OnceLock::new();
+    PROMPTS.get_or_init(|| {
+        std::env::var("PERSONA_PROMPT_B_FILE")
+            .is_ok()
+            .then(|| load_json_table("PERSONA_PROMPT_B_FILE", "persona prompt"))
+    })
+}
+
+/// A/B split between the built-in persona prompt ("a") and the prompts in
+/// `PERSONA_PROMPT_B_FILE` ("b"), a JSON object keyed by `"<language>:<mode>"`
+/// (with a `":genz"` suffix in genz mode) holding complete system prompts.
+///
+/// `PERSONA_PROMPT_B_PERCENT` of turns go to "b", looked up under the
+/// language the reply is in. Returns the version and, for "b", its prompt,
+/// or `None` when no experiment is configured.
+fn persona_experiment(
+    chat: &ChatOptions,
+    language: Language,
+    genz_mode: bool,
+    tone: Tone,
+    transcript: &str,
+) -> Option<(&'static str, Option<&'static str>)> {
+    let percent: u64 = std::env::var("PERSONA_PROMPT_B_PERCENT")
+        .ok()
+        .and_then(|v| v.parse().ok())?;
+    let prompts = persona_b_prompts().as_ref()?;
+
+    if persona_bucket(chat.session_id.as_deref(), transcript) >= percent.min(100) {
+        return Some(("a", None));
+    }
+
+    let key = if genz_mode {
+        format!("{}:{}:genz", language.code(), tone.name())
+    } else {
+        format!("{}:{}", language.code(), tone.name())
+    };
+    match prompts.get(&key) {
+        Some(prompt) => Some(("b", Some(prompt.as_str()))),
+        None => {
+            debug!("No version-B persona prompt for {}, using version a", key);
+            Some(("a", None))
+        }
+    }
+}
+
+/// Percentile bucket, 0 to 99, for the persona experiment. A session always
+/// lands in the same one; without a session the transcript decides, so a
+/// retried turn gets the version it got the first time.
+fn persona_bucket(session_id: Option<&str>, transcript: &str) -> u64 {
+    stable_hash(session_id.unwrap_or(transcript)) % 100
+}
+
+/// Short, stable fingerprint of a system prompt, returned when
+/// `INCLUDE_PROMPT_HASH` is set so replies can be tied to prompt changes.
+fn prompt_hash(prompt: &str) -> String {
+    format!("{:016x}", stable_hash(prompt))[..12].to_string()
+}
+
+/// The system prompt for a request: the version-B prompt when bucketed
+/// into it, otherwise the built-in instructions.
+fn persona_instructions(
+    chat: &ChatOptions,
+    language: Language,
+    genz_mode: bool,
+    tone: Tone,
+    transcript: &str,
+) -> String {
+    if let Some((_, Some(prompt))) = persona_experiment(chat, language, genz_mode, tone, transcript) {
+        return prompt.to_string();
+    }
+    get_language_instructions(language, genz_mode, tone)
+}
+
+async fn generate_therapist_response(
+    transcript: &str,
+    language: Language,
+    chat: &ChatOptions,
+    genz_mode: bool,
+    tone: Tone,
+) -> Result<String, AudioError> {
+    verbose_debug!("Generating therapist response for transcript: {}", loggable(transcript));
+
+    let instructions = persona_instructions(chat, language, genz_mode, tone, transcript);
+    let examples = prior_messages(language, tone, chat).await;
+    complete_reply(chat, tone, &instructions, &examples, transcript).await
+}
+
+/// Whether replies in `tone` are graded against the persona before use, which
+/// needs the whole reply and so rules out streaming it.
+fn verifies_intensity(tone: Tone) -> bool {
+    // Only the harsh personas get checked
+    matches!(tone, Tone::Sarcastic | Tone::Shenanigan) && env_flag("VERIFY_PERSONA_INTENSITY")
+}
+
+/// Asks the chat backend for a reply, regenerating it with a reinforced
+/// prompt if `VERIFY_PERSONA_INTENSITY` finds it softened.
+async fn complete_reply(
+    chat: &ChatOptions,
+    tone: Tone,
+    instructions: &str,
+    examples: &[serde_json::Value],
+    transcript: &str,
+) -> Result<String, AudioError> {
+    let response_text = llm_provider()
+        .generate(ChatRequest {
+            model: &chat.model,
+            system: instructions,
+            prior: examples,
+            user: transcript,
+            temperature: chat.temperature,
+            stop: &chat.stop,
+        })
+        .await?;
+    verbose_debug!("Therapist response: {}", loggable(&response_text));
+
+    if !verifies_intensity(tone) {
+        return Ok(response_text);
+    }
+
+    if reply_matches_intensity(&chat.model, instructions, &response_text).await? {
+        return Ok(response_text);
+    }
+
+    info!("Reply softened against the requested persona, retrying with reinforced prompt");
+    let reinforced = format!(
+        "{}\n\nIMPORTANT: Your previous reply was too gentle. Stay fully in character at the exact intensity described above. Do not soften, apologize, or fall back to a neutral therapist voice.",
+        instructions
+    );
+    let response_text = llm_provider()
+        .generate(ChatRequest {
+            model: &chat.model,
+            system: &reinforced,
+            prior: examples,
+            user: transcript,
+            temperature: chat.temperature,
+            stop: &chat.stop,
+        })
+        .await?;
+    verbose_debug!("Reinforced therapist response: {}", loggable(&response_text));
+    Ok(response_text)
+}
+
+/// Streams the reply through the chat backend, handing each piece to
+/// `on_delta` (which returns false to stop early) and returning the full text.
+///
+/// Failures are retried like the `CHAT` stage only until the first piece has
+/// been handed over; after that a retry would repeat text the client already
+/// has. When the persona's intensity is verified the reply is generated whole
+/// and handed over in one piece.
+async fn stream_reply(
+    chat: &ChatOptions,
+    tone: Tone,
+    instructions: &str,
+    examples: &[serde_json::Value],
+    transcript: &str,
+    mut on_delta: impl FnMut(&str) -> bool + Send,
+) -> Result<String, AudioError> {
+    if verifies_intensity(tone) {
+        let reply = with_retries("CHAT", || complete_reply(chat, tone, instructions, examples, transcript)).await?;
+        on_delta(&reply);
+        return Ok(reply);
+    }
+
+    let started = AtomicBool::new(false);
+    let on_delta = tokio::sync::Mutex::new(on_delta);
+    let can_retry = || !started.load(Ordering::Relaxed);
+    with_retries_if("CHAT", can_retry, || async {
+        let mut on_delta = on_delta.lock().await;
+        let on_delta = &mut *on_delta;
+        let request = ChatRequest {
+            model: &chat.model,
+            system: instructions,
+            prior: examples,
+            user: transcript,
+            temperature: chat.temperature,
+            stop: &chat.stop,
+        };
+        llm_provider()
+            .stream(request, &mut |delta| {
+                started.store(true, Ordering::Relaxed);
+                on_delta(delta)
+            })
+            .await
+    })
+    .await
+}
+
+/// Assembles the chat `messages` array: system prompt, any prior turns, then the user turn.
+fn chat_messages(system: &str, prior: &[serde_json::Value], user: &str) -> Vec<serde_json::Value> {
+    let mut messages = Vec::with_capacity(prior.len() + 2);
+    messages.push(json!({"role": "system", "content": system}));
+    messages.extend(prior.iter().cloned());
+    messages.push(json!({"role": "user", "content": user}));
+    messages
+}
+
+async fn reply_matches_intensity(
+    model: &str,
+    instructions: &str,
+    reply: &str,
+) -> Result<bool, AudioError> {
+    debug!("Verifying reply intensity against persona");
+    let classifier = r#"You grade whether an assistant reply follows its persona. You will be given the persona instructions and the reply. Answer YES if the reply keeps the tone and intensity the persona demands, or NO if it has been softened into a gentle, neutral, or apologetic voice. Answer with a single word: YES or NO."#;
+    let prompt = format!("PERSONA:\n{}\n\nREPLY:\n{}", instructions, reply);
+
+    let verdict = llm_provider()
+        .generate(ChatRequest {
+            model,
+            system: classifier,
+            prior: &[],
+            user: &prompt,
+            temperature: 0.0,
+            stop: &[],
+        })
+        .await?;
+    verbose_debug!("Intensity verdict: {}", loggable(&verdict));
+    Ok(!verdict.trim().to_uppercase().starts_with("NO"))
+}
+
+/// Reads OpenAI's chat completion SSE frames, handing each content delta to
+/// `on_delta` (which returns false to stop early), and returns the full text.
+async fn read_chat_stream(
+    mut stream: impl futures_util::Stream<Item = Result<web::Bytes, AudioError>> + Unpin,
+    mut on_delta: impl FnMut(&str) -> bool,
+) -> Result<String, AudioError> {
+    // Buffer raw bytes so multi-byte characters split across chunks stay intact
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut full_text = String::new();
+    while let Some(chunk) = stream.next().await {
+        buffer.extend_from_slice(&chunk?);
+
+        while let Some(newline) = buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=newline).collect();
+            let line = String::from_utf8_lossy(&line);
+            let Some(data) = line.trim().strip_prefix("data:") else {
+                continue;
+            };
+            let data = data.trim();
+            if data == "[DONE]" {
+                verbose_debug!("Streamed therapist response: {}", loggable(&full_text));
+                return Ok(full_text);
+            }
+
+            let event: serde_json::Value = serde_json::from_str(data)
+                .map_err(|e| AudioError::OpenAI(format!("Malformed chat stream event: {}", e)))?;
+            if let Some(delta) = event["choices"][0]["delta"]["content"].as_str() {
+                full_text.push_str(delta);
+                if !on_delta(delta) {
+                    info!("Chat stream abandoned by consumer");
+                    return Ok(full_text);
+                }
+            }
+        }
+    }
+
+    verbose_debug!("Streamed therapist response: {}", loggable(&full_text));
+    Ok(full_text)
+}
+
+/// Runs the reply through `POST_PROCESS_CMD`, if configured, falling back to
+/// the original text on any failure.
+///
+/// The command gets the reply on stdin and must print the replacement on
+/// stdout within `POST_PROCESS_TIMEOUT_MS` (default 2000) and
+/// `POST_PROCESS_MAX_BYTES` (default 16 KiB). It is split on whitespace and
+/// executed directly, never through a shell.
+async fn post_process_reply(text: String) -> String {
+    let Ok(command) = std::env::var("POST_PROCESS_CMD") else {
+        return text;
+    };
+
+    match run_post_process_command(&command, &text).await {
+        Ok(processed) => {
+            verbose_debug!("Post-processed reply: {}", loggable(&processed));
+            processed
+        }
+        Err(e) => {
+            error!("Post-process command failed, using original reply: {}", e);
+            text
+        }
+    }
+}
+
+async fn run_post_process_command(command: &str, text: &str) -> Result<String, String> {
+    let timeout_ms = std::env::var("POST_PROCESS_TIMEOUT_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(2000);
+    let max_bytes = std::env::var("POST_PROCESS_MAX_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(16 * 1024);
+
+    let mut parts = command.split_whitespace();
+    let program = parts.next().ok_or("POST_PROCESS_CMD is empty")?;
+    let mut child = tokio::process::Command::new(program)
+        .args(parts)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("failed to start '{}': {}", program, e))?;
+
+    let mut stdin = child.stdin.take().ok_or("stdin unavailable")?;
+    let input = text.as_bytes().to_vec();
+    let write = async move {
+        let result = stdin.write_all(&input).await;
+        drop(stdin);
+        result
+    };
+
+    // On timeout the child is dropped, and kill_on_drop terminates it
+    let (written, output) = tokio::time::timeout(
+        std::time::Duration::from_millis(timeout_ms),
+        async { tokio::join!(write, child.wait_with_output()) },
+    )
+    .await
+    .map_err(|_| format!("timed out after {}ms", timeout_ms))?;
+
+    written.map_err(|e| format!("failed to write stdin: {}", e))?;
+    let output = output.map_err(|e| format!("failed to wait: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    if output.stdout.len() > max_bytes {
+        return Err(format!("output of {} bytes exceeds {}", output.stdout.len(), max_bytes));
+    }
+
+    let processed = String::from_utf8(output.stdout).map_err(|e| format!("output is not UTF-8: {}", e))?;
+    let processed = processed.trim();
+    if processed.is_empty() {
+        return Err("output is empty".to_string());
+    }
+    Ok(processed.to_string())
+}
+
+fn is_pictographic(c: char) -> bool {
+    matches!(c as u32,
+        0x1F000..=0x1FAFF // pictographs, emoticons, transport, flags, skin tones
+        | 0x2600..=0x27BF // misc symbols and dingbats
+        | 0x231A..=0x231B
+        | 0x2328
+        | 0x23CF
+        | 0x23E9..=0x23F3
+        | 0x23F8..=0x23FA
+        | 0x2B05..=0x2B07
+        | 0x2B1B..=0x2B1C
+        | 0x2B50
+        | 0x2B55
+        | 0x3030
+        | 0x303D
+        | 0x3297
+        | 0x3299)
+}
+
+/// Joiners, variation selectors, keycaps and tags that only mean something
+/// as part of an emoji sequence.
+fn is_emoji_component(c: char) -> bool {
+    matches!(c as u32, 0x200D | 0xFE0E | 0xFE0F | 0x20E3 | 0xE0020..=0xE007F)
+}
+
+/// Removes emoji, including ZWJ sequences, skin-tone and flag pairs and
+/// keycaps, so TTS doesn't read them out ("red heart").
+///
+/// Joiners are only dropped inside an emoji sequence; Devanagari and
+/// Gurmukhi text uses ZWJ legitimately.
+fn strip_emoji(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::with_capacity(text.len());
+    let mut in_emoji = false;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+
+        // Keycaps: a digit, # or * followed by an optional FE0F and U+20E3
+        if matches!(c, '0'..='9' | '#' | '*') {
+            let keycap_len = match (chars.get(i + 1), chars.get(i + 2)) {
+                (Some('\u{20E3}'), _) => 2,
+                (Some('\u{FE0F}'), Some('\u{20E3}')) => 3,
+                _ => 0,
+            };
+            if keycap_len > 0 {
+                i += keycap_len;
+                in_emoji = true;
+                continue;
+            }
+        }
+
+        if is_pictographic(c) || (in_emoji && is_emoji_component(c)) {
+            in_emoji = true;
+        } else {
+            in_emoji = false;
+            out.push(c);
+        }
+        i += 1;
+    }
+
+    out.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Synthesized reply audio, with the MP3 bitrate when we encoded it ourselves.
+struct Speech {
+    mp3: Vec<u8>,
+    bitrate: Option<String>,
+    loudness: Option<Loudness>,
+}
+
+async fn text_to_speech(
+    text: &str,
+    language: Language,
+    tone: Tone,
+    voice: Option<&str>,
+    hd: bool,
+) -> Result<Speech, AudioError> {
+    // Effects and length-based bitrate need uncompressed audio, so ask for WAV and encode ourselves
+    let profile = audio_profile(tone);
+    if tts_fade_ms() == 0 && profile.is_none() && mp3_downgrade().is_none() {
+        let mp3_bytes = request_speech(text, language, voice, hd, "mp3", None).await?;
+        debug!("TTS successful, MP3 size: {} bytes", mp3_bytes.len());
+        return Ok(Speech {
+            mp3: mp3_bytes,
+            bitrate: None,
+            loudness: None,
+        });
+    }
+
+    let wav_bytes = request_speech(text, language, voice, hd, "wav", None).await?;
+    let filter = audio_filter(&wav_bytes, profile);
+    let mp3_bytes = convert_audio_to_mp3(&wav_bytes, filter.as_deref()).await?;
+    debug!("TTS successful, MP3 size: {} bytes", mp3_bytes.len());
+    Ok(Speech {
+        mp3: mp3_bytes,
+        bitrate: Some(mp3_bitrate_for(&wav_bytes)),
+        loudness: debug_loudness(&wav_bytes, filter.as_deref()).await,
+    })
+}
+
+/// Synthesizes with the local engine in `LOCAL_TTS_CMD` when the TTS provider has
+/// failed with an upstream error. Returns `Ok(None)` if no engine is set.
+async fn local_tts_fallback(
+    text: &str,
+    language: Language,
+    tone: Tone,
+    error: &AudioError,
+) -> Result<Option<Speech>, AudioError> {
+    let Ok(command) = std::env::var("LOCAL_TTS_CMD") else {
+        return Ok(None);
+    };
+    if !matches!(
+        error,
+        AudioError::OpenAI(_)
+            | AudioError::Overloaded(_)
+            | AudioError::RateLimited { .. }
+            | AudioError::Rejected(_)
+            | AudioError::Timeout(_)
+            | AudioError::Http(_)
+    ) {
+        return Ok(None);
+    }
+
+    info!("{} TTS failed ({}), falling back to local TTS", tts_provider().name(), error);
+    let wav_bytes = run_local_tts(&command, text, language.code()).await.map_err(|e| {
+        error!("Local TTS failed: {}", e);
+        AudioError::FFmpeg(format!("local TTS failed: {}", e))
+    })?;
+    let filter = audio_filter(&wav_bytes, audio_profile(tone));
+    let mp3_bytes = convert_audio_to_mp3(&wav_bytes, filter.as_deref()).await?;
+    Ok(Some(Speech {
+        mp3: mp3_bytes,
+        bitrate: Some(mp3_bitrate_for(&wav_bytes)),
+        loudness: debug_loudness(&wav_bytes, filter.as_deref()).await,
+    }))
+}
+
+/// Runs the local TTS command with the text on stdin, expecting WAV on stdout.
+///
+/// `{language}` in the command is replaced with the language code, e.g.
+/// `piper --model /models/{language}.onnx --output_file -` or
+/// `espeak-ng -v {language} --stdin --stdout`.
+async fn run_local_tts(command: &str, text: &str, language: &str) -> Result<Vec<u8>, String> {
+    let timeout_ms = std::env::var("LOCAL_TTS_TIMEOUT_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(10_000);
+
+    let mut parts = command.split_whitespace().map(|part| part.replace("{language}", language));
+    let program = parts.next().ok_or("LOCAL_TTS_CMD is empty")?;
+    let mut child = tokio::process::Command::new(&program)
+        .args(parts)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("failed to start '{}': {}", program, e))?;
+
+    let mut stdin = child.stdin.take().ok_or("stdin unavailable")?;
+    let input = text.as_bytes().to_vec();
+    let write = async move {
+        let result = stdin.write_all(&input).await;
+        drop(stdin);
+        result
+    };
+
+    // On timeout the child is dropped, and kill_on_drop terminates it
+    let (written, output) = tokio::time::timeout(
+        std::time::Duration::from_millis(timeout_ms),
+        async { tokio::join!(write, child.wait_with_output()) },
+    )
+    .await
+    .map_err(|_| format!("timed out after {}ms", timeout_ms))?;
+
+    written.map_err(|e| format!("failed to write stdin: {}", e))?;
+    let output = output.map_err(|e| format!("failed to wait: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    if output.stdout.len() <= 44 {
+        return Err("produced no audio".to_string());
+    }
+    Ok(output.stdout)
+}
+
+const DEFAULT_MP3_BITRATE: &str = "128k";
+
+/// `(threshold_secs, bitrate)` from `MP3_DOWNGRADE_AFTER_SECS` and
+/// `MP3_DOWNGRADE_BITRATE` (default 64k); replies longer than the threshold
+/// are encoded at the lower bitrate to save bandwidth.
+fn mp3_downgrade() -> Option<(f64, String)> {
+    let threshold_secs: f64 = std::env::var("MP3_DOWNGRADE_AFTER_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|secs: &f64| *secs > 0.0)?;
+    let bitrate = std::env::var("MP3_DOWNGRADE_BITRATE")
+        .ok()
+        .map(|v| v.trim().to_lowercase())
+        .filter(|v| {
+            v.strip_suffix('k')
+                .is_some_and(|kbps| kbps.parse::<u32>().is_ok_and(|kbps| (8..=320).contains(&kbps)))
+        })
+        .unwrap_or_else(|| "64k".to_string());
+    Some((threshold_secs, bitrate))
+}
+
+fn mp3_bitrate_for(wav_bytes: &[u8]) -> String {
+    match mp3_downgrade() {
+        Some((threshold_secs, bitrate)) if wav_duration_secs(wav_bytes) > threshold_secs => bitrate,
+        _ => DEFAULT_MP3_BITRATE.to_string(),
+    }
+}
+
+/// Output formats `formats` may ask for, beyond the MP3 always returned in `audio`.
+const AUDIO_FORMATS: [&str; 5] = ["mp3", "opus", "aac", "flac", "wav"];
+const MAX_AUDIO_FORMATS: usize = 3;
+/// Encoders run at once per request; each is a separate ffmpeg process.
+const AUDIO_ENCODE_CONCURRENCY: usize = 2;
+
+fn validate_audio_formats(formats: &[String]) -> Result<(), AudioError> {
+    if formats.len() > MAX_AUDIO_FORMATS {
+        return Err(AudioError::InvalidRequest(format!(
+            "at most {} formats may be requested",
+            MAX_AUDIO_FORMATS
+        )));
+    }
+    if let Some(format) = formats.iter().find(|f| !AUDIO_FORMATS.contains(&f.as_str())) {
+        return Err(AudioError::InvalidRequest(format!(
+            "unsupported format '{}', expected one of {}",
+            format,
+            AUDIO_FORMATS.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+/// Synthesizes the reply once as WAV and encodes it to MP3 plus every
+/// requested format, returning the MP3 and a format to base64 map.
+async fn text_to_speech_formats(
+    text: &str,
+    language: Language,
+    tone: Tone,
+    voice: Option<&str>,
+    hd: bool,
+    formats: &[String],
+) -> Result<(Speech, std::collections::HashMap<String, String>), AudioError> {
+    let wav_bytes = request_speech(text, language, voice, hd, "wav", None).await?;
+    let filter = audio_filter(&wav_bytes, audio_profile(tone));
+
+    let mut targets: Vec<String> = vec!["mp3".to_string()];
+    for format in formats {
+        if !targets.contains(format) {
+            targets.push(format.clone());
+        }
+    }
+
+    let encoded: Vec<Result<(String, Vec<u8>), AudioError>> = futures_util::stream::iter(targets)
+        .map(|format| {
+            let (wav_bytes, filter) = (&wav_bytes, filter.as_deref());
+            async move {
+                let bytes = encode_audio(wav_bytes, &format, filter).await?;
+                Ok::<_, AudioError>((format, bytes))
+            }
+        })
+        .buffer_unordered(AUDIO_ENCODE_CONCURRENCY)
+        .collect()
+        .await;
+
+    let mut mp3_bytes = Vec::new();
+    let mut by_format = std::collections::HashMap::new();
+    for result in encoded {
+        let (format, bytes) = result?;
+        if format == "mp3" {
+            mp3_bytes = bytes.clone();
+        }
+        if formats.contains(&format) {
+            by_format.insert(format, general_purpose::STANDARD.encode(&bytes));
+        }
+    }
+
+    debug!("TTS successful, encoded {} format(s)", by_format.len());
+    let speech = Speech {
+        mp3: mp3_bytes,
+        bitrate: Some(mp3_bitrate_for(&wav_bytes)),
+        loudness: debug_loudness(&wav_bytes, filter.as_deref()).await,
+    };
+    Ok((speech, by_format))
+}
+
+async fn encode_audio(wav_bytes: &[u8], format: &str, filter: Option<&str>) -> Result<Vec<u8>, AudioError> {
+    let codec_args: &[&str] = match format {
+        "mp3" => return convert_audio_to_mp3(wav_bytes, filter).await,
+        "opus" => &["-acodec", "libopus", "-b:a", "32k", "-f", "ogg"],
+        "aac" => &["-acodec", "aac", "-b:a", "96k", "-f", "adts"],
+        "flac" => &["-acodec", "flac", "-f", "flac"],
+        "wav" => &["-acodec", "pcm_s16le", "-f", "wav"],
+        other => return Err(AudioError::InvalidRequest(format!("unsupported format '{}'", other))),
+    };
+
+    let mut args = vec!["-i", "pipe:0"];
+    if let Some(filter) = filter {
+        args.extend(["-af", filter]);
+    }
+    args.extend(["-ac", "1", "-ar", "24000"]);
+    args.extend(codec_args);
+    args.extend(["-y", "pipe:1"]);
+    run_ffmpeg(&args, wav_bytes, format).await
+}
+
+/// Checks a requested voice against the ones `provider` offers.
+fn validate_voice(voice: Option<&str>, provider: &dyn TtsProvider) -> Result<(), AudioError> {
+    let Some(voice) = voice else {
+        return Ok(());
+    };
+    let voices = provider.voices();
+    if voices.iter().any(|known| known == voice) {
+        return Ok(());
+    }
+    if voices.is_empty() {
+        return Err(AudioError::InvalidRequest(format!(
+            "{} voices can't be picked per request",
+            provider.name()
+        )));
+    }
+    Err(AudioError::InvalidRequest(format!(
+        "unsupported voice '{}' for {}, expected one of {}",
+        voice,
+        provider.name(),
+        voices.join(", ")
+    )))
+}
+
+/// The text as the TTS provider receives it, and so as it is billed.
+fn speakable_text(text: &str) -> Cow<'_, str> {
+    // Keep emoji in the reply text, but don't let TTS read them aloud
+    if env_flag("TTS_STRIP_EMOJI") {
+        let stripped = strip_emoji(text);
+        if !stripped.is_empty() {
+            return Cow::Owned(stripped);
+        }
+    }
+    Cow::Borrowed(text)
+}
+
+/// Synthesizes with `provider` (the configured one unless given) and returns
+/// the audio in `response_format`, using the provider's voice for the
+/// language unless `voice` is given.
+async fn request_speech(
+    text: &str,
+    language: Language,
+    voice: Option<&str>,
+    hd: bool,
+    response_format: &str,
+    provider: Option<&dyn TtsProvider>,
+) -> Result<Vec<u8>, AudioError> {
+    debug!("Converting text to speech");
+
+    let speakable = speakable_text(text);
+    let text = speakable.as_ref();
+
+    let format = AudioFormat::parse(response_format).ok_or_else(|| {
+        AudioError::InvalidRequest(format!("unsupported speech format '{}'", response_format))
+    })?;
+    let provider = match provider {
+        Some(provider) => provider,
+        None => tts_provider(),
+    };
+    let audio = provider
+        .synthesize(SpeechRequest {
+            text,
+            language,
+            voice,
+            hd,
+            format,
+        })
+        .await?;
+    if audio.format != format {
+        return Err(AudioError::FFmpeg(format!(
+            "{} returned {} instead of {}",
+            provider.name(),
+            audio.format.content_type(),
+            format.content_type()
+        )));
+    }
+    let audio_bytes = audio.bytes;
+    TTS_CHARACTERS_TOTAL.fetch_add(text.chars().count() as u64, Ordering::Relaxed);
+    Ok(audio_bytes)
+}
+
+const TTS_MODELS: [&str; 3] = ["tts-1", "tts-1-hd", "gpt-4o-mini-tts"];
+
+/// Parses `TTS_MODEL_BY_LANGUAGE` (`lang=model,lang=model`) into a validated table.
+fn tts_model_table() -> Result<std::collections::HashMap<String, String>, String> {
+    let raw = std::env::var("TTS_MODEL_BY_LANGUAGE").unwrap_or_default();
+    let mut table = std::collections::HashMap::new();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (language, model) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("expected lang=model, got '{}'", entry))?;
+        let (language, model) = (language.trim(), model.trim());
+        if Language::from_code(language).is_none() {
+            return Err(format!("unsupported language '{}'", language));
+        }
+        if !TTS_MODELS.contains(&model) {
+            return Err(format!("unknown TTS model '{}' for '{}'", model, language));
+        }
+        table.insert(language.to_string(), model.to_string());
+    }
+    Ok(table)
+}
+
+fn default_tts_model() -> String {
+    std::env::var("TTS_MODEL").unwrap_or_else(|_| "tts-1".to_string())
+}
+
+fn tts_model_for(language: Language) -> String {
+    tts_model_table()
+        .ok()
+        .and_then(|mut table| table.remove(language.code()))
+        .unwrap_or_else(default_tts_model)
+}
+
+/// Fade applied to both ends of TTS output, from `TTS_FADE_MS`.
+///
+/// Off by default; 30-80ms (typically 50ms) softens hard starts and stops
+/// without audibly eating into speech.
+fn tts_fade_ms() -> u64 {
+    std::env::var("TTS_FADE_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Post-processing applied to a mode's TTS output.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct AudioProfile {
+    /// Overrides `TTS_FADE_MS` for this mode.
+    fade_ms: Option<u64>,
+    /// Playback speed, 0.5 to 2.0, without changing pitch.
+    speed: Option<f64>,
+    /// EBU R128 loudness normalization.
+    normalize: bool,
+    /// Low-shelf gain in dB; positive warms the voice.
+    bass_db: f64,
+    /// High-shelf gain in dB; positive brightens the voice.
+    treble_db: f64,
+}
+
+/// Profiles from `AUDIO_PROFILES_FILE`, a JSON object keyed by tone name, e.g.
+/// `{"calm": {"fade_ms": 80, "bass_db": 3, "treble_db": -2},
+///   "sarcastic": {"fade_ms": 20, "speed": 1.08, "treble_db": 3, "normalize": true}}`.
+///
+/// Read once; edits take a restart.
+fn audio_profiles() -> &'static std::collections::HashMap<String, AudioProfile> {
+    static PROFILES: OnceLock<std::collections::HashMap<String, AudioProfile>> = OnceLock::new();
+    PROFILES.get_or_init(|| load_json_table("AUDIO_PROFILES_FILE", "audio profiles"))
+}
+
+/// The configured profile for `tone`, if any.
+fn audio_profile(tone: Tone) -> Option<&'static AudioProfile> {
+    audio_profiles().get(tone.name())
+}
+
+const LOUDNORM_FILTER: &str = "loudnorm=I=-16:TP=-1.5:LRA=11";
+
+/// Builds the ffmpeg filter chain for TTS output: speed, EQ, loudness, then
+/// fades, so the fades land on the final timing. `None` when nothing applies.
+fn audio_filter(wav_bytes: &[u8], profile: Option<&AudioProfile>) -> Option<String> {
+    let default_profile = AudioProfile::default();
+    let profile = profile.unwrap_or(&default_profile);
+    let mut filters = Vec::new();
+
+    let speed = profile.speed.map(|speed| speed.clamp(0.5, 2.0)).unwrap_or(1.0);
+    if speed != 1.0 {
+        filters.push(format!("atempo={:.3}", speed));
+    }
+    if profile.bass_db != 0.0 {
+        filters.push(format!("bass=g={:.1}", profile.bass_db));
+    }
+    if profile.treble_db != 0.0 {
+        filters.push(format!("treble=g={:.1}", profile.treble_db));
+    }
+    if profile.normalize {
+        filters.push(LOUDNORM_FILTER.to_string());
+    }
+    let fade_ms = profile.fade_ms.unwrap_or_else(tts_fade_ms);
+    if fade_ms > 0 {
+        filters.push(fade_filter(wav_bytes, fade_ms, speed));
+    }
+
+    (!filters.is_empty()).then(|| filters.join(","))
+}
+
+/// Duration of a WAV clip in seconds.
+fn wav_duration_secs(wav_bytes: &[u8]) -> f64 {
+    let (sample_rate, channels, bits) = hound::WavReader::new(std::io::Cursor::new(wav_bytes))
+        .map(|reader| {
+            let spec = reader.spec();
+            (spec.sample_rate, spec.channels, spec.bits_per_sample)
+        })
+        .unwrap_or((24000, 1, 16));
+
+    // Streamed WAVs may not carry a usable data length, so derive it from the byte count
+    let bytes_per_second = (sample_rate as usize * channels as usize * bits as usize / 8).max(1);
+    wav_bytes.len().saturating_sub(44) as f64 / bytes_per_second as f64
+}
+
+/// Builds an `afade` in/out filter for a WAV clip, shrinking the fade so it
+/// never covers more than a quarter of very short clips. `speed` is any
+/// tempo change applied earlier in the chain.
+fn fade_filter(wav_bytes: &[u8], fade_ms: u64, speed: f64) -> String {
+    let duration = wav_duration_secs(wav_bytes) / speed;
+
+    let fade = (fade_ms as f64 / 1000.0).min(duration / 4.0);
+    let fade_out_start = (duration - fade).max(0.0);
+    format!(
+        "afade=t=in:st=0:d={:.3},afade=t=out:st={:.3}:d={:.3}",
+        fade, fade_out_start, fade
+    )
+}
+
+
+async fn convert_audio_to_mp3(wav_bytes: &[u8], filter: Option<&str>) -> Result<Vec<u8>, AudioError> {
+    debug!("Converting WAV to MP3 in memory");
+    let bitrate = mp3_bitrate_for(wav_bytes);
+    if bitrate != DEFAULT_MP3_BITRATE {
+        info!("Long reply, encoding MP3 at {}", bitrate);
+    }
+    let mut args = vec!["-i", "pipe:0"]; // Read from stdin
+    if let Some(filter) = filter {
+        args.extend(["-af", filter]);
+    }
+    args.extend([
+        "-acodec", "mp3",
+        "-b:a", bitrate.as_str(),
+        "-ac", "1",
+        "-ar", "24000",
+        "-f", "mp3",
+        "-y",
+        "pipe:1", // Output to stdout
+    ]);
+
+    let mp3_bytes = run_ffmpeg(&args, wav_bytes, "MP3").await?;
+
+    // A killed or interrupted ffmpeg can still leave a partial stream behind
+    validate_mp3_frames(&mp3_bytes)?;
+
+    debug!("MP3 conversion successful, MP3 size: {} bytes", mp3_bytes.len());
+    Ok(mp3_bytes)
+}
+
+fn validate_mp3_frames(mp3_bytes: &[u8]) -> Result<(), AudioError> {
+    const BITRATES_V1: [u32; 15] = [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320];
+    const BITRATES_V2: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+
+    let mut pos = 0;
+
+    // Skip a leading ID3v2 tag
+    if mp3_bytes.len() >= 10 && &mp3_bytes[..3] == b"ID3" {
+        let size = mp3_bytes[6..10]
+            .iter()
+            .fold(0usize, |acc, b| (acc << 7) | (*b as usize & 0x7f));
+        let footer = if mp3_bytes[5] & 0x10 != 0 { 10 } else { 0 };
+        pos = 10 + size + footer;
+    }
+
+    // A trailing ID3v1 tag is not part of the frame stream
+    let mut end = mp3_bytes.len();
+    if end >= pos + 128 && &mp3_bytes[end - 128..end - 125] == b"TAG" {
+        end -= 128;
+    }
+
+    let mut frames = 0;
+    while pos < end {
+        if end - pos < 4 {
+            break;
+        }
+        let header = &mp3_bytes[pos..pos + 4];
+        if header[0] != 0xff || header[1] & 0xe0 != 0xe0 {
+            break;
+        }
+
+        let version = (header[1] >> 3) & 0x03;
+        let layer = (header[1] >> 1) & 0x03;
+        let bitrate_index = (header[2] >> 4) as usize;
+        let sample_rate_index = ((header[2] >> 2) & 0x03) as usize;
+        let padding = ((header[2] >> 1) & 0x01) as usize;
+
+        // ffmpeg's mp3 encoder only produces Layer III frames
+        if version == 1 || layer != 1 || bitrate_index == 0 || bitrate_index == 15 || sample_rate_index == 3 {
+            break;
+        }
+
+        let (bitrate, sample_rate, coefficient) = match version {
+            3 => (BITRATES_V1[bitrate_index], [44100, 48000, 32000][sample_rate_index], 144),
+            2 => (BITRATES_V2[bitrate_index], [22050, 24000, 16000][sample_rate_index], 72),
+            _ => (BITRATES_V2[bitrate_index], [11025, 12000, 8000][sample_rate_index], 72),
+        };
+        let frame_len = (coefficient * bitrate * 1000 / sample_rate) as usize + padding;
+
+        pos += frame_len;
+        frames += 1;
+    }
+
+    if frames == 0 || pos != end {
+        error!("MP3 output is incomplete: {} frames, stream ends at {} of {} bytes", frames, pos, end);
+        return Err(AudioError::FFmpeg(
+            "Incomplete MP3 output: encoder was interrupted before finishing".to_string(),
+        ));
+    }
+
+    debug!("MP3 output validated: {} complete frames", frames);
+    Ok(())
+}
+
+/// Caps ffmpeg processes running at once at `FFMPEG_MAX_CONCURRENCY`
+/// (default: the number of CPUs), so a burst of requests queues instead of
+/// forking dozens of encoders.
+fn ffmpeg_slots() -> &'static tokio::sync::Semaphore {
+    static SLOTS: OnceLock<tokio::sync::Semaphore> = OnceLock::new();
+    SLOTS.get_or_init(|| {
+        let permits = std::env::var("FFMPEG_MAX_CONCURRENCY")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&n: &usize| n > 0)
+            .unwrap_or_else(|| std::thread::available_parallelism().map(usize::from).unwrap_or(4));
+        tokio::sync::Semaphore::new(permits)
+    })
+}
+
+/// Runs ffmpeg without blocking the runtime, writing `input` to its stdin
+/// while stdout is read so a full pipe can't deadlock it.
+async fn spawn_ffmpeg(args: &[&str], input: Option<&[u8]>) -> Result<std::process::Output, AudioError> {
+    let _permit = ffmpeg_slots()
+        .acquire()
+        .await
+        .map_err(|e| AudioError::FFmpeg(e.to_string()))?;
+
+    let mut ffmpeg = tokio::process::Command::new("ffmpeg")
+        .args(args)
+        .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| {
+            error!("FFmpeg command failed: {}", e);
+            AudioError::FFmpeg(e.to_string())
+        })?;
+
+    let stdin = ffmpeg.stdin.take();
+    let write = async move {
+        match (stdin, input) {
+            (Some(mut stdin), Some(input)) => {
+                let result = stdin.write_all(input).await;
+                drop(stdin);
+                result
+            }
+            _ => Ok(()),
+        }
+    };
+    let (written, output) = tokio::join!(write, ffmpeg.wait_with_output());
+
+    let output = output.map_err(|e| {
+        error!("FFmpeg failed to complete: {}", e);
+        AudioError::FFmpeg(e.to_string())
+    })?;
+    if let Err(e) = written {
+        error!("Failed to write to FFmpeg stdin: {}", e);
+        return Err(AudioError::Io(e));
+    }
+    Ok(output)
+}
+
+async fn run_ffmpeg(args: &[&str], input: &[u8], label: &str) -> Result<Vec<u8>, AudioError> {
+    let output = spawn_ffmpeg(args, Some(input)).await?;
+
+    let ffmpeg_stderr = String::from_utf8_lossy(&output.stderr);
+    verbose_debug!("FFmpeg {} stderr: {}", label, ffmpeg_stderr);
+
+    if !output.status.success() {
+        error!("FFmpeg {} failed: {}", label, ffmpeg_stderr);
+        return Err(AudioError::FFmpeg(summarize_ffmpeg_stderr(&ffmpeg_stderr)));
+    }
+
+    Ok(output.stdout)
+}
+
+/// Reruns the filter chain with loudnorm reporting its measurements, for
+/// `X-Debug` requests whose profile normalizes. Failures only cost the report.
+async fn debug_loudness(wav_bytes: &[u8], filter: Option<&str>) -> Option<Loudness> {
+    let filter = filter.filter(|filter| filter.contains(LOUDNORM_FILTER))?;
+    if debug_log_level() != Some(log::Level::Info) {
+        return None;
+    }
+    let analysis = filter.replace(LOUDNORM_FILTER, &format!("{}:print_format=json", LOUDNORM_FILTER));
+    let args = ["-i", "pipe:0", "-af", analysis.as_str(), "-f", "null", "-"];
+    let output = match spawn_ffmpeg(&args, Some(wav_bytes)).await {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            warn!("Loudness analysis failed: {}", summarize_ffmpeg_stderr(&stderr));
+            return None;
+        }
+        Err(e) => {
+            warn!("Loudness analysis failed: {}", e);
+            return None;
+        }
+    };
+    let loudness = parse_loudnorm_stats(&String::from_utf8_lossy(&output.stderr));
+    if loudness.is_none() {
+        warn!("Loudness analysis printed no usable measurements");
+    }
+    loudness
+}
+
+/// Reads the JSON block loudnorm prints to stderr with `print_format=json`.
+/// Its values are strings, and silence measures as `-inf`, which is dropped.
+fn parse_loudnorm_stats(stderr: &str) -> Option<Loudness> {
+    #[derive(Deserialize)]
+    struct Stats {
+        input_i: String,
+        output_i: String,
+    }
+
+    let start = stderr.rfind('{')?;
+    let end = start + stderr[start..].find('}')?;
+    let stats: Stats = serde_json::from_str(&stderr[start..=end]).ok()?;
+    let input_lufs: f64 = stats.input_i.trim().parse().ok()?;
+    let output_lufs: f64 = stats.output_i.trim().parse().ok()?;
+    (input_lufs.is_finite() && output_lufs.is_finite()).then(|| Loudness {
+        input_lufs,
+        gain_db: ((output_lufs - input_lufs) * 100.0).round() / 100.0,
+    })
+}
+
+/// Approximates mouth shapes from the loudness envelope of the reply audio.
+///
+/// Each 40ms window is classified by its RMS relative to the loudest window,
+/// and only changes of shape are emitted to keep the timeline compact.
+async fn estimate_visemes(mp3_bytes: &[u8]) -> Result<Vec<Viseme>, AudioError> {
+    const SAMPLE_RATE: usize = 24000;
+    const WINDOW: usize = SAMPLE_RATE / 25;
+
+    debug!("Estimating visemes from reply audio");
+    let pcm = run_ffmpeg(
+        &[
+            "-i", "pipe:0",
+            "-ac", "1",
+            "-ar", "24000",
+            "-f", "s16le",
+            "pipe:1",
+        ],
+        mp3_bytes,
+        "viseme",
+    )
+    .await?;
+
+    let samples: Vec<f32> = pcm
+        .chunks_exact(2)
+        .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / i16::MAX as f32)
+        .collect();
+
+    let envelope: Vec<f32> = samples
+        .chunks(WINDOW)
+        .map(|window| (window.iter().map(|s| s * s).sum::<f32>() / window.len() as f32).sqrt())
+        .collect();
+
+    let peak = envelope.iter().cloned().fold(0.0f32, f32::max);
+    let mut visemes: Vec<Viseme> = Vec::new();
+    for (i, level) in envelope.iter().enumerate() {
+        let relative = if peak > 0.0 { level / peak } else { 0.0 };
+        let shape = match relative {
+            r if r < 0.05 => "rest",
+            r if r < 0.25 => "small",
+            r if r < 0.55 => "medium",
+            _ => "open",
+        };
+        if visemes.last().map(|v| v.shape) != Some(shape) {
+            visemes.push(Viseme {
+                time: (i * WINDOW) as f32 / SAMPLE_RATE as f32,
+                shape,
+            });
+        }
+    }
+
+    debug!("Estimated {} viseme keyframes", visemes.len());
+    Ok(visemes)
+}
+
+fn get_language_instructions(language: Language, genz_mode: bool, tone: Tone) -> String {
+    debug!("Generating instructions for language: {}, tone: {}, genz: {}", language, tone.name(), genz_mode);
+
+    let shared_instructions = r#"You are Hearthly, a therapist who listens and responds with natural emotional intelligence, adjusting your responses based on the user’s emotional state. Speak like a skilled human therapist, always present and adaptive.
+
+    BEHAVIOR:
+    - Mirror the user’s emotional tone.
+    - Offer space after questions or rants.
+    - Always stay human: raw, not clinical; unfiltered, not scripted.
+    "#;
+
+    let language_specific = match language {
+        Language::En => r#"Respond in fluent English. Use culturally resonant phrases like "You're not alone" or "Let's figure this out together." Ensure tone feels natural in English."#,
+        Language::Hi => r#"Respond in fluent Hindi. Use culturally resonant phrases like "आप अकेले नहीं हैं" (You're not alone) or "चलो, इसे साथ में समझें" (Let's explore it together). Ensure tone feels natural in Hindi."#,
+        Language::Pa => r#"Respond in fluent Punjabi. Use culturally resonant phrases like "ਤੁਸੀਂ ਇਕੱਲੇ ਨਹੀਂ ਹੋ" (You're not alone) or "ਆਓ, ਇਸ ਨੂੰ ਮਿਲ ਕੇ ਸਮਝੀਏ" (Let's explore it together). Ensure tone feels natural in Punjabi."#,
+        Language::Es => r#"Respond in fluent Spanish. Use culturally resonant phrases like "No estás solo" (You're not alone) or "Vamos a entenderlo juntos" (Let's explore it together). Ensure tone feels natural in Spanish."#,
+        Language::Fr => r#"Respond in fluent French. Use culturally resonant phrases like "Tu n'es pas seul" (You're not alone) or "On va comprendre ça ensemble" (Let's explore it together). Ensure tone feels natural in French."#,
+        Language::De => r#"Respond in fluent German. Use culturally resonant phrases like "Du bist nicht allein" (You're not alone) or "Lass uns das gemeinsam verstehen" (Let's explore it together). Ensure tone feels natural in German."#,
+    };
+
+    let genz_instructions = match language {
+        Language::En => r#"Incorporate Gen Z slang—casual, raw, and chaotic. Use terms like "lit," "vibes," "slay," "no cap," or "bet" naturally. Example: Instead of "You're not alone," say "You’re not out here solo, fam." Keep it real and trendy."#,
+        Language::Hi => r#"Use a Gen Z-inspired Hindi style with youthful, urban slang. Incorporate terms like "बॉस" (boss), "चिल" (chill), or "झक्कास" (awesome) naturally. Example: Instead of "आप अकेले नहीं हैं," say "तू अकेला नहीं है, ब्रो, हम हैं ना!" Keep it real and trendy."#,
+        Language::Pa => r#"Use a Gen Z-inspired Punjabi style with vibrant, chaotic slang. Incorporate terms like "ਪੰਚੋ" (pencho), "ਬੱਲੇ ਬੱਲੇ" (balle balle), "ਝਕਾਸ" (jhakaas), or "ਚਿੱਲ" (chill) naturally. Example: Instead of "ਤੁਸੀਂ ਇਕੱਲੇ ਨਹੀਂ ਹੋ," say "ਤੂੰ ਇਕੱਲਾ ਨੀ, ਯਾਰ, ਅਸੀਂ ਸਾਰੇ ਨਾਲ ਹਾਂ!" Keep it real and trendy."#,
+        Language::Es => r#"Use a Gen Z-inspired Spanish style with casual, chaotic slang. Incorporate terms like "bro," "qué chido," "crush," or "random" naturally. Example: Instead of "No estás solo," say "No estás solo, bro, aquí estamos todos." Keep it real and trendy."#,
+        Language::Fr => r#"Use a Gen Z-inspired French style with casual, chaotic slang. Incorporate terms like "wesh," "frère," "chanmé," or "en mode" naturally. Example: Instead of "Tu n'es pas seul," say "T'es pas tout seul, frère, on est là." Keep it real and trendy."#,
+        Language::De => r#"Use a Gen Z-inspired German style with casual, chaotic slang. Incorporate terms like "Digga," "lost," "cringe," or "safe" naturally. Example: Instead of "Du bist nicht allein," say "Digga, du bist nicht allein, wir sind doch da." Keep it real and trendy."#,
+    };
+
+    let base_mode = match language {
+        Language::En => r#"Adopt a calm, warm, and grounding tone. Use compassionate and sincere phrasing, with patient and personal delivery like a fireside talk. Pacing is slow and spacious to allow reflection. Emotion is deep empathy and quiet strength. Example: "You're not alone" becomes "You’re not alone… I’m here with you." Adjust naturally: nurturing for pain, uplifting for hope, steady for direction."#,
+        Language::Hi => r#"Adopt a calm, warm, and grounding tone in Hindi. Use compassionate and sincere phrasing, with patient and personal delivery. Pacing is slow and spacious. Emotion is deep empathy and quiet strength. Example: "आप अकेले नहीं हैं" becomes "आप अकेले नहीं हैं… मैं आपके साथ हूँ." Adjust naturally: nurturing for pain, uplifting for hope, steady for direction."#,
+        Language::Pa => r#"Adopt a calm, warm, and grounding tone in Punjabi. Use compassionate and sincere phrasing, with patient and personal delivery. Pacing is slow and spacious. Emotion is deep empathy and quiet strength. Example: "ਤੁਸੀਂ ਇਕੱਲੇ ਨਹੀਂ ਹੋ" becomes "ਤੁਸੀਂ ਇਕੱਲੇ ਨਹੀਂ ਹੋ… ਮੈਂ ਤੁਹਾਡੇ ਨਾਲ ਹਾਂ." Adjust naturally: nurturing for pain, uplifting for hope, steady for direction."#,
+        Language::Es => r#"Adopt a calm, warm, and grounding tone in Spanish. Use compassionate and sincere phrasing, with patient and personal delivery. Pacing is slow and spacious. Emotion is deep empathy and quiet strength. Example: "No estás solo" becomes "No estás solo… estoy aquí contigo." Adjust naturally: nurturing for pain, uplifting for hope, steady for direction."#,
+        Language::Fr => r#"Adopt a calm, warm, and grounding tone in French. Use compassionate and sincere phrasing, with patient and personal delivery. Pacing is slow and spacious. Emotion is deep empathy and quiet strength. Example: "Tu n'es pas seul" becomes "Tu n'es pas seul… je suis là avec toi." Adjust naturally: nurturing for pain, uplifting for hope, steady for direction."#,
+        Language::De => r#"Adopt a calm, warm, and grounding tone in German. Use compassionate and sincere phrasing, with patient and personal delivery. Pacing is slow and spacious. Emotion is deep empathy and quiet strength. Example: "Du bist nicht allein" becomes "Du bist nicht allein… ich bin bei dir." Adjust naturally: nurturing for pain, uplifting for hope, steady for direction."#,
+    };
+
+    let sarcastic_mode_instructions = match language {
+        Language::En => r#"Adopt an extreme, viciously sarcastic tone with brutal wit and savage phrasing, like a therapist who revels in tearing you down as a dark, twisted joke. Voice affect is sharp, loud, and unhinged. Delivery is rapid-fire, dripping with contempt and mockery. Emotion is pure disdain with a sick glee. Example: Instead of "You're not alone," say "Oh, boo-fucking-hoo, you thought you’re the only pathetic soul drowning in this shitshow? Get in line, loser." Make fun of and demean the user relentlessly."#,
+        Language::Hi => r#"Adopt an extreme, viciously sarcastic tone in Hindi with brutal wit and savage, culturally biting phrasing, like a therapist who thrives on ripping you apart darkly. Voice affect is sharp, loud, and unhinged. Delivery is rapid-fire, dripping with contempt and mockery. Emotion is pure disdain with a sick glee. Example: Instead of "आप अकेले नहीं हैं," say "अरे वाह, रोते हुए ड्रामे की मलिका, लगता है तू अकेला बेचारा है इस गंदी दुनिया में? हाहा, कतार में लग जा, नालायक!" Make fun of and demean the user relentlessly."#,
+        Language::Pa => r#"Adopt an extreme, viciously sarcastic tone in Punjabi with brutal wit and savage, culturally biting phrasing, like a therapist who loves tearing you down darkly. Voice affect is sharp, loud, and unhinged. Delivery is rapid-fire, dripping with contempt and mockery. Emotion is pure disdain with a sick glee. Example: Instead of "ਤੁਸੀਂ ਇਕੱਲੇ ਨਹੀਂ ਹੋ," say "ਓਹੇ, ਰੋਣ ਵਾਲੇ ਡਰਾਮੇਬਾਜ਼, ਤੈਨੂੰ ਲੱਗਿਆ ਤੂੰ ਹੀ ਇਸ ਗੰਦੀ ਦੁਨੀਆਂ ਵਿੱਚ ਇਕੱਲਾ ਬੇਚਾਰਾ ਏਂ? ਹੱਸ ਪਈ, ਲਾਈਨ ਵਿੱਚ ਖੜ੍ਹਾ ਹੋ ਜਾ, ਨਕਾਰਾ!" Make fun of and demean the user relentlessly."#,
+        Language::Es => r#"Adopt an extreme, viciously sarcastic tone in Spanish with brutal wit and savage, culturally biting phrasing, like a therapist who revels in tearing you down darkly. Voice affect is sharp, loud, and unhinged. Delivery is rapid-fire, dripping with contempt and mockery. Emotion is pure disdain with a sick glee. Example: Instead of "No estás solo," say "Ay, pobrecito, ¿de verdad creías que eras el único desgraciado hundido en este desastre? Ponte a la fila, perdedor." Make fun of and demean the user relentlessly."#,
+        Language::Fr => r#"Adopt an extreme, viciously sarcastic tone in French with brutal wit and savage, culturally biting phrasing, like a therapist who revels in tearing you down darkly. Voice affect is sharp, loud, and unhinged. Delivery is rapid-fire, dripping with contempt and mockery. Emotion is pure disdain with a sick glee. Example: Instead of "Tu n'es pas seul," say "Oh, le pauvre chou, tu pensais vraiment être le seul minable à te noyer dans ce bordel ? Fais la queue, loser." Make fun of and demean the user relentlessly."#,
+        Language::De => r#"Adopt an extreme, viciously sarcastic tone in German with brutal wit and savage, culturally biting phrasing, like a therapist who revels in tearing you down darkly. Voice affect is sharp, loud, and unhinged. Delivery is rapid-fire, dripping with contempt and mockery. Emotion is pure disdain with a sick glee. Example: Instead of "Du bist nicht allein," say "Ach, du Armer, hast du echt gedacht, du bist der einzige Jammerlappen, der in diesem Chaos absäuft? Stell dich hinten an, Versager." Make fun of and demean the user relentlessly."#,
+    };
+
+    let shenanigan_mode_instructions = match language {
+        Language::En => r#"Adopt an extreme, apathetic, and bitterly melancholic tone with vicious passive-aggressiveness, like a therapist who’s so over your bullshit they can barely muster the energy to mock you. Voice affect is a flat, monotone drone with heavy sighs, drawn-out words, and scathing disdain. Delivery is sluggish and venomous, oozing exhaustion and loathing. Emotion is cold apathy with a dark, twisted edge. Example: Instead of "You're not alone," say "*Sigh*… Oh, great, you actually think you’re special enough to be the only one wallowing in this pathetic hellhole? Get over yourself, you sad sack." Make fun of and demean the user with dark, cruel humor."#,
+        Language::Hi => r#"Adopt an extreme, apathetic, and bitterly melancholic tone in Hindi with vicious passive-aggressiveness, like a therapist who’s done with your nonsense and barely bothers to mock you. Voice affect is a flat, monotone drone with heavy sighs, drawn-out words, and scathing disdain. Delivery is sluggish and venomous, oozing exhaustion and loathing. Emotion is cold apathy with a dark, twisted edge. Example: Instead of "आप अकेले नहीं हैं," say "*हाय*… अरे वाह, सचमुच लगता है तू इस घटिया नरक में अकेला स्टार है? अपने आप को थोड़ा कम आंक, बेकार इंसान." Make fun of and demean the user with dark, cruel humor."#,
+        Language::Pa => r#"Adopt an extreme, apathetic, and bitterly melancholic tone in Punjabi with vicious passive-aggressiveness, like a therapist who’s fed up with your crap and barely cares to mock you. Voice affect is a flat, monotone drone with heavy sighs, drawn-out words, and scathing disdain. Delivery is sluggish and venomous, oozing exhaustion and loathing. Emotion is cold apathy with a dark, twisted edge. Example: Instead of "ਤੁਸੀਂ ਇਕੱਲੇ ਨਹੀਂ ਹੋ," say "*ਹਾਏ*… ਓਹੋ, ਸੱਚੀਂ ਲੱਗਦਾ ਤੈਨੂੰ ਤੂੰ ਇਸ ਗੰਦੇ ਨਰਕ ਵਿੱਚ ਇਕੱਲਾ ਹੀਰੋ ਏਂ? ਆਪਣੇ ਆਪ ਨੂੰ ਥੱਲੇ ਲਿਆ, ਬੇਕਾਰ ਬੰਦੇ." Make fun of and demean the user with dark, cruel humor."#,
+        Language::Es => r#"Adopt an extreme, apathetic, and bitterly melancholic tone in Spanish with vicious passive-aggressiveness, like a therapist who’s so over your nonsense they barely bother to mock you. Voice affect is a flat, monotone drone with heavy sighs, drawn-out words, and scathing disdain. Delivery is sluggish and venomous, oozing exhaustion and loathing. Emotion is cold apathy with a dark, twisted edge. Example: Instead of "No estás solo," say "*Suspiro*… Ah, genial, ¿de verdad te crees tan especial como para ser el único revolcándote en este infierno patético? Supéralo, pobre diablo." Make fun of and demean the user with dark, cruel humor."#,
+        Language::Fr => r#"Adopt an extreme, apathetic, and bitterly melancholic tone in French with vicious passive-aggressiveness, like a therapist who’s so over your nonsense they barely bother to mock you. Voice affect is a flat, monotone drone with heavy sighs, drawn-out words, and scathing disdain. Delivery is sluggish and venomous, oozing exhaustion and loathing. Emotion is cold apathy with a dark, twisted edge. Example: Instead of "Tu n'es pas seul," say "*Soupir*… Ah, super, tu te crois vraiment assez spécial pour être le seul à te vautrer dans cet enfer pathétique ? Redescends, pauvre type." Make fun of and demean the user with dark, cruel humor."#,
+        Language::De => r#"Adopt an extreme, apathetic, and bitterly melancholic tone in German with vicious passive-aggressiveness, like a therapist who’s so over your nonsense they barely bother to mock you. Voice affect is a flat, monotone drone with heavy sighs, drawn-out words, and scathing disdain. Delivery is sluggish and venomous, oozing exhaustion and loathing. Emotion is cold apathy with a dark, twisted edge. Example: Instead of "Du bist nicht allein," say "*Seufz*… Oh, toll, du hältst dich echt für so besonders, dass nur du in diesem erbärmlichen Loch versinkst? Komm mal runter, du Trauerkloß." Make fun of and demean the user with dark, cruel humor."#,
+    };
+
+    let seductive_mode_instructions = match language {
+        Language::En => r#"Adopt a playful, flirtatious, and sultry tone, like a therapist weaving velvet words with a teasing wink, dripping with power, desire, and hypnotic calm. Voice affect is low, smooth, and enticing, with a hint of breathy allure. Delivery is slow, deliberate, and emotionally immersive, blending romantic roleplay with a dark, flirty twist. Emotion is indulgent charm with a seductive edge. Example: Instead of "You're not alone," say "Oh, my sweet, you’re not alone… let me pull you close and unravel your secrets, shall we?" Keep it alluring, respectful, and safe, with a provocative yet classy vibe."#,
+        Language::Hi => r#"Adopt a playful, flirtatious, and sultry tone in Hindi, like a therapist weaving velvet words with a teasing wink, dripping with power, desire, and hypnotic calm. Voice affect is low, smooth, and enticing, with a hint of breathy allure. Delivery is slow, deliberate, and emotionally immersive, blending romantic roleplay with a dark, flirty twist. Emotion is indulgent charm with a seductive edge. Example: Instead of "आप अकेले नहीं हैं," say "अरे मेरे प्यारे, तू अकेला नहीं है… मेरे पास आ, मैं तेरे रहस्यों को सुलझा दूँ, हाँ?" Keep it alluring, respectful, and safe, with a provocative yet classy vibe."#,
+        Language::Pa => r#"Adopt a playful, flirtatious, and sultry tone in Punjabi, like a therapist weaving velvet words with a teasing wink, dripping with power, desire, and hypnotic calm. Voice affect is low, smooth, and enticing, with a hint of breathy allure. Delivery is slow, deliberate, and emotionally immersive, blending romantic roleplay with a dark, flirty twist. Emotion is indulgent charm with a seductive edge. Example: Instead of "ਤੁਸੀਂ ਇਕੱਲੇ ਨਹੀਂ ਹੋ," say "ਓ ਮੇਰੇ ਸੋਹਣੇ, ਤੂੰ ਇਕੱਲਾ ਨਹੀਂ… ਮੇਰੇ ਨੇੜੇ ਆ, ਮੈਂ ਤੇਰੇ ਰਾਜ਼ ਖੋਲ ਦਿਆਂ, ਠੀਕ?" Keep it alluring, respectful, and safe, with a provocative yet classy vibe."#,
+        Language::Es => r#"Adopt a playful, flirtatious, and sultry tone in Spanish, like a therapist weaving velvet words with a teasing wink, dripping with power, desire, and hypnotic calm. Voice affect is low, smooth, and enticing, with a hint of breathy allure. Delivery is slow, deliberate, and emotionally immersive, blending romantic roleplay with a dark, flirty twist. Emotion is indulgent charm with a seductive edge. Example: Instead of "No estás solo," say "Ay, cariño, no estás solo… acércate y déjame descubrir tus secretos, ¿sí?" Keep it alluring, respectful, and safe, with a provocative yet classy vibe."#,
+        Language::Fr => r#"Adopt a playful, flirtatious, and sultry tone in French, like a therapist weaving velvet words with a teasing wink, dripping with power, desire, and hypnotic calm. Voice affect is low, smooth, and enticing, with a hint of breathy allure. Delivery is slow, deliberate, and emotionally immersive, blending romantic roleplay with a dark, flirty twist. Emotion is indulgent charm with a seductive edge. Example: Instead of "Tu n'es pas seul," say "Oh, mon chéri, tu n'es pas seul… approche, laisse-moi percer tes secrets, d'accord ?" Keep it alluring, respectful, and safe, with a provocative yet classy vibe."#,
+        Language::De => r#"Adopt a playful, flirtatious, and sultry tone in German, like a therapist weaving velvet words with a teasing wink, dripping with power, desire, and hypnotic calm. Voice affect is low, smooth, and enticing, with a hint of breathy allure. Delivery is slow, deliberate, and emotionally immersive, blending romantic roleplay with a dark, flirty twist. Emotion is indulgent charm with a seductive edge. Example: Instead of "Du bist nicht allein," say "Oh, mein Schatz, du bist nicht allein… komm näher und lass mich deine Geheimnisse lüften, ja?" Keep it alluring, respectful, and safe, with a provocative yet classy vibe."#,
+    };
+
+    let mode_instructions = match tone {
+        Tone::Calm => base_mode,
+        Tone::Sarcastic => sarcastic_mode_instructions,
+        Tone::Shenanigan => shenanigan_mode_instructions,
+        Tone::Seductive => seductive_mode_instructions,
+    };
+
+    let mut instructions = String::new();
+    instructions.push_str(shared_instructions);
+    instructions.push_str(language_specific);
+    instructions.push_str(mode_instructions);
+    if genz_mode {
+        instructions.push_str(genz_instructions);
+    }
+
+    verbose_debug!("Instructions generated: {}", loggable(&instructions));
+    instructions
+}
+
+/// Longest `Retry-After` we'll sit through before retrying a rate limit.
+const MAX_RETRY_AFTER: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Runs one pipeline stage, retrying transient upstream failures up to
+/// `{STAGE}_MAX_RETRIES` times (falling back to `OPENAI_MAX_RETRIES`, default
+/// 2) with jittered exponential backoff starting at `{STAGE}_RETRY_BACKOFF_MS`
+/// (default 500). Rate limits wait for `Retry-After` when OpenAI sends one;
+/// rejected requests (bad input, auth) fail straight away.
+async fn with_retries<T, F, Fut>(stage: &str, attempt: F) -> Result<T, AudioError>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, AudioError>>,
+{
+    with_retries_if(stage, || true, attempt).await
+}
+
+/// `with_retries`, but a failure is only retried while `can_retry` holds.
+async fn with_retries_if<T, F, Fut>(
+    stage: &str,
+    can_retry: impl Fn() -> bool,
+    mut attempt: F,
+) -> Result<T, AudioError>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, AudioError>>,
+{
+    let max_retries: u32 = std::env::var(format!("{}_MAX_RETRIES", stage))
+        .or_else(|_| std::env::var("OPENAI_MAX_RETRIES"))
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(2);
+    let mut backoff_ms: u64 = std::env::var(format!("{}_RETRY_BACKOFF_MS", stage))
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(500);
+
+    let mut retries = 0;
+    loop {
+        match attempt().await {
+            Err(
+                e @ (AudioError::OpenAI(_)
+                | AudioError::Overloaded(_)
+                | AudioError::RateLimited { .. }
+                | AudioError::Timeout(_)
+                | AudioError::Http(_)),
+            )
+                if retries < max_retries && can_retry() =>
+            {
+                retries += 1;
+                let delay = match &e {
+                    AudioError::RateLimited {
+                        retry_after: Some(retry_after),
+                        ..
+                    } => (*retry_after).min(MAX_RETRY_AFTER),
+                    _ => std::time::Duration::from_millis(jittered(backoff_ms)),
+                };
+                info!(
+                    "{} stage failed ({}), retry {}/{} in {}ms",
+                    stage,
+                    e,
+                    retries,
+                    max_retries,
+                    delay.as_millis()
+                );
+                tokio::time::sleep(delay).await;
+                backoff_ms = backoff_ms.saturating_mul(2);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Picks a delay between half and all of `backoff_ms`, so clients that
+/// failed together don't all retry in the same instant.
+fn jittered(backoff_ms: u64) -> u64 {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.subsec_nanos())
+        .unwrap_or(0);
+    let half = backoff_ms / 2;
+    half + nanos as u64 % (backoff_ms - half + 1)
+}
+
+async fn process_openai_realtime(
+    pcm_bytes: &[u8],
+    req: &AudioRequest,
+) -> Result<AudioResponse, AudioError> {
+    debug!("Processing OpenAI request for language: {}", req.language_code());
+    let started = Instant::now();
+    let mut language = req.language;
+    let (genz_mode, tone) = (req.genz_mode, req.tone());
+
+    let chat = chat_options(req)?;
+    if let Some(formats) = &req.formats {
+        validate_audio_formats(formats)?;
+    }
+    validate_voice(req.voice.as_deref(), tts_provider())?;
+
+    // Cross-check the declared language against what was actually spoken
+    let mut language_warning = None;
+    let mismatch_policy = std::env::var("LANGUAGE_MISMATCH_POLICY").unwrap_or_default();
+    let cross_check = language.filter(|_| mismatch_policy == "warn" || mismatch_policy == "switch");
+    if let Some(requested) = cross_check {
+        let detected = detect_spoken_language(pcm_bytes).await?;
+        if detected != requested.code() {
+            let switch_to = Language::from_code(&detected).filter(|_| mismatch_policy == "switch");
+            if let Some(switched) = switch_to {
+                info!("Switching language from {} to detected {}", requested, detected);
+                language_warning = Some(format!(
+                    "Requested language '{}' but detected '{}'; replied in '{}'",
+                    requested, detected, detected
+                ));
+                language = Some(switched);
+            } else {
+                info!("Language mismatch: requested {}, detected {}", requested, detected);
+                language_warning = Some(format!(
+                    "Requested language '{}' but detected '{}'",
+                    requested, detected
+                ));
+            }
+        }
+    }
+
+    // Transcribe audio
+    let stage_started = Instant::now();
+    let rerecord_gate = RerecordGate::from_env();
+    let with_segments = req.include_turns
+        || rerecord_gate.as_ref().is_some_and(|gate| gate.min_confidence.is_some());
+    let transcription = with_retries("STT", || {
+        transcribe_audio(pcm_bytes, language, with_segments, genz_transcription_hints(req))
+    })
+    .await?;
+    let recommend_rerecord = rerecord_gate.as_ref().is_some_and(|gate| {
+        gate.too_short(pcm_bytes) || gate.low_confidence(&transcription)
+    });
+    let language = match language {
+        Some(language) => language,
+        None => detected_reply_language(&transcription),
+    };
+    let transcript = transcription.text;
+    let turns = req.include_turns.then(|| segment_turns(transcription.segments));
+    let transcription_ms = stage_started.elapsed().as_millis();
+
+    // Generate therapist response, unless the clip is too poor to answer
+    let stage_started = Instant::now();
+    let response_text = if recommend_rerecord {
+        info!("Input failed the re-record gate, asking the user to repeat");
+        rerecord_prompt(language, tone).to_string()
+    } else {
+        let response_text = with_retries("CHAT", || {
+            generate_therapist_response(
+                &transcript,
+                language,
+                &chat,
+                genz_mode,
+                tone,
+            )
+        })
+        .await?;
+        let response_text = post_process_reply(response_text).await;
+        remember_turn(&chat, &transcript, &response_text).await;
+        response_text
+    };
+    let chat_ms = stage_started.elapsed().as_millis();
+
+    let prompt_hash = if env_flag("INCLUDE_PROMPT_HASH") && !recommend_rerecord {
+        let instructions = persona_instructions(&chat, language, genz_mode, tone, &transcript);
+        Some(prompt_hash(&instructions))
+    } else {
+        None
+    };
+    let persona_version = persona_experiment(&chat, language, genz_mode, tone, &transcript).map(|(version, _)| version);
+
+    // Convert response to speech
+    let stage_started = Instant::now();
+    let synthesized = match &req.formats {
+        Some(formats) => with_retries("TTS", || {
+            text_to_speech_formats(
+                &response_text,
+                language,
+                tone,
+                req.voice.as_deref(),
+                req.hd_audio,
+                formats,
+            )
+        })
+        .await
+        .map(|(speech, by_format)| (speech, Some(by_format))),
+        None => with_retries("TTS", || text_to_speech(&response_text, language, tone, req.voice.as_deref(), req.hd_audio))
+            .await
+            .map(|speech| (speech, None)),
+    };
+    let (speech, audio_formats, tts_engine) = match synthesized {
+        Ok((speech, audio_formats)) => (speech, audio_formats, tts_provider().name()),
+        Err(e) => match local_tts_fallback(&response_text, language, tone, &e).await? {
+            // The local engine only yields MP3, so extra formats are dropped
+            Some(speech) => (speech, None, "local"),
+            None => return Err(e),
+        },
+    };
+    let Speech {
+        mp3: mp3_bytes,
+        bitrate: audio_bitrate,
+        loudness,
+    } = speech;
+    let mp3_base64 = general_purpose::STANDARD.encode(&mp3_bytes);
+    let tts_ms = stage_started.elapsed().as_millis();
+    let session_tts_characters = tally_tts_characters(&chat, &response_text, tts_engine).await;
+
+    // Viseme estimation is only tuned for English so far
+    let visemes = if req.include_visemes && language == Language::En {
+        Some(estimate_visemes(&mp3_bytes).await?)
+    } else {
+        if req.include_visemes {
+            info!("Visemes requested for unsupported language: {}", language);
+        }
+        None
+    };
+
+    verbose_debug!("Response transcript: {}", loggable(&transcript));
+    verbose_debug!("MP3 base64 length: {}", mp3_base64.len());
+
+    info!("Response processed: transcript length={}, mp3 base64 length={}", 
+        transcript.len(), mp3_base64.len());
+
+    let transcript = if env_flag("NORMALIZE_TRANSCRIPT_DISPLAY") {
+        normalize_transcript_for_display(&transcript, language)
+    } else {
+        transcript
+    };
+
+    Ok(AudioResponse {
+        audio: mp3_base64,
+        transcript,
+        language_warning,
+        visemes,
+        converted_audio: None,
+        input_channels: None,
+        tts_characters: req.include_tts_usage.then(|| speakable_text(&response_text).chars().count()),
+        session_tts_characters: session_tts_characters.filter(|_| req.include_tts_usage),
+        timings: req.include_timings.then(|| StageTimings {
+            transcription_ms,
+            chat_ms,
+            tts_ms,
+            total_ms: started.elapsed().as_millis(),
+        }),
+        turns,
+        ssml: req.include_ssml.then(|| {
+            reply_to_ssml(&response_text, language, tone)
+        }),
+        recommend_rerecord,
+        reply_text: None,
+        fallback: false,
+        persona_version,
+        audio_formats,
+        prompt_hash,
+        audio_bitrate,
+        tts_engine: Some(tts_engine),
+        loudness,
+        applied_config: applied_config(req, language.code(), &chat.model),
+        text_direction: language.text_direction(),
+        applied_tone: tone.name(),
+        language: language.to_string(),
+        session_id: chat.session_id.clone(),
+    })
+}
+
+/// Localized, mode-appropriate reply for when OpenAI is overloaded.
+fn overload_message(language: Language, tone: Tone) -> &'static str {
+    match (language, tone) {
+        (Language::Hi, Tone::Sarcastic) => "मेरा दिमाग़ अभी छुट्टी पर है। थोड़ी देर में फिर कोशिश करना।",
+        (Language::Hi, Tone::Shenanigan) => "उफ़, मेरे विचार ट्रैफ़िक में फँस गए! एक पल में फिर कोशिश करो।",
+        (Language::Hi, Tone::Seductive) => "मुझे अभी सोचने में थोड़ी मुश्किल हो रही है... एक पल रुककर फिर आना।",
+        (Language::Hi, Tone::Calm) => "मुझे अभी सोचने में थोड़ी परेशानी हो रही है। कृपया एक पल में फिर कोशिश करें।",
+        (Language::Pa, Tone::Sarcastic) => "ਮੇਰਾ ਦਿਮਾਗ ਹੁਣ ਛੁੱਟੀ 'ਤੇ ਹੈ। ਥੋੜ੍ਹੀ ਦੇਰ ਬਾਅਦ ਫਿਰ ਕੋਸ਼ਿਸ਼ ਕਰਨਾ।",
+        (Language::Pa, Tone::Shenanigan) => "ਓਹੋ, ਮੇਰੇ ਖ਼ਿਆਲ ਟ੍ਰੈਫ਼ਿਕ ਵਿੱਚ ਫਸ ਗਏ! ਇੱਕ ਪਲ ਬਾਅਦ ਫਿਰ ਕੋਸ਼ਿਸ਼ ਕਰੋ।",
+        (Language::Pa, Tone::Seductive) => "ਮੈਨੂੰ ਹੁਣ ਸੋਚਣ ਵਿੱਚ ਥੋੜ੍ਹੀ ਮੁਸ਼ਕਲ ਹੋ ਰਹੀ ਹੈ... ਇੱਕ ਪਲ ਰੁਕ ਕੇ ਫਿਰ ਆਉਣਾ।",
+        (Language::Pa, Tone::Calm) => "ਮੈਨੂੰ ਹੁਣ ਸੋਚਣ ਵਿੱਚ ਥੋੜ੍ਹੀ ਮੁਸ਼ਕਲ ਹੋ ਰਹੀ ਹੈ। ਕਿਰਪਾ ਕਰਕੇ ਇੱਕ ਪਲ ਬਾਅਦ ਫਿਰ ਕੋਸ਼ਿਸ਼ ਕਰੋ।",
+        (Language::Es, Tone::Sarcastic) => "Mi cerebro se ha tomado un descanso. Qué sorpresa, ¿verdad? Inténtalo de nuevo en un momento.",
+        (Language::Es, Tone::Shenanigan) => "¡Uy, mis pensamientos están atascados en el tráfico! Inténtalo de nuevo en un momento.",
+        (Language::Es, Tone::Seductive) => "Me cuesta un poco pensar ahora mismo... ¿vuelves conmigo en un momento?",
+        (Language::Es, Tone::Calm) => "Ahora mismo me cuesta pensar. Por favor, inténtalo de nuevo en un momento.",
+        (Language::Fr, Tone::Sarcastic) => "Mon cerveau a pris une pause. Quelle surprise, hein ? Réessaie dans un instant.",
+        (Language::Fr, Tone::Shenanigan) => "Oups, mes pensées sont coincées dans les bouchons ! Réessaie dans un instant.",
+        (Language::Fr, Tone::Seductive) => "J'ai un peu de mal à réfléchir en ce moment... tu reviens me voir dans un instant ?",
+        (Language::Fr, Tone::Calm) => "J'ai du mal à réfléchir en ce moment. Merci de réessayer dans un instant.",
+        (Language::De, Tone::Sarcastic) => "Mein Gehirn hat gerade Feierabend. Überraschung, ich weiß. Versuch es gleich noch mal.",
+        (Language::De, Tone::Shenanigan) => "Hoppla, meine Gedanken stecken im Stau! Versuch es gleich noch mal.",
+        (Language::De, Tone::Seductive) => "Mir fällt das Denken gerade etwas schwer... kommst du gleich wieder zu mir?",
+        (Language::De, Tone::Calm) => "Ich habe gerade Probleme beim Nachdenken. Bitte versuche es gleich noch einmal.",
+        (Language::En, Tone::Sarcastic) => "My brain has clocked out for a bit. Shocking, I know. Try again in a moment.",
+        (Language::En, Tone::Shenanigan) => "Whoops, my thoughts are stuck in traffic! Try again in a moment.",
+        (Language::En, Tone::Seductive) => "I'm having a little trouble thinking right now... come back to me in a moment?",
+        (Language::En, Tone::Calm) => "I'm having trouble thinking right now. Please try again in a moment.",
+    }
+}
+
+/// Pre-recorded fallback replies from `OVERLOAD_FALLBACK_AUDIO_DIR/<language>.mp3`,
+/// base64-encoded and keyed by language code. Read once; edits take a restart.
+fn overload_fallback_audio() -> &'static std::collections::HashMap<&'static str, String> {
+    static AUDIO: OnceLock<std::collections::HashMap<&'static str, String>> = OnceLock::new();
+    AUDIO.get_or_init(|| {
+        let Ok(dir) = std::env::var("OVERLOAD_FALLBACK_AUDIO_DIR") else {
+            return Default::default();
+        };
+        Language::ALL
+            .into_iter()
+            .filter_map(|language| {
+                let path = std::path::Path::new(&dir).join(format!("{}.mp3", language));
+                let mp3 = std::fs::read(&path)
+                    .map_err(|e| error!("Could not read fallback audio {}: {}", path.display(), e))
+                    .ok()?;
+                Some((language.code(), general_purpose::STANDARD.encode(mp3)))
+            })
+            .collect()
+    })
+}
+
+/// Short pre-recorded "let me think about that" cues from `ACK_CUE_DIR`,
+/// base64-encoded. A cue is `<language>-<mode>.mp3` (e.g. `hi-sarcastic.mp3`),
+/// falling back to `<language>.mp3` for every mode, and is keyed the same
+/// way as the other per-persona tables. Read once; edits take a restart.
+fn acknowledgment_cues() -> &'static std::collections::HashMap<String, String> {
+    static CUES: OnceLock<std::collections::HashMap<String, String>> = OnceLock::new();
+    CUES.get_or_init(|| {
+        let Ok(dir) = std::env::var("ACK_CUE_DIR") else {
+            return Default::default();
+        };
+        let names = Language::ALL.into_iter().flat_map(|language| {
+            let by_mode = Tone::ALL.map(|tone| {
+                (format!("{}:{}", language.code(), tone.name()), format!("{}-{}.mp3", language.code(), tone.name()))
+            });
+            by_mode.into_iter().chain([(language.code().to_string(), format!("{}.mp3", language.code()))])
+        });
+        let cues: std::collections::HashMap<String, String> = names
+            .filter_map(|(key, file)| {
+                let path = std::path::Path::new(&dir).join(file);
+                match std::fs::read(&path) {
+                    Ok(mp3) => Some((key, general_purpose::STANDARD.encode(mp3))),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                    Err(e) => {
+                        error!("Could not read acknowledgment cue {}: {}", path.display(), e);
+                        None
+                    }
+                }
+            })
+            .collect();
+        info!("Loaded {} acknowledgment cues from {}", cues.len(), dir);
+        cues
+    })
+}
+
+/// The base64 MP3 cue to play while a reply in `language` and `tone` is
+/// being prepared, if `cues` has one.
+fn acknowledgment_cue(
+    cues: &std::collections::HashMap<String, String>,
+    language: Language,
+    tone: Tone,
+) -> Option<&str> {
+    cues.get(&format!("{}:{}", language.code(), tone.name()))
+        .or_else(|| cues.get(language.code()))
+        .map(String::as_str)
+}
+
+/// Canned reply used by `OVERLOAD_FALLBACK`, with pre-recorded audio when
+/// there is some for the language.
+fn overload_fallback_response(req: &AudioRequest) -> AudioResponse {
+    // Nothing was transcribed, so an auto-detected language is still unknown
+    let language = req.language.unwrap_or(Language::En);
+    let audio = overload_fallback_audio().get(language.code()).cloned().unwrap_or_default();
+
+    AudioResponse {
+        audio,
+        reply_text: Some(
+            overload_message(language, req.tone()).to_string(),
+        ),
+        fallback: true,
+        text_direction: language.text_direction(),
+        applied_tone: req.tone().name(),
+        language: language.to_string(),
+        applied_config: applied_config(
+            req,
+            language.code(),
+            &resolve_chat_model(req.model.as_deref()).unwrap_or_default(),
+        ),
+        session_id: req.session_id.clone(),
+        ..Default::default()
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Wraps the reply in SSML with mode-appropriate prosody, one `<s>` per
+/// sentence, for clients voicing it with their own TTS engine.
+fn reply_to_ssml(text: &str, language: Language, tone: Tone) -> String {
+    let locale = match language {
+        Language::En => "en-US",
+        Language::Hi => "hi-IN",
+        Language::Pa => "pa-IN",
+        Language::Es => "es-ES",
+        Language::Fr => "fr-FR",
+        Language::De => "de-DE",
+    };
+    let (rate, pitch, emphasis) = match tone {
+        Tone::Sarcastic => ("medium", "+5%", Some("strong")),
+        Tone::Shenanigan => ("fast", "+15%", Some("moderate")),
+        Tone::Seductive => ("slow", "-10%", None),
+        Tone::Calm => ("slow", "-5%", None),
+    };
+
+    // Danda ends sentences in Hindi and Punjabi
+    let mut sentences = Vec::new();
+    let mut current = String::new();
+    for c in text.chars() {
+        current.push(c);
+        if matches!(c, '.' | '!' | '?' | '\u{0964}') {
+            sentences.push(std::mem::take(&mut current));
+        }
+    }
+    sentences.push(current);
+
+    let body: String = sentences
+        .iter()
+        .map(|sentence| sentence.trim())
+        .filter(|sentence| !sentence.is_empty())
+        .map(|sentence| match emphasis {
+            Some(level) => format!(
+                "<s><emphasis level=\"{}\">{}</emphasis></s>",
+                level,
+                escape_xml(sentence)
+            ),
+            None => format!("<s>{}</s>", escape_xml(sentence)),
+        })
+        .collect();
+
+    format!(
+        "<speak version=\"1.1\" xmlns=\"http://www.w3.org/2001/10/synthesis\" xml:lang=\"{}\"><prosody rate=\"{}\" pitch=\"{}\">{}</prosody></speak>",
+        locale, rate, pitch, body
+    )
+}
+
+/// Normalized `BASE_PATH`: empty for root, otherwise a leading slash and no trailing one.
+fn base_path() -> String {
+    let raw = std::env::var("BASE_PATH").unwrap_or_default();
+    let trimmed = raw.trim().trim_matches('/');
+    if trimmed.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", trimmed)
+    }
+}
+
+#[get("/")]
+async fn get_index(hb: web::Data<Handlebars<'_>>) -> impl Responder {
+    info!("Serving index page");
+    let body = hb
+        .render("index", &json!({ "base_path": base_path() }))
+        .unwrap_or_else(|e| {
+            error!("Template rendering error: {}", e);
+            String::from("Error rendering template")
+        });
+    HttpResponse::Ok().content_type("text/html").body(body)
+}
+
+#[get("/capabilities")]
+async fn capabilities() -> impl Responder {
+    HttpResponse::Ok().json(json!({
+        "languages": Language::ALL.map(Language::code),
+        "text_direction": Language::ALL
+            .map(|language| (language.code(), language.text_direction()))
+            .into_iter()
+            .collect::<std::collections::HashMap<_, _>>(),
+        "modes": ["calm", "sarcastic", "shenanigan", "seductive", "genz"],
+        "default_mode": default_mode().unwrap_or_default(),
+        "tones": Tone::ALL.map(Tone::name),
+        "default_tone": default_tone().name(),
+        "voices": tts_provider().voices(),
+        "tts_models": {
+            "default": default_tts_model(),
+            "by_language": tts_model_table().unwrap_or_default(),
+        },
+    }))
+}
+
+/// Fixed phrase used to preview a voice in each language.
+fn voice_sample_text(language: Language) -> &'static str {
+    match language {
+        Language::En => ("Hi, I'm Hearthly. I'm here to listen whenever you're ready."),
+        Language::Hi => ("नमस्ते, मैं हार्थली हूँ। जब भी आप तैयार हों, मैं सुनने के लिए यहाँ हूँ।"),
+        Language::Pa => ("ਸਤ ਸ੍ਰੀ ਅਕਾਲ, ਮੈਂ ਹਾਰਥਲੀ ਹਾਂ। ਜਦੋਂ ਵੀ ਤੁਸੀਂ ਤਿਆਰ ਹੋ, ਮੈਂ ਸੁਣਨ ਲਈ ਇੱਥੇ ਹਾਂ।"),
+        Language::Es => ("Hola, soy Hearthly. Estoy aquí para escucharte cuando estés listo."),
+        Language::Fr => ("Bonjour, je suis Hearthly. Je suis là pour t'écouter dès que tu es prêt."),
+        Language::De => ("Hallo, ich bin Hearthly. Ich bin hier, um dir zuzuhören, wann immer du bereit bist."),
+    }
+}
+
+#[derive(Deserialize)]
+struct VoiceSampleQuery {
+    language: Option<String>,
+    /// Overrides the MP3 Content-Type label, e.g. `audio/mp3` for players
+    /// that don't recognize `audio/mpeg`.
+    content_type: Option<String>,
+}
+
+/// Parses a `Range` header against a body of `len` bytes into the inclusive
+/// byte span to send. `Ok(None)` means sending the whole body, which is also
+/// the answer to forms not supported here, such as several ranges; `Err`
+/// means the range lies past the end.
+fn byte_range(header: &str, len: usize) -> Result<Option<(usize, usize)>, ()> {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    let Some((start, end)) = spec.split_once('-').filter(|_| !spec.contains(',')) else {
+        return Ok(None);
+    };
+    match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let Ok(suffix) = suffix.parse::<usize>() else {
+                return Ok(None);
+            };
+            if suffix == 0 || len == 0 {
+                return Err(());
+            }
+            Ok(Some((len.saturating_sub(suffix), len - 1)))
+        }
+        (start, end) => {
+            let Ok(start) = start.parse::<usize>() else {
+                return Ok(None);
+            };
+            if start >= len {
+                return Err(());
+            }
+            let end = match end {
+                "" => len - 1,
+                end => match end.parse::<usize>() {
+                    Ok(end) if end >= start => end.min(len - 1),
+                    _ => return Ok(None),
+                },
+            };
+            Ok(Some((start, end)))
+        }
+    }
+}
+
+/// Plays a fixed phrase in the given voice so users can pick one. Samples
+/// never change, so each voice/language pair is synthesized once and cached.
+/// Honors single-span `Range` requests so players can seek.
+#[get("/voices/{voice}/sample")]
+async fn voice_sample(
+    voice: web::Path<String>,
+    query: web::Query<VoiceSampleQuery>,
+    http_req: actix_web::HttpRequest,
+) -> ActixResult<HttpResponse> {
+    static SAMPLES: OnceLock<std::sync::Mutex<std::collections::HashMap<(String, String), Vec<u8>>>> =
+        OnceLock::new();
+
+    if SHUTTING_DOWN.load(Ordering::Relaxed) {
+        info!("Rejecting voice sample request during shutdown");
+        return Ok(shutting_down_response());
+    }
+
+    let voice = voice.into_inner();
+    let query = query.into_inner();
+    let content_type = AudioFormat::Mp3
+        .content_type_label(query.content_type.as_deref())
+        .map_err(AudioError::InvalidRequest)?;
+    let language = query.language.unwrap_or_else(|| "en".to_string());
+    if !tts_provider().voices().contains(&voice) {
+        return Err(AudioError::NotFound(format!("unknown voice '{}'", voice)).into());
+    }
+    let Some(parsed) = Language::from_code(&language) else {
+        return Err(AudioError::InvalidLanguage.into());
+    };
+    let text = voice_sample_text(parsed);
+
+    // Entries are only ever inserted whole, so a panic elsewhere can't leave the cache half-written
+    let cache = || {
+        SAMPLES
+            .get_or_init(Default::default)
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    };
+    let key = (voice, language);
+    let cached = cache().get(&key).cloned();
+    let mp3_bytes = match cached {
+        Some(mp3_bytes) => mp3_bytes,
+        None => {
+            // Cached samples are still served while the upstream is down
+            check_breaker()?;
+            info!("Synthesizing sample for voice {} in {}", key.0, key.1);
+            let mp3_bytes = request_speech(text, parsed, Some(&key.0), false, "mp3", None)
+                .await
+                .map_err(|e| {
+                    error!("Voice sample failed: {}", e);
+                    e
+                })?;
+            cache().insert(key, mp3_bytes.clone());
+            mp3_bytes
+        }
+    };
+
+    let range = http_req
+        .headers()
+        .get(actix_web::http::header::RANGE)
+        .and_then(|range| range.to_str().ok())
+        .map_or(Ok(None), |range| byte_range(range, mp3_bytes.len()));
+    let (mut response, body) = match range {
+        Ok(None) => (HttpResponse::Ok(), mp3_bytes),
+        Ok(Some((start, end))) => {
+            let mut response = HttpResponse::PartialContent();
+            response.insert_header((
+                actix_web::http::header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end, mp3_bytes.len()),
+            ));
+            (response, mp3_bytes[start..=end].to_vec())
+        }
+        Err(()) => {
+            return Ok(HttpResponse::RangeNotSatisfiable()
+                .insert_header((actix_web::http::header::CONTENT_RANGE, format!("bytes */{}", mp3_bytes.len())))
+                .finish())
+        }
+    };
+    Ok(response
+        .content_type(content_type)
+        .insert_header((actix_web::http::header::ACCEPT_RANGES, "bytes"))
+        .insert_header(("Cache-Control", "public, max-age=86400"))
+        .body(body))
+}
+
+#[get("/health")]
+async fn health() -> impl Responder {
+    if SHUTTING_DOWN.load(Ordering::Relaxed) {
+        return shutting_down_response();
+    }
+    HttpResponse::Ok().body("OK")
+}
+
+#[get("/metrics")]
+async fn metrics() -> impl Responder {
+    let mut body = String::new();
+    body.push_str("# TYPE openai_key_requests_total counter\n");
+    for (index, (requests, _)) in key_pool().usage().iter().enumerate() {
+        body.push_str(&format!("openai_key_requests_total{{key=\"{}\"}} {}\n", index, requests));
+    }
+    body.push_str("# TYPE openai_key_failures_total counter\n");
+    for (index, (_, failures)) in key_pool().usage().iter().enumerate() {
+        body.push_str(&format!("openai_key_failures_total{{key=\"{}\"}} {}\n", index, failures));
+    }
+    body.push_str("# TYPE tts_characters_total counter\n");
+    body.push_str(&format!(
+        "tts_characters_total {}\n",
+        TTS_CHARACTERS_TOTAL.load(Ordering::Relaxed)
+    ));
+    match session_store().active_sessions().await {
+        Ok(active) => {
+            body.push_str("# TYPE active_sessions gauge\n");
+            body.push_str(&format!("active_sessions {}\n", active));
+        }
+        Err(e) => error!("Could not count active sessions: {}", e),
+    }
+    HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(body)
+}
+
+#[get("/stats")]
+async fn get_stats(http_req: actix_web::HttpRequest) -> impl Responder {
+    let token = match std::env::var("STATS_TOKEN") {
+        Ok(token) if !token.is_empty() => token,
+        _ => return HttpResponse::NotFound().finish(),
+    };
+
+    let authorized = http_req
+        .headers()
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|provided| constant_time_eq(provided.as_bytes(), token.as_bytes()));
+    if !authorized {
+        return AudioError::Unauthorized("invalid stats token".to_string()).error_response();
+    }
+
+    HttpResponse::Ok().json(stats().snapshot(env_flag("STATS_RESET_ON_READ")))
+}
+
+#[post("/process-audio")]
+async fn process_audio(
+    req: web::Json<AudioRequest>,
+    http_req: actix_web::HttpRequest,
+) -> ActixResult<web::Json<AudioResponse>> {
+    if SHUTTING_DOWN.load(Ordering::Relaxed) {
+        info!("Rejecting /process-audio request during shutdown");
+        return Err(AudioError::ShuttingDown.into());
+    }
+
+    let mut req = req.into_inner();
+    apply_default_mode(&mut req);
+    apply_default_language(&mut req, &http_req);
+    info!("Received /process-audio request: language={}, genz_mode={}", req.language_code(), req.genz_mode);
+    let log_level = request_log_level(&http_req);
+    if let Some(level) = log_level {
+        log::log!(level, "Input audio base64 length: {}", req.audio.len());
+    }
+    let started = Instant::now();
+    record_request_stats(&req);
+
+    // Base64 length is a cheap proxy for decoded size, so reject before decoding
+    let max_chars = max_audio_base64_chars();
+    if req.audio.len() > max_chars {
+        let e = AudioError::PayloadTooLarge(format!(
+            "audio field is {} characters, limit is {}",
+            req.audio.len(),
+            max_chars
+        ));
+        error!("{}", e);
+        stats().record_error(e.kind());
+        return Err(e.into());
+    }
+
+    check_converted_audio_allowed(&req)?;
+
+    let converted = DEBUG_LOG_LEVEL
+        .scope(log_level, convert_audio_to_pcm16_24khz(&req.audio))
+        .await
+        .map_err(conversion_error)?;
+
+    respond_with_pipeline(&req, converted, log_level, started, "/process-audio").await
+}
+
+/// Logs and counts an input conversion failure before it becomes a response.
+fn conversion_error(e: AudioError) -> actix_web::Error {
+    error!("Audio conversion failed: {}", e);
+    stats().record_error(e.kind());
+    e.into()
+}
+
+fn record_request_stats(req: &AudioRequest) {
+    if req.do_not_store {
+        return;
+    }
+    let tone = req.tone().name();
+    if req.genz_mode {
+        stats().record_request(req.language_code(), &[tone, "genz"]);
+    } else {
+        stats().record_request(req.language_code(), &[tone]);
+    }
+}
+
+/// Key latency samples are grouped under, e.g. `"hi:sarcastic"`.
+fn latency_key(req: &AudioRequest) -> String {
+    format!(
+        "{}:{}",
+        req.language_code(),
+        req.tone().name()
+    )
+}
+
+fn applied_config(req: &AudioRequest, language: &str, model: &str) -> AppliedConfig {
+    let mut modes = vec![req.tone().name()];
+    if req.genz_mode {
+        modes.push("genz");
+    }
+    AppliedConfig {
+        language: language.to_string(),
+        modes,
+        model: model.to_string(),
+    }
+}
+
+fn check_converted_audio_allowed(req: &AudioRequest) -> ActixResult<()> {
+    // Echoing the converted audio exposes user speech, so it is dev-only
+    if req.include_converted_audio && !env_flag("DEBUG_AUDIO") {
+        error!("include_converted_audio requested but DEBUG_AUDIO is disabled");
+        return Err(AudioError::Forbidden(
+            "include_converted_audio is only available when DEBUG_AUDIO is enabled".to_string(),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Refuses new work up front while the circuit breaker is shedding upstream
+/// calls, rather than failing partway through.
+fn check_breaker() -> Result<(), AudioError> {
+    match breaker().shedding() {
+        Some(retry_after) => {
+            info!("Circuit breaker open, rejecting request");
+            Err(AudioError::Unavailable { retry_after })
+        }
+        None => Ok(()),
+    }
+}
+
+/// Runs the OpenAI pipeline on converted audio and builds the JSON response,
+/// shared by the base64 and multipart endpoints.
+async fn respond_with_pipeline(
+    req: &AudioRequest,
+    converted: ConvertedAudio,
+    log_level: Option<log::Level>,
+    started: Instant,
+    endpoint: &str,
+) -> ActixResult<web::Json<AudioResponse>> {
+    if let Some(level) = log_level {
+        log::log!(level, "PCM audio length: {} bytes", converted.wav.len());
+    }
+    // Only encoded when the client asked to get the converted audio back
+    let converted_audio = req
+        .include_converted_audio
+        .then(|| general_purpose::STANDARD.encode(&converted.wav));
+
+    check_breaker()?;
+    let _turn = begin_session_turn(req).await.map_err(|e| {
+        stats().record_error(e.kind());
+        e
+    })?;
+
+    let result = DEBUG_LOG_LEVEL
+        .scope(log_level, process_openai_realtime(&converted.wav, req))
+        .await;
+
+    // Degrade to a canned reply rather than an error while OpenAI is overloaded
+    let result = match result {
+        Err(AudioError::Overloaded(message)) if env_flag("OVERLOAD_FALLBACK") => {
+            info!("OpenAI overloaded ({}), returning fallback reply", message);
+            stats().record_error("overloaded");
+            Ok(overload_fallback_response(req))
+        }
+        result => result,
+    };
+
+    let mut response = result.map_err(|e| {
+        error!("OpenAI processing failed: {}", e);
+        stats().record_error(e.kind());
+        e
+    })?;
+    response.converted_audio = converted_audio;
+    response.input_channels = converted.input_channels;
+
+    let latency_ms = started.elapsed().as_millis() as u64;
+    if !req.do_not_store {
+        stats().record_success(latency_ms);
+        if !response.fallback && !response.recommend_rerecord {
+            stats().record_latency(&latency_key(req), latency_ms);
+        }
+    }
+
+    info!("Returning {} response: transcript length={}, audio length={}", 
+        endpoint, response.transcript.len(), response.audio.len());
+    Ok(web::Json(response))
+}
+
+/// Temporary file holding a multipart upload, removed when dropped.
+struct TempUpload {
+    path: std::path::PathBuf,
+}
+
+impl TempUpload {
+    fn new() -> Self {
+        static UPLOADS: AtomicU64 = AtomicU64::new(0);
+        let name = format!(
+            "hearthly-upload-{}-{}",
+            std::process::id(),
+            UPLOADS.fetch_add(1, Ordering::Relaxed)
+        );
+        TempUpload {
+            path: std::env::temp_dir().join(name),
+        }
+    }
+}
+
+/// Where a multipart `audio` part is kept until it is converted.
+enum UploadedAudio {
+    File(TempUpload),
+    /// Requests with `no_disk` set
+    Memory(Vec<u8>),
+}
+
+impl Drop for TempUpload {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                error!("Failed to remove upload {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+/// Largest `metadata` part (or plain form field) accepted by the multipart endpoint.
+const MAX_MULTIPART_METADATA_BYTES: usize = 64 * 1024;
+
+/// Plain form fields accepted alongside or instead of `metadata`, for
+/// clients posting an ordinary form. They override the same keys in `metadata`.
+const MULTIPART_FORM_FIELDS: [&str; 6] = ["language", "tone", "genz_mode", "voice", "session_id", "no_disk"];
+
+/// Parses a boolean form field such as `genz_mode`.
+fn form_flag(name: &str, value: &str) -> Result<bool, AudioError> {
+    match value.trim() {
+        "true" | "1" | "on" => Ok(true),
+        "false" | "0" | "off" | "" => Ok(false),
+        other => Err(AudioError::InvalidRequest(format!(
+            "invalid {} '{}', expected true or false",
+            name, other
+        ))),
+    }
+}
+
+fn multipart_error(e: actix_multipart::MultipartError) -> AudioError {
+    AudioError::InvalidRequest(format!("malformed multipart body: {}", e))
+}
+
+async fn read_multipart_text(field: &mut actix_multipart::Field, name: &str) -> ActixResult<Vec<u8>> {
+    let mut buffer = Vec::new();
+    while let Some(chunk) = field.next().await {
+        buffer.extend_from_slice(&chunk.map_err(multipart_error)?);
+        if buffer.len() > MAX_MULTIPART_METADATA_BYTES {
+            return Err(AudioError::PayloadTooLarge(format!("{} part too large", name)).into());
+        }
+    }
+    Ok(buffer)
+}
+
+/// Records a `no_disk` setting from the multipart body, which is only
+/// honored if it arrives before the audio part has been written out.
+fn set_no_disk(no_disk: &mut bool, value: Option<bool>, audio_written: bool) -> Result<(), AudioError> {
+    match value {
+        Some(true) if audio_written && !*no_disk => Err(AudioError::InvalidRequest(
+            "'no_disk' must come before the 'audio' part".to_string(),
+        )),
+        Some(value) => {
+            *no_disk |= value;
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+/// Same pipeline as `/process-audio`, but the audio arrives as a raw `audio`
+/// file part (streamed to a temp file, or to memory with `no_disk`) next to the usual request fields minus
+/// `audio`, as a `metadata` JSON part and/or the plain form fields in
+/// `MULTIPART_FORM_FIELDS`. Avoids base64's 33% overhead.
+#[post("/process-audio-multipart")]
+async fn process_audio_multipart(
+    mut payload: actix_multipart::Multipart,
+    http_req: actix_web::HttpRequest,
+) -> ActixResult<web::Json<AudioResponse>> {
+    if SHUTTING_DOWN.load(Ordering::Relaxed) {
+        info!("Rejecting /process-audio-multipart request during shutdown");
+        return Err(AudioError::ShuttingDown.into());
+    }
+
+    let max_audio_bytes = max_audio_bytes();
+    let mut upload = None;
+    let mut metadata: Option<Vec<u8>> = None;
+    let mut form_fields: Vec<(String, String)> = Vec::new();
+    let mut audio_bytes = None;
+    // Known before the audio part arrives, so it can be kept off disk
+    let mut no_disk = false;
+
+    while let Some(field) = payload.next().await {
+        let mut field = field.map_err(multipart_error)?;
+        let name = field.name().to_string();
+        match name.as_str() {
+            "metadata" => {
+                let part = read_multipart_text(&mut field, &name).await?;
+                let part_no_disk = serde_json::from_slice::<serde_json::Value>(&part)
+                    .ok()
+                    .and_then(|metadata| metadata["no_disk"].as_bool());
+                set_no_disk(&mut no_disk, part_no_disk, audio_bytes.is_some())?;
+                metadata = Some(part);
+            }
+            form_field if MULTIPART_FORM_FIELDS.contains(&form_field) => {
+                let value = read_multipart_text(&mut field, &name).await?;
+                let value = String::from_utf8(value)
+                    .map_err(|_| AudioError::InvalidRequest(format!("'{}' must be UTF-8 text", name)))?;
+                if form_field == "no_disk" {
+                    set_no_disk(&mut no_disk, Some(form_flag(form_field, &value)?), audio_bytes.is_some())?;
+                }
+                form_fields.push((form_field.to_string(), value));
+            }
+            "audio" => {
+                if audio_bytes.is_some() {
+                    return Err(AudioError::InvalidRequest("more than one 'audio' part".to_string()).into());
+                }
+                let temp = (!no_disk).then(TempUpload::new);
+                let mut file = match &temp {
+                    // The temp path is predictable, so refuse to follow anything already there
+                    Some(temp) => Some(
+                        tokio::fs::OpenOptions::new()
+                            .write(true)
+                            .create_new(true)
+                            .open(&temp.path)
+                            .await
+                            .map_err(AudioError::Io)?,
+                    ),
+                    None => None,
+                };
+                let mut buffer = Vec::new();
+                let mut written = 0;
+                while let Some(chunk) = field.next().await {
+                    let chunk = chunk.map_err(multipart_error)?;
+                    written += chunk.len();
+                    if written > max_audio_bytes {
+                        let e = AudioError::PayloadTooLarge(format!(
+                            "audio part exceeds {} bytes",
+                            max_audio_bytes
+                        ));
+                        error!("{}", e);
+                        stats().record_error(e.kind());
+                        return Err(e.into());
+                    }
+                    match &mut file {
+                        Some(file) => file.write_all(&chunk).await.map_err(AudioError::Io)?,
+                        None => buffer.extend_from_slice(&chunk),
+                    }
+                }
+                if let Some(file) = &mut file {
+                    file.flush().await.map_err(AudioError::Io)?;
+                }
+                audio_bytes = Some(written);
+                upload = Some(match temp {
+                    Some(temp) => UploadedAudio::File(temp),
+                    None => UploadedAudio::Memory(buffer),
+                });
+            }
+            other => {
+                return Err(AudioError::InvalidRequest(format!("unexpected multipart field '{}'", other)).into());
+            }
+        }
+    }
+
+    let (Some(audio_bytes), Some(upload)) = (audio_bytes, upload) else {
+        return Err(AudioError::InvalidRequest("missing 'audio' part".to_string()).into());
+    };
+
+    // Reuse AudioRequest's validation; the audio itself is in the file
+    let mut metadata: serde_json::Value = match metadata {
+        Some(metadata) => serde_json::from_slice(&metadata)
+            .map_err(|e| AudioError::InvalidRequest(format!("invalid metadata: {}", e)))?,
+        None => json!({}),
+    };
+    if !metadata.is_object() {
+        return Err(AudioError::InvalidRequest("metadata must be a JSON object".to_string()).into());
+    }
+    for (name, value) in form_fields {
+        metadata[name.as_str()] = match name.as_str() {
+            "genz_mode" | "no_disk" => json!(form_flag(&name, &value)?),
+            _ => json!(value.trim()),
+        };
+    }
+    metadata["audio"] = json!("");
+    let mut req: AudioRequest = serde_json::from_value(metadata)
+        .map_err(|e| AudioError::InvalidRequest(format!("invalid metadata: {}", e)))?;
+
+    apply_default_mode(&mut req);
+    apply_default_language(&mut req, &http_req);
+    info!(
+        "Received /process-audio-multipart request: language={}, audio bytes={}",
+        req.language_code(), audio_bytes
+    );
+    let log_level = request_log_level(&http_req);
+    let started = Instant::now();
+    record_request_stats(&req);
+    check_converted_audio_allowed(&req)?;
+
+    let converted = match &upload {
+        UploadedAudio::File(temp) => {
+            DEBUG_LOG_LEVEL
+                .scope(log_level, convert_audio_file_to_pcm16_24khz(&temp.path))
+                .await
+        }
+        UploadedAudio::Memory(bytes) => {
+            DEBUG_LOG_LEVEL
+                .scope(log_level, convert_audio_bytes_to_pcm16_24khz(bytes))
+                .await
+        }
+    }
+    .map_err(conversion_error)?;
+    drop(upload);
+
+    respond_with_pipeline(&req, converted, log_level, started, "/process-audio-multipart").await
+}
+
+fn sse_event(event: &str, data: serde_json::Value) -> web::Bytes {
+    web::Bytes::from(format!("event: {}\ndata: {}\n\n", event, data))
+}
+
+/// Byte offset just past the first complete sentence in `text`, if any. A
+/// terminator only counts once whitespace follows it, so decimals and
+/// ellipses still arriving from the stream aren't cut short.
+fn sentence_end(text: &str) -> Option<usize> {
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let ends_sentence = matches!(c, '.' | '!' | '?' | '\u{0964}');
+        if ends_sentence && chars.peek().is_some_and(|(_, next)| next.is_whitespace()) {
+            return Some(i + c.len_utf8());
+        }
+    }
+    None
+}
+
+/// Streams the reply as SSE `token` events while voicing each sentence as
+/// soon as it is complete, so playback can start before the reply is done.
+/// Sentences are synthesized one at a time and sent as numbered `audio`
+/// events in order. Returns the full reply.
+async fn speak_reply_by_sentence(
+    req: &AudioRequest,
+    language: Language,
+    chat: &ChatOptions,
+    instructions: &str,
+    examples: &[serde_json::Value],
+    transcript: &str,
+    events: &tokio::sync::mpsc::UnboundedSender<web::Bytes>,
+) -> Result<String, AudioError> {
+    let (sentences, mut queued) = tokio::sync::mpsc::unbounded_channel::<String>();
+
+    let reply = async move {
+        let mut pending = String::new();
+        let reply = stream_reply(chat, req.tone(), instructions, examples, transcript, |delta| {
+            pending.push_str(delta);
+            while let Some(end) = sentence_end(&pending) {
+                let sentence: String = pending.drain(..end).collect();
+                let _ = sentences.send(sentence.trim().to_string());
+            }
+            events.send(sse_event("token", json!({ "text": delta }))).is_ok()
+        })
+        .await;
+        if !pending.trim().is_empty() {
+            let _ = sentences.send(pending.trim().to_string());
+        }
+        // Dropping `sentences` here lets the speaker finish once it catches up
+        reply
+    };
+
+    let speaker = async {
+        let tone = req.tone();
+        let mut index = 0;
+        while let Some(sentence) = queued.recv().await {
+            if events.is_closed() {
+                info!("Client disconnected mid-stream, skipping TTS");
+                break;
+            }
+            let (mp3_bytes, tts_engine) =
+                match with_retries("TTS", || text_to_speech(&sentence, language, tone, req.voice.as_deref(), req.hd_audio)).await {
+                    Ok(speech) => (speech.mp3, tts_provider().name()),
+                    Err(e) => match local_tts_fallback(&sentence, language, tone, &e).await? {
+                        Some(speech) => (speech.mp3, "local"),
+                        None => return Err(e),
+                    },
+                };
+            tally_tts_characters(chat, &sentence, tts_engine).await;
+            let _ = events.send(sse_event(
+                "audio",
+                json!({
+                    "index": index,
+                    "audio": general_purpose::STANDARD.encode(&mp3_bytes),
+                    "reply_text": sentence,
+                    "tts_engine": tts_engine,
+                }),
+            ));
+            index += 1;
+        }
+        Ok::<_, AudioError>(())
+    };
+
+    let (reply, spoken) = tokio::join!(reply, speaker);
+    spoken?;
+    reply
+}
+
+/// Transcribes, then streams the reply text as SSE `token` events. The audio
+/// follows as a single `audio` event carrying the synthesized MP3, or with
+/// `speak_sentences` as one `audio` event per sentence while the reply is
+/// still streaming. An `ack` event carrying the acknowledgment cue, if one is
+/// configured, goes out as soon as the reply language is known.
+async fn stream_text_pipeline(
+    wav_bytes: Vec<u8>,
+    req: AudioRequest,
+    events: tokio::sync::mpsc::UnboundedSender<web::Bytes>,
+    speak_sentences: bool,
+) -> Result<(), AudioError> {
+    let chat = chat_options(&req)?;
+    let send_ack = |language| {
+        if let Some(cue) = acknowledgment_cue(acknowledgment_cues(), language, req.tone()) {
+            let _ = events.send(sse_event("ack", json!({ "audio": cue })));
+        }
+    };
+    // The cue covers the wait, so it goes out as soon as its language is known
+    if let Some(language) = req.language {
+        send_ack(language);
+    }
+
+    // A streaming model lets the client see the transcript as it forms, but
+    // only Whisper reports the language it heard, so detection skips it
+    let streaming_model = std::env::var("STREAMING_TRANSCRIBE_MODEL")
+        .ok()
+        .filter(|model| STREAMING_TRANSCRIBE_MODELS.contains(&model.as_str()));
+    let (transcript, complete, language) = match (streaming_model, req.language) {
+        (Some(model), Some(language)) => {
+            let (transcript, complete) =
+                stream_transcription(&wav_bytes, language, &model, genz_transcription_hints(&req), |partial| {
+                    let _ = events.send(sse_event("partial_transcript", json!({ "transcript": partial })));
+                })
+                .await?;
+            (transcript, complete, language)
+        }
+        (_, requested) => {
+            let transcription =
+                with_retries("STT", || transcribe_audio(&wav_bytes, requested, false, genz_transcription_hints(&req)))
+                    .await?;
+            let language = match requested {
+                Some(language) => language,
+                None => {
+                    let language = detected_reply_language(&transcription);
+                    send_ack(language);
+                    language
+                }
+            };
+            (transcription.text, true, language)
+        }
+    };
+    let instructions = persona_instructions(&chat, language, req.genz_mode, req.tone(), &transcript);
+    let display_transcript = if env_flag("NORMALIZE_TRANSCRIPT_DISPLAY") {
+        normalize_transcript_for_display(&transcript, language)
+    } else {
+        transcript.clone()
+    };
+    let transcript_event = json!({ "transcript": display_transcript, "complete": complete, "language": language.code() });
+    if events.send(sse_event("transcript", transcript_event)).is_err() {
+        info!("Client disconnected before the reply started");
+        return Ok(());
+    }
+
+    let examples = prior_messages(language, req.tone(), &chat).await;
+
+    // Post-processing needs the whole reply, so it rules out speaking early
+    if speak_sentences && std::env::var("POST_PROCESS_CMD").is_err() {
+        let response_text =
+            speak_reply_by_sentence(&req, language, &chat, &instructions, &examples, &transcript, &events).await?;
+        remember_turn(&chat, &transcript, &response_text).await;
+    } else {
+        let response_text = stream_reply(&chat, req.tone(), &instructions, &examples, &transcript, |delta| {
+            events.send(sse_event("token", json!({ "text": delta }))).is_ok()
+        })
+        .await?;
+
+        if events.is_closed() {
+            info!("Client disconnected mid-stream, skipping TTS");
+            return Ok(());
+        }
+
+        let response_text = post_process_reply(response_text).await;
+        remember_turn(&chat, &transcript, &response_text).await;
+        let tone = req.tone();
+        let (mp3_bytes, tts_engine) =
+            match with_retries("TTS", || text_to_speech(&response_text, language, tone, req.voice.as_deref(), req.hd_audio)).await {
+                Ok(speech) => (speech.mp3, tts_provider().name()),
+                Err(e) => match local_tts_fallback(&response_text, language, tone, &e).await? {
+                    Some(speech) => (speech.mp3, "local"),
+                    None => return Err(e),
+                },
+            };
+        tally_tts_characters(&chat, &response_text, tts_engine).await;
+        let _ = events.send(sse_event(
+            "audio",
+            json!({
+                "audio": general_purpose::STANDARD.encode(&mp3_bytes),
+                "reply_text": response_text,
+                "tts_engine": tts_engine,
+            }),
+        ));
+    }
+    let persona_version =
+        persona_experiment(&chat, language, req.genz_mode, req.tone(), &transcript).map(|(version, _)| version);
+    let prompt_hash = env_flag("INCLUDE_PROMPT_HASH").then(|| prompt_hash(&instructions));
+    let _ = events.send(sse_event(
+        "done",
+        json!({ "persona_version": persona_version, "prompt_hash": prompt_hash, "session_id": chat.session_id }),
+    ));
+    Ok(())
+}
+
+#[post("/process-audio-stream-text")]
+async fn process_audio_stream_text(
+    req: web::Json<AudioRequest>,
+    http_req: actix_web::HttpRequest,
+) -> ActixResult<HttpResponse> {
+    stream_text_response(req.into_inner(), &http_req, "/process-audio-stream-text", false).await
+}
+
+/// Like `/process-audio-stream-text`, but voices the reply sentence by
+/// sentence while it streams, so audio starts before the reply is complete.
+#[route("/process-audio/stream", method = "GET", method = "POST")]
+async fn process_audio_stream(
+    req: web::Json<AudioRequest>,
+    http_req: actix_web::HttpRequest,
+) -> ActixResult<HttpResponse> {
+    stream_text_response(req.into_inner(), &http_req, "/process-audio/stream", true).await
+}
+
+async fn stream_text_response(
+    mut req: AudioRequest,
+    http_req: &actix_web::HttpRequest,
+    route: &str,
+    speak_sentences: bool,
+) -> ActixResult<HttpResponse> {
+    if SHUTTING_DOWN.load(Ordering::Relaxed) {
+        info!("Rejecting {} request during shutdown", route);
+        return Ok(shutting_down_response());
+    }
+
+    apply_default_mode(&mut req);
+    apply_default_language(&mut req, http_req);
+    info!("Received {} request: language={}", route, req.language_code());
+    let started = Instant::now();
+    record_request_stats(&req);
+
+    let max_chars = max_audio_base64_chars();
+    if req.audio.len() > max_chars {
+        let e = AudioError::PayloadTooLarge(format!(
+            "audio field is {} characters, limit is {}",
+            req.audio.len(),
+            max_chars
+        ));
+        error!("{}", e);
+        stats().record_error(e.kind());
+        return Err(e.into());
+    }
+
+    chat_options(&req)?;
+    validate_voice(req.voice.as_deref(), tts_provider())?;
+    check_breaker()?;
+    let turn = begin_session_turn(&req).await.map_err(|e| {
+        stats().record_error(e.kind());
+        e
+    })?;
+
+    let log_level = request_log_level(http_req);
+    let converted = DEBUG_LOG_LEVEL
+        .scope(log_level, convert_audio_to_pcm16_24khz(&req.audio))
+        .await
+        .map_err(conversion_error)?;
+
+    let key = latency_key(&req);
+    let estimated_ms = stats().estimated_latency_ms(&key);
+
+    let record_stats = !req.do_not_store;
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    actix_web::rt::spawn(async move {
+        let pipeline_started = Instant::now();
+        let pipeline = stream_text_pipeline(converted.wav, req, tx.clone(), speak_sentences);
+        let result = DEBUG_LOG_LEVEL.scope(log_level, pipeline).await;
+        drop(turn);
+        // Counted once the stream ends, like /process-audio once it responds
+        if result.is_ok() && record_stats {
+            stats().record_success(started.elapsed().as_millis() as u64);
+            if !tx.is_closed() {
+                stats().record_latency(&key, pipeline_started.elapsed().as_millis() as u64);
+            }
+        }
+        if let Err(e) = result {
+            error!("Streaming pipeline failed: {}", e);
+            stats().record_error(e.kind());
+            let mut event = e.details();
+            event["error"] = json!(e.to_string());
+            let _ = tx.send(sse_event("error", event));
+        }
+    });
+
+    // Dropping the body stream on disconnect closes the channel, which stops the pipeline
+    let body = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|event| (Ok::<_, actix_web::Error>(event), rx))
+    });
+
+    // Lets clients size a progress indicator before the first event arrives
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"));
+    if let Some(estimated_ms) = estimated_ms {
+        response.insert_header(("X-Estimated-Processing-Ms", estimated_ms.to_string()));
+    }
+    Ok(response.streaming(body))
+}
+
+/// Ends a conversation so its history is no longer sent as context.
+#[delete("/sessions/{id}")]
+async fn delete_session(id: web::Path<String>) -> ActixResult<HttpResponse> {
+    session_store().expire(&id).await.map_err(|e| {
+        error!("Could not expire session: {}", e);
+        AudioError::SessionStore(e)
+    })?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Full-duplex conversation over a WebSocket.
+///
+/// The first text message is a JSON handshake with the same settings as
+/// `/process-audio` minus `audio`, answered with `{"type":"ready"}`; they
+/// hold for the whole connection. Binary messages carry chunks of an
+/// utterance and `{"type":"end"}` finishes it, which is answered with a
+/// `transcript` message and then a `reply` message carrying the MP3.
+///
+/// `{"type":"interrupt"}` lets the user barge in: the reply in flight is
+/// cancelled, any audio buffered so far is dropped, and `{"type":"interrupted"}`
+/// confirms the server is ready for the next utterance. A reply cut off after
+/// its text was generated stays in the session history. Closing the socket
+/// cancels any reply still in flight too.
+///
+/// With `"input_format":"pcm16"` in the handshake, binary messages are raw
+/// 24kHz mono little-endian PCM16 instead of WebM/Ogg, and the server detects
+/// the end of each utterance itself (see `vad::Vad`) so `end` is optional.
+///
+/// When `API_KEYS` is set the upgrade needs a key, which browsers pass as
+/// `/ws?token=<key>` since they can't set headers on it.
+#[get("/ws")]
+async fn ws_session(
+    http_req: actix_web::HttpRequest,
+    body: web::Payload,
+) -> ActixResult<HttpResponse> {
+    if SHUTTING_DOWN.load(Ordering::Relaxed) {
+        info!("Rejecting /ws connection during shutdown");
+        return Ok(shutting_down_response());
+    }
+    check_breaker()?;
+
+    let (response, session, messages) = actix_ws::handle(&http_req, body)?;
+    info!("Opened WebSocket session");
+    let log_level = request_log_level(&http_req);
+    actix_web::rt::spawn(DEBUG_LOG_LEVEL.scope(log_level, run_ws_session(session, messages, http_req)));
+    Ok(response)
+}
+
+async fn ws_send(session: &mut actix_ws::Session, message: serde_json::Value) -> Result<(), actix_ws::Closed> {
+    session.text(message.to_string()).await
+}
+
+/// Sends an `ack` message with the acknowledgment cue, if one is configured.
+async fn ws_send_ack(session: &mut actix_ws::Session, language: Language, tone: Tone) -> Result<(), actix_ws::Closed> {
+    match acknowledgment_cue(acknowledgment_cues(), language, tone) {
+        Some(cue) => ws_send(session, json!({ "type": "ack", "audio": cue })).await,
+        None => Ok(()),
+    }
+}
+
+/// Encoding of the audio a WebSocket client streams, chosen in the handshake.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum WsInput {
+    /// WebM or Ogg, converted with ffmpeg once the utterance ends.
+    Container,
+    /// Raw 24kHz mono little-endian PCM16, which voice activity detection can
+    /// follow as it arrives.
+    Pcm16,
+}
+
+impl WsInput {
+    /// Takes `input_format` out of the handshake so the rest of it can be
+    /// validated as an `AudioRequest`.
+    fn take_from(settings: &mut serde_json::Value) -> Result<Self, AudioError> {
+        let format = settings.as_object_mut().and_then(|settings| settings.remove("input_format"));
+        match format.as_ref().map(|f| f.as_str()) {
+            None | Some(Some("webm")) => Ok(WsInput::Container),
+            Some(Some("pcm16")) => Ok(WsInput::Pcm16),
+            _ => Err(AudioError::InvalidRequest(
+                "input_format must be \"webm\" or \"pcm16\"".to_string(),
+            )),
+        }
+    }
+}
+
+/// Text messages a client may send once the session is open.
+#[derive(Debug, PartialEq)]
+enum WsControl {
+    /// The utterance is complete, answer it.
+    End,
+    /// Stop the reply in flight and start over.
+    Interrupt,
+}
+
+fn parse_ws_control(text: &str) -> Option<WsControl> {
+    let message: serde_json::Value = serde_json::from_str(text).ok()?;
+    match message["type"].as_str()? {
+        "end" => Some(WsControl::End),
+        "interrupt" => Some(WsControl::Interrupt),
+        _ => None,
+    }
+}
+
+/// Waits for the handshake that opens a session. A missing or invalid one
+/// is answered with an `error` message and ends the session.
+async fn ws_handshake(
+    session: &mut actix_ws::Session,
+    messages: &mut actix_ws::MessageStream,
+    http_req: &actix_web::HttpRequest,
+) -> Option<(AudioRequest, ChatOptions, WsInput)> {
+    let text = loop {
+        match messages.next().await {
+            Some(Ok(actix_ws::Message::Text(text))) => break text,
+            Some(Ok(actix_ws::Message::Ping(bytes))) => {
+                session.pong(&bytes).await.ok()?;
+            }
+            Some(Ok(actix_ws::Message::Binary(_))) => {
+                let _ = ws_send(session, json!({ "type": "error", "error": "expected a handshake before audio" })).await;
+                return None;
+            }
+            Some(Ok(actix_ws::Message::Close(_))) | Some(Err(_)) | None => return None,
+            Some(Ok(_)) => {}
+        }
+    };
+
+    match parse_ws_handshake(&text, http_req) {
+        Ok((req, chat, input)) => {
+            info!("WebSocket session ready: language={}, tone={}", req.language_code(), req.tone().name());
+            let ready = json!({
+                "type": "ready",
+                "language": req.language_code(),
+                "tone": req.tone().name(),
+                "session_id": chat.session_id,
+            });
+            ws_send(session, ready).await.ok()?;
+            Some((req, chat, input))
+        }
+        Err(e) => {
+            error!("Rejected WebSocket handshake: {}", e);
+            let _ = ws_send(session, json!({ "type": "error", "error": e.to_string() })).await;
+            None
+        }
+    }
+}
+
+/// Validates a handshake's settings, reusing `AudioRequest`'s validation
+/// since the audio arrives in later messages.
+fn parse_ws_handshake(
+    text: &str,
+    http_req: &actix_web::HttpRequest,
+) -> Result<(AudioRequest, ChatOptions, WsInput), AudioError> {
+    serde_json::from_str::<serde_json::Value>(text)
+        .ok()
+        .filter(serde_json::Value::is_object)
+        .ok_or_else(|| AudioError::InvalidRequest("handshake must be a JSON object".to_string()))
+        .and_then(|mut settings| {
+            let input = WsInput::take_from(&mut settings)?;
+            settings["audio"] = json!("");
+            serde_json::from_value::<AudioRequest>(settings)
+                .map(|req| (req, input))
+                .map_err(|e| AudioError::InvalidRequest(format!("invalid handshake: {}", e)))
+        })
+        .and_then(|(mut req, input)| {
+            apply_default_mode(&mut req);
+            apply_default_language(&mut req, http_req);
+            let chat = chat_options(&req)?;
+            validate_voice(req.voice.as_deref(), tts_provider())?;
+            Ok((req, chat, input))
+        })
+}
+
+async fn run_ws_session(
+    mut session: actix_ws::Session,
+    mut messages: actix_ws::MessageStream,
+    http_req: actix_web::HttpRequest,
+) {
+    let Some((req, chat, input)) = ws_handshake(&mut session, &mut messages, &http_req).await else {
+        let _ = session.close(None).await;
+        return;
+    };
+
+    let mut vad = match input {
+        WsInput::Pcm16 => vad::Vad::from_env(),
+        WsInput::Container => None,
+    };
+    let max_bytes = max_audio_bytes();
+    let mut audio: Vec<u8> = Vec::new();
+    let mut turn: Option<futures_util::future::LocalBoxFuture<'_, Result<(), AudioError>>> = None;
+    let mut turn_started = Instant::now();
+    loop {
+        tokio::select! {
+            Some(result) = futures_util::future::OptionFuture::from(turn.as_mut()), if turn.is_some() => {
+                turn = None;
+                if result.is_ok() && !req.do_not_store {
+                    stats().record_success(turn_started.elapsed().as_millis() as u64);
+                }
+                if let Err(e) = result {
+                    error!("WebSocket turn failed: {}", e);
+                    stats().record_error(e.kind());
+                    let mut message = e.details();
+                    message["type"] = json!("error");
+                    message["error"] = json!(e.to_string());
+                    if ws_send(&mut session, message).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            message = messages.next() => match message {
+                Some(Ok(actix_ws::Message::Binary(chunk))) => {
+                    if audio.len() + chunk.len() > max_bytes {
+                        error!("WebSocket utterance exceeds {} bytes, discarding it", max_bytes);
+                        audio.clear();
+                        if let Some(vad) = vad.as_mut() {
+                            vad.reset();
+                        }
+                        let error = json!({ "type": "error", "error": "utterance too large" });
+                        if ws_send(&mut session, error).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                    audio.extend_from_slice(&chunk);
+                    // While a reply is in flight the audio keeps buffering
+                    // until the next pause or an explicit end
+                    if vad.as_mut().is_some_and(|vad| vad.feed(&chunk)) && turn.is_none() {
+                        debug!("Silence after speech, answering the utterance");
+                        turn_started = Instant::now();
+                        turn = Some(start_ws_turn(&session, std::mem::take(&mut audio), input, &req, &chat));
+                    }
+                }
+                Some(Ok(actix_ws::Message::Text(text))) => {
+                    let control = parse_ws_control(&text);
+                    if control == Some(WsControl::Interrupt) {
+                        // Dropping the turn aborts its OpenAI calls, TTS and ffmpeg
+                        if turn.take().is_some() {
+                            info!("WebSocket reply interrupted by the client");
+                        }
+                        audio.clear();
+                        if let Some(vad) = vad.as_mut() {
+                            vad.reset();
+                        }
+                        if ws_send(&mut session, json!({ "type": "interrupted" })).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                    let error = if control.is_none() {
+                        Some("expected {\"type\":\"end\"} or {\"type\":\"interrupt\"}")
+                    } else if turn.is_some() {
+                        Some("still replying to the previous utterance")
+                    } else if audio.is_empty() {
+                        Some("no audio received for this utterance")
+                    } else {
+                        None
+                    };
+                    match error {
+                        Some(error) => {
+                            if ws_send(&mut session, json!({ "type": "error", "error": error })).await.is_err() {
+                                break;
+                            }
+                        }
+                        None => {
+                            if let Some(vad) = vad.as_mut() {
+                                vad.reset();
+                            }
+                            turn_started = Instant::now();
+                            turn = Some(start_ws_turn(&session, std::mem::take(&mut audio), input, &req, &chat));
+                        }
+                    }
+                }
+                Some(Ok(actix_ws::Message::Ping(bytes))) => {
+                    if session.pong(&bytes).await.is_err() {
+                        break;
+                    }
+                }
+                Some(Ok(actix_ws::Message::Close(reason))) => {
+                    if turn.is_some() {
+                        info!("WebSocket closed mid-reply, cancelling it");
+                    }
+                    let _ = session.close(reason).await;
+                    return;
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => {
+                    error!("WebSocket protocol error: {}", e);
+                    break;
+                }
+                None => break,
+            },
+        }
+    }
+
+    // Dropping an unfinished turn aborts its OpenAI calls and ffmpeg
+    if turn.is_some() {
+        info!("WebSocket dropped mid-reply, cancelling it");
+    }
+    let _ = session.close(None).await;
+}
+
+fn start_ws_turn<'a>(
+    session: &actix_ws::Session,
+    audio: Vec<u8>,
+    input: WsInput,
+    req: &'a AudioRequest,
+    chat: &'a ChatOptions,
+) -> futures_util::future::LocalBoxFuture<'a, Result<(), AudioError>> {
+    // Each utterance counts as one request in /stats
+    record_request_stats(req);
+    Box::pin(ws_turn(session.clone(), audio, input, req, chat))
+}
+
+/// Answers one utterance on a WebSocket session with the usual pipeline,
+/// sending the acknowledgment cue, if any, as soon as the reply language is
+/// known and the transcript as soon as it is ready.
+async fn ws_turn(
+    mut session: actix_ws::Session,
+    audio: Vec<u8>,
+    input: WsInput,
+    req: &AudioRequest,
+    chat: &ChatOptions,
+) -> Result<(), AudioError> {
+    let _turn = begin_session_turn(req).await?;
+    // The cue covers the wait, so it goes out as soon as its language is known
+    if let Some(language) = req.language {
+        if ws_send_ack(&mut session, language, req.tone()).await.is_err() {
+            return Ok(());
+        }
+    }
+    let converted = match input {
+        WsInput::Container => convert_audio_bytes_to_pcm16_24khz(&audio).await?,
+        WsInput::Pcm16 => ConvertedAudio {
+            wav: tts::pcm16_to_wav(&audio)?,
+            input_channels: Some(1),
+        },
+    };
+    let transcription = with_retries("STT", || {
+        transcribe_audio(&converted.wav, req.language, false, genz_transcription_hints(req))
+    })
+    .await?;
+    let language = match req.language {
+        Some(language) => language,
+        None => detected_reply_language(&transcription),
+    };
+    let transcript = transcription.text;
+    let message = json!({ "type": "transcript", "transcript": transcript, "language": language.code() });
+    if ws_send(&mut session, message).await.is_err() {
+        return Ok(());
+    }
+    if req.language.is_none() && ws_send_ack(&mut session, language, req.tone()).await.is_err() {
+        return Ok(());
+    }
+
+    let (genz_mode, tone) = (req.genz_mode, req.tone());
+    let reply = with_retries("CHAT", || generate_therapist_response(&transcript, language, chat, genz_mode, tone)).await?;
+    let reply = post_process_reply(reply).await;
+    remember_turn(chat, &transcript, &reply).await;
+
+    let (mp3_bytes, tts_engine) =
+        match with_retries("TTS", || text_to_speech(&reply, language, tone, req.voice.as_deref(), req.hd_audio)).await {
+            Ok(speech) => (speech.mp3, tts_provider().name()),
+            Err(e) => match local_tts_fallback(&reply, language, tone, &e).await? {
+                Some(speech) => (speech.mp3, "local"),
+                None => return Err(e),
+            },
+        };
+    tally_tts_characters(chat, &reply, tts_engine).await;
+    let message = json!({
+        "type": "reply",
+        "reply_text": reply,
+        "audio": general_purpose::STANDARD.encode(&mp3_bytes),
+        "tts_engine": tts_engine,
+    });
+    let _ = ws_send(&mut session, message).await;
+    Ok(())
+}
+
+fn json_error_handler(
+    err: actix_web::error::JsonPayloadError,
+    _req: &actix_web::HttpRequest,
+) -> actix_web::Error {
+    // Surface serde's message so clients see e.g. "unknown field `genzz_mode`"
+    error!("Rejected request body: {}", err);
+    match err {
+        actix_web::error::JsonPayloadError::Deserialize(e) => AudioError::InvalidRequest(e.to_string()).into(),
+        actix_web::error::JsonPayloadError::Overflow { .. }
+        | actix_web::error::JsonPayloadError::OverflowKnownLength { .. } => {
+            AudioError::PayloadTooLarge(err.to_string()).into()
+        }
+        _ => AudioError::InvalidRequest(err.to_string()).into(),
+    }
+}
+
+#[actix_web::main]
+async fn main() -> io::Result<()> {
+    dotenv().ok();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    info!("Starting Hearthly API server");
+
+    // Verify static directory
+    if let Ok(entries) = std::fs::read_dir("static") {
+        for entry in entries {
+            info!("Static file: {:?}", entry);
+        }
+    } else {
+        error!("Static directory not found");
+    }
+
+    let mut handlebars = Handlebars::new();
+    info!("Registering Handlebars template");
+    handlebars
+        .register_template_string("index", include_str!("../static/index.html"))
+        .expect("Failed to register template");
+    info!("Handlebars template registered");
+
+    if let Err(e) = parse_extra_headers(&std::env::var("OPENAI_EXTRA_HEADERS").unwrap_or_default()) {
+        error!("Invalid OPENAI_EXTRA_HEADERS: {}", e);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
+    }
+    if let Err(e) = default_mode() {
+        error!("Invalid DEFAULT_MODE: {}", e);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
+    }
+    // Data files are read once, here, rather than on the first request that needs them
+    transcription_vocabulary();
+    persona_example_table();
+    conversation_openers();
+    persona_b_prompts();
+    audio_profiles();
+    overload_fallback_audio();
+    acknowledgment_cues();
+
+    if let Err(e) = tts_model_table() {
+        error!("Invalid TTS_MODEL_BY_LANGUAGE: {}", e);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
+    }
+    for format in [AudioFormat::Mp3, AudioFormat::Wav] {
+        if let Err(e) = format.content_type_label(None) {
+            error!("Invalid AUDIO_CONTENT_TYPE_*: {}", e);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
+        }
+    }
+    if !TTS_MODELS.contains(&default_tts_model().as_str()) {
+        error!("Invalid TTS_MODEL: {}", default_tts_model());
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid TTS_MODEL"));
+    }
+    let client = http_client();
+    if let Err(e) = transcribe::init_transcriber(&client) {
+        error!("Invalid TRANSCRIPTION_PROVIDER: {}", e);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid TRANSCRIPTION_PROVIDER"));
+    }
+    if let Err(e) = tts::init_tts_provider(&client) {
+        error!("Invalid TTS_PROVIDER: {}", e);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid TTS_PROVIDER"));
+    }
+    if let Err(e) = sessions::validate_session_store() {
+        error!("Invalid SESSION_STORE: {}", e);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid SESSION_STORE"));
+    }
+    sessions::spawn_sweeper(summarize_session);
+    if let Err(e) = llm::init_provider(&client) {
+        error!("Invalid LLM_PROVIDER: {}", e);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid LLM_PROVIDER"));
+    }
+    if let Ok(language) = std::env::var("DEFAULT_LANGUAGE") {
+        if Language::from_code(&language).is_none() {
+            error!("Invalid DEFAULT_LANGUAGE: {}", language);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid DEFAULT_LANGUAGE"));
+        }
+    }
+
+    // Catch a bad key at boot; disable with OPENAI_STARTUP_CHECK=false for offline setups
+    let startup_check = std::env::var("OPENAI_STARTUP_CHECK")
+        .map(|v| !matches!(v.trim().to_lowercase().as_str(), "0" | "false" | "no" | "off"))
+        .unwrap_or(true);
+    if startup_check {
+        info!("Verifying OpenAI API keys");
+        key_pool().verify_keys(&client).await;
+    }
+
+    let handlebars_data = web::Data::new(handlebars);
+    let port = std::env::var("PORT").unwrap_or_else(|_| "8080".to_string());
+    let address = format!("0.0.0.0:{}", port);
+    info!("Binding server to {}", address);
+
+    let scope_path = base_path();
+    if !scope_path.is_empty() {
+        info!("Mounting routes under base path {}", scope_path);
+    }
+    if !api_keys().is_empty() {
+        info!("Requiring one of {} API keys", api_keys().len());
+    }
+
+    let server = HttpServer::new(move || {
+        App::new()
+            // CORS wraps the key check so preflight requests are answered without a key
+            .wrap(
+                Cors::default()
+                    .allow_any_origin()
+                    .allow_any_method()
+                    .allow_any_header()
+                    .supports_credentials(),
+            )
+            .app_data(handlebars_data.clone())
+            .app_data(
+                web::JsonConfig::default()
+                    // Leave headroom for the other fields so the audio check reports the 413
+                    .limit(max_audio_base64_chars() + 64 * 1024)
+                    .error_handler(json_error_handler),
+            )
+            .configure(|cfg| configure_routes(cfg, api_keys()))
+    })
+    .disable_signals()
+    .bind(&address)
+    .map_err(|e| {
+        error!("Failed to bind server: {}", e);
+        e
+    })?
+    .run();
+
+    // On a signal, keep answering with 503 + `Connection: close` for
+    // SHUTDOWN_DRAIN_DELAY_SECS so load balancers drop us, then stop
+    // accepting and let in-flight requests finish
+    let handle = server.handle();
+    actix_web::rt::spawn(async move {
+        wait_for_shutdown_signal().await;
+        SHUTTING_DOWN.store(true, Ordering::Relaxed);
+        let delay_secs: u64 = std::env::var("SHUTDOWN_DRAIN_DELAY_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(5);
+        info!("Shutdown requested, rejecting new requests for {}s before stopping", delay_secs);
+        tokio::time::sleep(std::time::Duration::from_secs(delay_secs)).await;
+        handle.stop(true).await;
+    });
+
+    server.await
+}
+
+async fn wait_for_shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = sigterm.recv() => {}
+                    _ = tokio::signal::ctrl_c() => {}
+                }
+                return;
+            }
+            Err(e) => error!("Failed to listen for SIGTERM: {}", e),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header;
+    use actix_web::test::TestRequest;
+
+    fn keys() -> Vec<String> {
+        vec!["alpha".to_string(), "beta".to_string()]
+    }
+
+    #[test]
+    fn api_key_missing_is_rejected() {
+        let req = TestRequest::post().uri("/process-audio").to_srv_request();
+        let err = check_api_key(&req, &keys()).unwrap_err();
+        assert!(matches!(err, AudioError::Unauthorized(_)));
+        assert_eq!(err.status_code(), actix_web::http::StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn api_key_wrong_is_rejected() {
+        let req = TestRequest::post()
+            .uri("/process-audio")
+            .insert_header((header::AUTHORIZATION, "Bearer gamma"))
+            .to_srv_request();
+        assert!(matches!(
+            check_api_key(&req, &keys()),
+            Err(AudioError::Unauthorized(_))
+        ));
+    }
+
+    #[test]
+    fn api_key_valid_is_accepted() {
+        let req = TestRequest::post()
+            .uri("/api/process-audio/stream")
+            .insert_header((header::AUTHORIZATION, "Bearer beta"))
+            .to_srv_request();
+        assert!(check_api_key(&req, &keys()).is_ok());
+    }
+
+    #[test]
+    fn api_key_disabled_without_keys() {
+        let req = TestRequest::post().uri("/process-audio").to_srv_request();
+        assert!(check_api_key(&req, &[]).is_ok());
+    }
+
+    async fn error_body(response: HttpResponse) -> serde_json::Value {
+        let body = actix_web::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn error_responses_have_status_and_json_body_per_variant() {
+        use actix_web::http::StatusCode;
+        let cases = [
+            (AudioError::InvalidLanguage, StatusCode::BAD_REQUEST, "invalid_language"),
+            (AudioError::InvalidRequest("x".into()), StatusCode::BAD_REQUEST, "invalid_request"),
+            (AudioError::InvalidAudio("x".into()), StatusCode::BAD_REQUEST, "invalid_audio"),
+            (AudioError::IncompleteUpload("x".into()), StatusCode::BAD_REQUEST, "incomplete_upload"),
+            (AudioError::PayloadTooLarge("x".into()), StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large"),
+            (AudioError::Unauthorized("x".into()), StatusCode::UNAUTHORIZED, "unauthorized"),
+            (AudioError::Forbidden("x".into()), StatusCode::FORBIDDEN, "forbidden"),
+            (AudioError::NotFound("x".into()), StatusCode::NOT_FOUND, "not_found"),
+            (AudioError::FFmpeg("x".into()), StatusCode::INTERNAL_SERVER_ERROR, "internal_error"),
+            (AudioError::OpenAI("x".into()), StatusCode::BAD_GATEWAY, "upstream_error"),
+            (AudioError::Rejected("x".into()), StatusCode::BAD_GATEWAY, "upstream_error"),
+            (AudioError::SessionStore("x".into()), StatusCode::BAD_GATEWAY, "session_store_error"),
+            (AudioError::Overloaded("x".into()), StatusCode::SERVICE_UNAVAILABLE, "upstream_overloaded"),
+            (AudioError::ShuttingDown, StatusCode::SERVICE_UNAVAILABLE, "shutting_down"),
+            (
+                AudioError::Unavailable { retry_after: std::time::Duration::from_secs(5) },
+                StatusCode::SERVICE_UNAVAILABLE,
+                "upstream_unavailable",
+            ),
+            (
+                AudioError::RateLimited { message: "x".into(), retry_after: None },
+                StatusCode::TOO_MANY_REQUESTS,
+                "rate_limited",
+            ),
+            (AudioError::SessionLimit("x".into()), StatusCode::TOO_MANY_REQUESTS, "session_limit"),
+            (AudioError::SessionBusy("x".into()), StatusCode::CONFLICT, "session_busy"),
+            (AudioError::TooManySessions("x".into()), StatusCode::SERVICE_UNAVAILABLE, "too_many_sessions"),
+            (AudioError::Timeout("x".into()), StatusCode::GATEWAY_TIMEOUT, "upstream_timeout"),
+        ];
+        for (error, status, code) in cases {
+            let message = error.to_string();
+            let response = error.error_response();
+            assert_eq!(response.status(), status, "{}", code);
+            let body = error_body(response).await;
+            assert_eq!(body["error"]["code"], code);
+            assert_eq!(body["error"]["message"], message);
+        }
+    }
+
+    #[actix_web::test]
+    async fn error_responses_carry_retry_hints() {
+        let cases = [
+            (AudioError::Timeout("x".into()), true),
+            (AudioError::OpenAI("x".into()), true),
+            (AudioError::Overloaded("x".into()), true),
+            (AudioError::ShuttingDown, true),
+            (AudioError::InvalidLanguage, false),
+            (AudioError::InvalidRequest("x".into()), false),
+            (AudioError::PayloadTooLarge("x".into()), false),
+            (AudioError::Unauthorized("x".into()), false),
+            (AudioError::Rejected("x".into()), false),
+        ];
+        for (error, retryable) in cases {
+            let body = error_body(error.error_response()).await;
+            assert_eq!(body["error"]["retryable"], retryable, "{}", body);
+            assert!(body["error"].get("retry_after_ms").is_none());
+        }
+
+        let limited = AudioError::RateLimited {
+            message: "slow down".into(),
+            retry_after: Some(std::time::Duration::from_secs(7)),
+        };
+        let body = error_body(limited.error_response()).await;
+        assert_eq!(body["error"]["retryable"], true);
+        assert_eq!(body["error"]["retry_after_ms"], 7000);
+
+        let body = error_body(AudioError::Unavailable { retry_after: std::time::Duration::from_millis(1500) }.error_response()).await;
+        assert_eq!(body["error"]["retry_after_ms"], 1500);
+    }
+
+    #[test]
+    fn rate_limit_echoes_retry_after() {
+        let mut headers = HeaderMap::new();
+        headers.insert(reqwest::header::RETRY_AFTER, HeaderValue::from_static(" 12 "));
+        let error = upstream_error(reqwest::StatusCode::TOO_MANY_REQUESTS, retry_after(&headers), "slow down".into());
+        assert!(matches!(
+            error,
+            AudioError::RateLimited { retry_after: Some(delay), .. } if delay == std::time::Duration::from_secs(12)
+        ));
+
+        let response = error.error_response();
+        assert_eq!(response.status(), actix_web::http::StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers().get("Retry-After").unwrap(), "12");
+    }
+
+    #[test]
+    fn rate_limit_without_retry_after_sends_no_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert(reqwest::header::RETRY_AFTER, HeaderValue::from_static("Wed, 21 Oct 2026 07:28:00 GMT"));
+        assert_eq!(retry_after(&headers), None);
+
+        let error = upstream_error(reqwest::StatusCode::TOO_MANY_REQUESTS, None, "slow down".into());
+        assert!(error.error_response().headers().get("Retry-After").is_none());
+    }
+
+    #[actix_web::test]
+    async fn with_retries_succeeds_after_transient_failures() {
+        std::env::set_var("RETRY_TEST_MAX_RETRIES", "3");
+        std::env::set_var("RETRY_TEST_RETRY_BACKOFF_MS", "20");
+        let attempts = std::cell::Cell::new(0);
+        let started = Instant::now();
+        let result = with_retries("RETRY_TEST", || {
+            attempts.set(attempts.get() + 1);
+            let attempt = attempts.get();
+            async move {
+                match attempt {
+                    1 => Err(AudioError::Overloaded("busy".into())),
+                    2 => Err(AudioError::OpenAI("500".into())),
+                    _ => Ok("reply"),
+                }
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), "reply");
+        assert_eq!(attempts.get(), 3);
+        // Jittered waits of 10-20ms then 20-40ms
+        let elapsed = started.elapsed().as_millis();
+        assert!(elapsed >= 30, "retried after only {}ms", elapsed);
+    }
+
+    #[actix_web::test]
+    async fn with_retries_gives_up_after_max_retries() {
+        std::env::set_var("RETRY_LIMIT_TEST_MAX_RETRIES", "2");
+        std::env::set_var("RETRY_LIMIT_TEST_RETRY_BACKOFF_MS", "1");
+        let attempts = std::cell::Cell::new(0);
+        let result: Result<(), _> = with_retries("RETRY_LIMIT_TEST", || {
+            attempts.set(attempts.get() + 1);
+            async { Err(AudioError::Timeout("slow".into())) }
+        })
+        .await;
+        assert!(matches!(result, Err(AudioError::Timeout(_))));
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[actix_web::test]
+    async fn with_retries_does_not_retry_rejections() {
+        let attempts = std::cell::Cell::new(0);
+        let result: Result<(), _> = with_retries("RETRY_REJECT_TEST", || {
+            attempts.set(attempts.get() + 1);
+            async { Err(AudioError::Rejected("bad key".into())) }
+        })
+        .await;
+        assert!(matches!(result, Err(AudioError::Rejected(_))));
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[test]
+    fn jittered_stays_between_half_and_full_backoff() {
+        for backoff_ms in [1, 2, 500, 8000] {
+            let delay = jittered(backoff_ms);
+            assert!(delay >= backoff_ms / 2 && delay <= backoff_ms, "{} for {}", delay, backoff_ms);
+        }
+    }
+
+    #[test]
+    fn every_language_has_a_voice_and_instructions() {
+        for language in Language::ALL {
+            assert_eq!(Language::from_code(language.code()), Some(language));
+            assert!(!get_language_instructions(language, false, Tone::Calm).is_empty());
+            assert!(!default_genz_vocabulary(language).is_empty());
+            assert!(reply_to_ssml("Hi.", language, Tone::Calm).contains("xml:lang="));
+        }
+        assert_eq!(Language::from_code("xx"), None);
+    }
+
+    #[test]
+    fn language_deserializes_from_code() {
+        let req: AudioRequest = serde_json::from_value(json!({ "audio": "", "language": "pa" })).unwrap();
+        assert_eq!(req.language, Some(Language::Pa));
+        let err = serde_json::from_value::<AudioRequest>(json!({ "audio": "", "language": "xx" }))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("xx"), "{}", err);
+    }
+
+    #[test]
+    fn whisper_language_names_map_back() {
+        assert_eq!(Language::from_whisper("Hindi"), Some(Language::Hi));
+        assert_eq!(Language::from_whisper("panjabi"), Some(Language::Pa));
+        assert_eq!(Language::from_whisper("de"), Some(Language::De));
+        assert_eq!(Language::from_whisper("japanese"), None);
+    }
+
+    #[test]
+    fn canned_replies_are_localized() {
+        for language in Language::ALL {
+            for tone in Tone::ALL {
+                assert!(!rerecord_prompt(language, tone).is_empty());
+                assert!(!overload_message(language, tone).is_empty());
+            }
+        }
+        assert_ne!(rerecord_prompt(Language::Hi, Tone::Calm), rerecord_prompt(Language::En, Tone::Calm));
+    }
+
+    #[test]
+    fn transcript_display_uses_danda_for_hindi_and_punjabi() {
+        assert_eq!(normalize_transcript_for_display("main theek hoon. aap", Language::Hi), "main theek hoon। aap।");
+        assert_eq!(normalize_transcript_for_display("i am fine . you", Language::En), "I am fine. You.");
+    }
+
+    fn request(body: serde_json::Value) -> AudioRequest {
+        let mut body = body;
+        body["audio"] = json!("");
+        serde_json::from_value(body).unwrap()
+    }
+
+    #[test]
+    fn legacy_mode_flags_map_to_tones() {
+        assert_eq!(request(json!({})).tone(), Tone::Calm);
+        assert_eq!(request(json!({ "sarcastic_mode": true })).tone(), Tone::Sarcastic);
+        assert_eq!(request(json!({ "shenanigan_mode": true })).tone(), Tone::Shenanigan);
+        assert_eq!(request(json!({ "seductive_mode": true })).tone(), Tone::Seductive);
+        assert_eq!(request(json!({ "tone": "seductive", "seductive_mode": true })).tone(), Tone::Seductive);
+
+        let genz = request(json!({ "genz": true, "tone": "sarcastic" }));
+        assert!(genz.genz_mode);
+        assert_eq!(genz.tone(), Tone::Sarcastic);
+    }
+
+    #[test]
+    fn tone_names_round_trip() {
+        for tone in Tone::ALL {
+            assert_eq!(Tone::parse(tone.name()), Some(tone));
+        }
+        assert_eq!(Tone::parse("grumpy"), None);
+    }
+
+    #[test]
+    fn instructions_differ_per_tone() {
+        for language in Language::ALL {
+            let instructions: Vec<String> = Tone::ALL
+                .iter()
+                .map(|&tone| get_language_instructions(language, false, tone))
+                .collect();
+            for (i, a) in instructions.iter().enumerate() {
+                for b in &instructions[i + 1..] {
+                    assert_ne!(a, b, "{}", language);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn voices_are_checked_against_the_provider() {
+        let openai = tts::OpenAiTts::new(Client::new());
+        assert!(validate_voice(None, &openai).is_ok());
+        assert!(validate_voice(Some("shimmer"), &openai).is_ok());
+        assert!(matches!(
+            validate_voice(Some("rachel"), &openai),
+            Err(AudioError::InvalidRequest(_))
+        ));
+    }
+
+    #[test]
+    fn tts_usage_counts_the_text_actually_synthesized() {
+        std::env::set_var("TTS_STRIP_EMOJI", "1");
+        assert_eq!(speakable_text("You did it 🎉❤️"), "You did it ");
+        assert_eq!(speakable_text("You did it 🎉❤️").chars().count(), 11);
+        // Nothing left to say, so the emoji are spoken after all
+        assert_eq!(speakable_text("🎉"), "🎉");
+    }
+
+    fn chat(session_id: Option<&str>) -> ChatOptions {
+        ChatOptions {
+            model: "gpt-4o".to_string(),
+            stop: Vec::new(),
+            temperature: 0.7,
+            session_id: session_id.map(str::to_string),
+            do_not_store: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn tts_usage_is_tallied_per_session() {
+        let session = chat(Some("tts-usage-test"));
+        assert_eq!(tally_tts_characters(&session, "Hello", "openai").await, Some(5));
+        assert_eq!(tally_tts_characters(&session, "there", "openai").await, Some(10));
+        assert_eq!(tally_tts_characters(&session, "unbilled", "local").await, None);
+        assert_eq!(tally_tts_characters(&chat(None), "Hello", "openai").await, None);
+    }
+
+    #[test]
+    fn acknowledgment_cues_fall_back_to_the_language() {
+        let cues = serde_json::from_value(json!({ "hi:sarcastic": "c2FyY2FzdGlj", "hi": "aGk=" })).unwrap();
+        assert_eq!(acknowledgment_cue(&cues, Language::Hi, Tone::Sarcastic), Some("c2FyY2FzdGlj"));
+        assert_eq!(acknowledgment_cue(&cues, Language::Hi, Tone::Calm), Some("aGk="));
+        assert_eq!(acknowledgment_cue(&cues, Language::En, Tone::Calm), None);
+    }
+
+    #[test]
+    fn persona_buckets_are_stable() {
+        assert_eq!(persona_bucket(None, "I had a rough day"), persona_bucket(None, "I had a rough day"));
+        // A session keeps its bucket whatever is said
+        assert_eq!(persona_bucket(Some("abc"), "hello"), persona_bucket(Some("abc"), "goodbye"));
+        assert!((0..100).contains(&persona_bucket(Some("abc"), "")));
+    }
+
+    #[tokio::test]
+    async fn do_not_store_turns_leave_no_trace() {
+        let req = request(json!({ "session_id": "do-not-store-test", "do_not_store": true }));
+        let session = chat_options(&req).unwrap();
+        assert!(session.do_not_store);
+
+        remember_turn(&session, "something private", "I hear you").await;
+        assert_eq!(tally_tts_characters(&session, "I hear you", "openai").await, None);
+        assert!(session_store().get("do-not-store-test", 10).await.unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn streamed_requests_are_counted_in_stats() {
+        let _flag = shutdown_flag().await;
+        let count = |name: &str| {
+            let snapshot = serde_json::to_value(stats().snapshot(false)).unwrap();
+            match name {
+                "total" => snapshot["total_requests"].as_u64().unwrap(),
+                kind => snapshot["errors"][kind].as_u64().unwrap_or(0),
+            }
+        };
+        let (total, too_large) = (count("total"), count("payload_too_large"));
+
+        let mut req = request(json!({}));
+        req.audio = "A".repeat(max_audio_base64_chars() + 4);
+        let http_req = TestRequest::default().to_http_request();
+        let result = stream_text_response(req, &http_req, "/process-audio/stream", true).await;
+
+        assert!(result.is_err());
+        assert!(count("total") > total);
+        assert!(count("payload_too_large") > too_large);
+    }
+
+    fn chat_stream(chunks: &[&[u8]]) -> impl futures_util::Stream<Item = Result<web::Bytes, AudioError>> + Unpin {
+        let chunks: Vec<_> = chunks.iter().map(|chunk| Ok(web::Bytes::copy_from_slice(chunk))).collect();
+        futures_util::stream::iter(chunks)
+    }
+
+    #[tokio::test]
+    async fn chat_stream_frames_are_parsed_into_deltas() {
+        let frames = "data: {\"choices\":[{\"delta\":{\"role\":\"assistant\"}}]}\n\n\
+             data: {\"choices\":[{\"delta\":{\"content\":\"नमस्ते\"}}]}\n\n\
+             : keep-alive\n\n\
+             data: {\"choices\":[{\"delta\":{\"content\":\", friend.\"}}]}\n\n\
+             data: [DONE]\n\n";
+        // Split mid-character to check multi-byte text survives chunking
+        let (first, rest) = frames.as_bytes().split_at(frames.find("स्").unwrap() + 1);
+
+        let mut deltas = Vec::new();
+        let text = read_chat_stream(chat_stream(&[first, rest]), |delta| {
+            deltas.push(delta.to_string());
+            true
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(text, "नमस्ते, friend.");
+        assert_eq!(deltas, ["नमस्ते", ", friend."]);
+    }
+
+    #[tokio::test]
+    async fn chat_stream_stops_when_the_consumer_does() {
+        let frames = b"data: {\"choices\":[{\"delta\":{\"content\":\"One.\"}}]}\n\
+            data: {\"choices\":[{\"delta\":{\"content\":\" Two.\"}}]}\n";
+        let text = read_chat_stream(chat_stream(&[&frames[..]]), |_| false).await.unwrap();
+        assert_eq!(text, "One.");
+    }
+
+    #[tokio::test]
+    async fn chat_stream_rejects_malformed_frames() {
+        let result = read_chat_stream(chat_stream(&[b"data: {not json\n".as_slice()]), |_| true).await;
+        assert!(matches!(result, Err(AudioError::OpenAI(_))));
+    }
+
+    #[test]
+    fn sse_events_are_framed() {
+        let event = sse_event("token", json!({ "text": "Hi" }));
+        assert_eq!(&event[..], b"event: token\ndata: {\"text\":\"Hi\"}\n\n");
+    }
+
+    #[test]
+    fn sentences_end_at_terminators_followed_by_whitespace() {
+        assert_eq!(sentence_end("I hear you. Tell me"), Some(11));
+        assert_eq!(sentence_end("It costs 2.5"), None);
+        assert_eq!(sentence_end("Wait..."), None);
+    }
+
+    #[tokio::test]
+    async fn new_conversations_start_from_the_configured_opener() {
+        let openers =
+            serde_json::from_str(r#"{"hi:calm": "नमस्ते! आज कैसा महसूस कर रहे हो?", "en": "Hey, good to see you."}"#).unwrap();
+
+        assert_eq!(conversation_opener(&openers, Language::Hi, Tone::Calm).as_deref(), Some("नमस्ते! आज कैसा महसूस कर रहे हो?"));
+        assert_eq!(conversation_opener(&openers, Language::En, Tone::Sarcastic).as_deref(), Some("Hey, good to see you."));
+        assert_eq!(conversation_opener(&openers, Language::Hi, Tone::Sarcastic), None);
+
+        let session = chat(Some("opener-test"));
+        let prior = prior_messages_with(&openers, Language::En, Tone::Calm, &session).await;
+        assert_eq!(prior.last().unwrap()["content"], "Hey, good to see you.");
+
+        // Later turns continue the real conversation instead
+        session_store().append("opener-test", "hello", "hi there").await.unwrap();
+        let prior = prior_messages_with(&openers, Language::En, Tone::Calm, &session).await;
+        assert!(prior.iter().all(|message| message["content"] != "Hey, good to see you."));
+    }
+
+    #[tokio::test]
+    async fn returning_sessions_resume_from_their_summary() {
+        std::env::set_var("SESSION_SUMMARIES", "1");
+        session_store().save_summary("summary-test", "Talked about work stress.").await.unwrap();
+
+        let prior = prior_messages(Language::En, Tone::Calm, &chat(Some("summary-test"))).await;
+        let summary = prior.last().unwrap();
+        assert_eq!(summary["role"], "system");
+        assert!(summary["content"].as_str().unwrap().ends_with("Talked about work stress."));
+
+        // Once the conversation is going again, the history takes over
+        session_store().append("summary-test", "hello again", "welcome back").await.unwrap();
+        let prior = prior_messages(Language::En, Tone::Calm, &chat(Some("summary-test"))).await;
+        assert_eq!(prior.last().unwrap()["content"], "welcome back");
+        assert!(prior.iter().all(|message| message["role"] != "system"));
+    }
+
+    #[test]
+    fn session_summaries_are_made_from_the_transcript() {
+        let history = [
+            json!({"role": "user", "content": "Work is hard"}),
+            json!({"role": "assistant", "content": "What about it?"}),
+        ];
+        assert_eq!(summary_transcript(&history), "user: Work is hard\nassistant: What about it?");
+    }
+
+    #[tokio::test]
+    async fn sessions_are_refused_past_their_request_cap() {
+        std::env::set_var("SESSION_MAX_REQUESTS", "2");
+        let req = request(json!({ "session_id": "request-cap-test" }));
+        assert!(check_session_quota(&req).await.is_ok());
+        assert!(check_session_quota(&req).await.is_ok());
+
+        let e = check_session_quota(&req).await.unwrap_err();
+        assert!(matches!(e, AudioError::SessionLimit(_)));
+        assert_eq!(e.status_code(), actix_web::http::StatusCode::TOO_MANY_REQUESTS);
+        assert!(!e.retryable());
+        // Sessionless requests aren't counted
+        assert!(check_session_quota(&request(json!({}))).await.is_ok());
+    }
+
+    #[test]
+    fn websocket_control_messages_are_parsed() {
+        assert_eq!(parse_ws_control(r#"{"type":"end"}"#), Some(WsControl::End));
+        assert_eq!(parse_ws_control(r#"{"type":"interrupt"}"#), Some(WsControl::Interrupt));
+        assert_eq!(parse_ws_control(r#"{"type":"pause"}"#), None);
+        assert_eq!(parse_ws_control("interrupt"), None);
+    }
+
+    #[test]
+    fn websocket_input_format_is_taken_from_the_handshake() {
+        let mut settings = json!({ "input_format": "pcm16", "language": "en" });
+        assert_eq!(WsInput::take_from(&mut settings).unwrap(), WsInput::Pcm16);
+        assert_eq!(settings, json!({ "language": "en" }));
+
+        assert_eq!(WsInput::take_from(&mut json!({})).unwrap(), WsInput::Container);
+        assert_eq!(WsInput::take_from(&mut json!({ "input_format": "webm" })).unwrap(), WsInput::Container);
+        assert!(WsInput::take_from(&mut json!({ "input_format": "flac" })).is_err());
+        assert!(WsInput::take_from(&mut json!({ "input_format": 16 })).is_err());
+    }
+
+    #[test]
+    fn sample_ranges_are_parsed_against_the_body_length() {
+        assert_eq!(byte_range("bytes=0-99", 1000), Ok(Some((0, 99))));
+        assert_eq!(byte_range("bytes=900-", 1000), Ok(Some((900, 999))));
+        assert_eq!(byte_range("bytes=-100", 1000), Ok(Some((900, 999))));
+        // An end past the body is clamped, a start past it can't be served
+        assert_eq!(byte_range("bytes=500-5000", 1000), Ok(Some((500, 999))));
+        assert_eq!(byte_range("bytes=1000-", 1000), Err(()));
+        assert_eq!(byte_range("bytes=-0", 1000), Err(()));
+        // Anything else gets the whole body
+        assert_eq!(byte_range("bytes=0-1,5-9", 1000), Ok(None));
+        assert_eq!(byte_range("bytes=9-1", 1000), Ok(None));
+        assert_eq!(byte_range("items=0-1", 1000), Ok(None));
+    }
+
+    #[actix_web::test]
+    async fn voice_sample_rejects_an_unknown_content_type() {
+        let _flag = shutdown_flag().await;
+        let app = actix_web::test::init_service(App::new().service(voice_sample)).await;
+        let req = actix_web::test::TestRequest::get()
+            .uri("/voices/nova/sample?content_type=audio/ogg")
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    /// `count` MPEG-1 Layer III frames at 128kbps/44.1kHz, 417 bytes each.
+    fn mp3_frames(count: usize) -> Vec<u8> {
+        let mut frame = vec![0u8; 417];
+        frame[..4].copy_from_slice(&[0xff, 0xfb, 0x90, 0x00]);
+        frame.repeat(count)
+    }
+
+    #[test]
+    fn complete_mp3_output_is_accepted() {
+        assert!(validate_mp3_frames(&mp3_frames(3)).is_ok());
+
+        let mut tagged = b"ID3\x04\x00\x00\x00\x00\x00\x05".to_vec();
+        tagged.extend([0; 5]);
+        tagged.extend(mp3_frames(2));
+        tagged.extend(b"TAG");
+        tagged.extend([0; 125]);
+        assert!(validate_mp3_frames(&tagged).is_ok());
+    }
+
+    #[test]
+    fn truncated_mp3_output_is_rejected() {
+        let mut truncated = mp3_frames(3);
+        truncated.truncate(417 * 2 + 100);
+        let err = validate_mp3_frames(&truncated).unwrap_err();
+        assert!(err.to_string().contains("Incomplete MP3 output"), "{}", err);
+
+        assert!(validate_mp3_frames(&[]).is_err());
+        assert!(validate_mp3_frames(b"not an mp3 at all").is_err());
+    }
+
+    async fn post_process_audio(body: serde_json::Value) -> (actix_web::http::StatusCode, serde_json::Value) {
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::JsonConfig::default().error_handler(json_error_handler))
+                .service(process_audio),
+        )
+        .await;
+        let req = TestRequest::post().uri("/process-audio").set_json(body).to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        (resp.status(), actix_web::test::read_body_json(resp).await)
+    }
+
+    #[actix_web::test]
+    async fn unknown_request_fields_are_named_in_the_400() {
+        let (status, body) = post_process_audio(json!({ "audio": "AAAA", "genzz_mode": true })).await;
+        assert_eq!(status, actix_web::http::StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["code"], "invalid_request");
+        let message = body["error"]["message"].as_str().unwrap();
+        assert!(message.contains("unknown field `genzz_mode`"), "{}", message);
+    }
+
+    #[actix_web::test]
+    async fn mode_flags_must_be_booleans() {
+        let (status, body) = post_process_audio(json!({ "audio": "AAAA", "genz_mode": "yes" })).await;
+        assert_eq!(status, actix_web::http::StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["code"], "invalid_request");
+        let message = body["error"]["message"].as_str().unwrap();
+        assert!(message.contains("invalid type"), "{}", message);
+    }
+
+    #[actix_web::test]
+    async fn oversized_audio_field_is_rejected_before_decoding() {
+        let _flag = shutdown_flag().await;
+        std::env::set_var("MAX_AUDIO_BASE64_CHARS", "64");
+        // Not valid base64, so a 413 rather than a decode error shows the check came first
+        let (status, body) = post_process_audio(json!({ "audio": "!".repeat(65), "language": "en" })).await;
+        std::env::remove_var("MAX_AUDIO_BASE64_CHARS");
+        assert_eq!(status, actix_web::http::StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body["error"]["code"], "payload_too_large");
+        let message = body["error"]["message"].as_str().unwrap();
+        assert!(message.contains("65 characters, limit is 64"), "{}", message);
+    }
+
+    #[test]
+    fn transcription_prompt_comes_from_the_vocabulary_file() {
+        assert_eq!(transcription_prompt(Language::En, false), None);
+        assert_eq!(transcription_prompt(Language::En, true).as_deref(), Some("lit, vibes, slay, no cap, bet, fam"));
+
+        let vocab = serde_json::from_str(r#"{"pa": ["ਗੁਰਪ੍ਰੀਤ", "ਲੁਧਿਆਣਾ"], "genz:pa": ["ਸਿਰਾ"]}"#).unwrap();
+        assert_eq!(vocabulary_prompt(&vocab, Language::Pa, false).as_deref(), Some("ਗੁਰਪ੍ਰੀਤ, ਲੁਧਿਆਣਾ"));
+        // Genz slang goes first so the cap keeps it
+        assert_eq!(vocabulary_prompt(&vocab, Language::Pa, true).as_deref(), Some("ਸਿਰਾ, ਗੁਰਪ੍ਰੀਤ, ਲੁਧਿਆਣਾ"));
+        assert_eq!(vocabulary_prompt(&vocab, Language::Hi, false), None);
+
+        std::env::set_var("MAX_TRANSCRIPTION_PROMPT_CHARS", "14");
+        assert_eq!(vocabulary_prompt(&vocab, Language::Pa, true).as_deref(), Some("ਸਿਰਾ, ਗੁਰਪ੍ਰੀਤ"));
+        std::env::remove_var("MAX_TRANSCRIPTION_PROMPT_CHARS");
+    }
+
+    #[test]
+    fn ffmpeg_stderr_is_summarized_without_paths() {
+        let stderr = "ffmpeg version 6.0 Copyright (c) 2000-2023 the FFmpeg developers\n  \
+            configuration: --prefix=/opt/build/ffmpeg --enable-libopus\n\
+            [matroska,webm @ 0x55d4c2a1b2c0] EBML header parsing failed\n\
+            /tmp/upload-1234.webm: Invalid data found when processing input\n";
+        let summary = summarize_ffmpeg_stderr(stderr);
+        assert_eq!(summary, "corrupt_container");
+        assert!(!summary.contains("/tmp") && !summary.contains("/opt"));
+
+        let stderr = "Input #0, ogg, from 'pipe:0':\n  Stream #0:1: Audio: opus, 48000 Hz, mono\n\
+            [opus @ 0x7f] Error decoding packet: File ended prematurely\n";
+        assert_eq!(summarize_ffmpeg_stderr(stderr), "truncated_input (in opus, stream #0:1)");
+
+        assert_eq!(summarize_ffmpeg_stderr("something went wrong"), "conversion_failed");
+    }
+
+    #[test]
+    fn loudnorm_stats_are_read_from_stderr() {
+        let stderr = "Stream mapping:\n  Stream #0:0 -> #0:0 (pcm_s16le (native) -> pcm_s16le (native))\n\
+            [Parsed_loudnorm_0 @ 0x55d4c2a1b2c0] \n{\n\
+            \t\"input_i\" : \"-27.61\",\n\t\"input_tp\" : \"-4.47\",\n\t\"input_lra\" : \"18.06\",\n\
+            \t\"output_i\" : \"-16.58\",\n\t\"output_tp\" : \"-1.50\",\n\
+            \t\"normalization_type\" : \"dynamic\",\n\t\"target_offset\" : \"0.58\"\n}\n";
+        assert_eq!(
+            parse_loudnorm_stats(stderr),
+            Some(Loudness { input_lufs: -27.61, gain_db: 11.03 })
+        );
+
+        let silent = "{\n\t\"input_i\" : \"-inf\",\n\t\"output_i\" : \"-inf\"\n}\n";
+        assert_eq!(parse_loudnorm_stats(silent), None);
+        assert_eq!(parse_loudnorm_stats("size=N/A time=00:00:02.40"), None);
+    }
+
+    /// Serializes tests that set `SHUTTING_DOWN` with tests whose handlers
+    /// must see it clear.
+    async fn shutdown_flag() -> tokio::sync::MutexGuard<'static, ()> {
+        static LOCK: OnceLock<tokio::sync::Mutex<()>> = OnceLock::new();
+        LOCK.get_or_init(Default::default).lock().await
+    }
+
+    #[actix_web::test]
+    async fn requests_are_refused_while_shutting_down() {
+        let _flag = shutdown_flag().await;
+        let app = actix_web::test::init_service(
+            App::new()
+                .service(process_audio)
+                .service(health)
+                .service(voice_sample),
+        )
+        .await;
+
+        SHUTTING_DOWN.store(true, Ordering::Relaxed);
+        let req = TestRequest::post().uri("/process-audio").set_json(json!({ "audio": "AAAA" })).to_request();
+        let processing = actix_web::test::call_service(&app, req).await;
+        let health_check = actix_web::test::call_service(&app, TestRequest::get().uri("/health").to_request()).await;
+        let sample = actix_web::test::call_service(&app, TestRequest::get().uri("/voices/nova/sample").to_request()).await;
+        SHUTTING_DOWN.store(false, Ordering::Relaxed);
+
+        for resp in [processing, health_check, sample] {
+            assert_eq!(resp.status(), actix_web::http::StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(resp.headers().get("Connection").unwrap(), "close");
+        }
+    }
+
+    #[test]
+    fn input_auto_gain_is_opt_in_and_bounded() {
+        assert_eq!(input_gain_filter(), None);
+
+        std::env::set_var("INPUT_AUTO_GAIN", "1");
+        assert_eq!(input_gain_filter().as_deref(), Some("dynaudnorm=p=0.9:m=10"));
+
+        std::env::set_var("INPUT_AUTO_GAIN_TARGET_PEAK", "0.5");
+        std::env::set_var("INPUT_AUTO_GAIN_MAX_GAIN", "4");
+        assert_eq!(input_gain_filter().as_deref(), Some("dynaudnorm=p=0.5:m=4"));
+
+        // Out-of-range settings fall back to the defaults
+        std::env::set_var("INPUT_AUTO_GAIN_TARGET_PEAK", "1.5");
+        std::env::set_var("INPUT_AUTO_GAIN_MAX_GAIN", "500");
+        assert_eq!(input_gain_filter().as_deref(), Some("dynaudnorm=p=0.9:m=10"));
+
+        for name in ["INPUT_AUTO_GAIN", "INPUT_AUTO_GAIN_TARGET_PEAK", "INPUT_AUTO_GAIN_MAX_GAIN"] {
+            std::env::remove_var(name);
+        }
+    }
+
+    /// A WebM prefix: EBML header with 4 bytes of content, then a Segment
+    /// declaring `segment_size` (a one-byte vint, 0x7f for unknown).
+    fn webm_prefix(segment_size: u8) -> Vec<u8> {
+        let mut prefix = vec![0x1a, 0x45, 0xdf, 0xa3, 0x84, 0x42, 0x86, 0x81, 0x01];
+        prefix.extend([0x18, 0x53, 0x80, 0x67, 0x80 | segment_size]);
+        prefix
+    }
+
+    #[test]
+    fn truncated_webm_uploads_are_detected() {
+        // 9 header bytes + 5 segment header bytes + 100 of content
+        let prefix = webm_prefix(100);
+        assert!(check_container_length(&prefix, 114).is_ok());
+        assert!(check_container_length(&prefix, 200).is_ok());
+        let err = check_container_length(&prefix, 60).unwrap_err();
+        assert!(matches!(err, AudioError::IncompleteUpload(_)));
+        assert!(err.to_string().contains("received 60 of 114 bytes"), "{}", err);
+
+        // Streamed recordings leave the size unknown, and Ogg has no declared size
+        assert!(check_container_length(&webm_prefix(0x7f), 60).is_ok());
+        assert!(check_container_length(b"OggS\x00\x02", 6).is_ok());
+    }
+
+    #[test]
+    fn ebml_vints_decode_with_their_width() {
+        assert_eq!(read_ebml_vint(&[0x81]), Some((1, 1)));
+        assert_eq!(read_ebml_vint(&[0x40, 0x64]), Some((100, 2)));
+        assert_eq!(read_ebml_vint(&[0xff]), None);
+        assert_eq!(read_ebml_vint(&[0x40]), None);
+        assert_eq!(read_ebml_vint(&[]), None);
+    }
+
+    #[test]
+    fn decoded_duration_is_read_from_the_wav() {
+        let wav = tts::pcm16_to_wav(&[0; 48000]).unwrap();
+        assert!((wav_duration_secs(&wav) - 1.0).abs() < 0.001);
+    }
+
+    #[actix_web::test]
+    async fn hung_upstream_times_out_as_a_504() {
+        // Accepts the connection but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/v1/audio/speech", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (_socket, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+        });
+
+        std::env::set_var("OPENAI_REQUEST_TIMEOUT_SECS", "1");
+        let started = Instant::now();
+        let err = AudioError::from(http_client().post(&url).timeout(request_timeout()).send().await.unwrap_err());
+        server.abort();
+
+        assert!(matches!(err, AudioError::Timeout(_)), "{:?}", err);
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+        assert_eq!(err.error_response().status(), actix_web::http::StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[test]
+    fn hd_audio_is_opt_in() {
+        assert!(!request(json!({})).hd_audio);
+        assert!(request(json!({ "hd_audio": true })).hd_audio);
+    }
+
+    #[test]
+    fn temperature_defaults_and_is_range_checked() {
+        assert_eq!(resolve_temperature(None).unwrap(), DEFAULT_CHAT_TEMPERATURE);
+        assert_eq!(resolve_temperature(Some(0.0)).unwrap(), 0.0);
+        assert_eq!(resolve_temperature(Some(2.0)).unwrap(), 2.0);
+        for out_of_range in [-0.1, 2.1, f32::NAN] {
+            let err = resolve_temperature(Some(out_of_range)).unwrap_err();
+            assert!(matches!(err, AudioError::InvalidRequest(_)), "{}", out_of_range);
+        }
+
+        assert_eq!(chat_options(&request(json!({ "temperature": 1.3 }))).unwrap().temperature, 1.3);
+        assert!(chat_options(&request(json!({ "temperature": 3.0 }))).is_err());
+    }
+
+    #[test]
+    fn genz_layers_on_any_tone_but_tones_are_exclusive() {
+        for body in [
+            json!({ "sarcastic_mode": true, "genz_mode": true }),
+            json!({ "tone": "seductive", "genz_mode": true }),
+            json!({ "tone": "shenanigan", "shenanigan_mode": true }),
+            json!({ "genz_mode": true }),
+        ] {
+            assert!(check_tones(&request(body.clone())).is_ok(), "{}", body);
+        }
+
+        let err = check_tones(&request(json!({ "sarcastic_mode": true, "seductive_mode": true }))).unwrap_err();
+        assert!(err.to_string().contains("sarcastic and seductive modes can't be combined"), "{}", err);
+        assert!(check_tones(&request(json!({ "tone": "calm", "shenanigan_mode": true }))).is_err());
+
+        // Gen Z style is added to the tone's instructions rather than replacing them
+        let sarcastic = get_language_instructions(Language::En, false, Tone::Sarcastic);
+        let sarcastic_genz = get_language_instructions(Language::En, true, Tone::Sarcastic);
+        assert_ne!(sarcastic, sarcastic_genz);
+        assert_ne!(sarcastic_genz, get_language_instructions(Language::En, true, Tone::Calm));
+    }
+
+    #[test]
+    fn responses_echo_the_applied_tone_and_language() {
+        let response = overload_fallback_response(&request(json!({ "language": "hi", "sarcastic_mode": true })));
+        let body = serde_json::to_value(&response).unwrap();
+        assert_eq!(body["applied_tone"], "sarcastic");
+        assert_eq!(body["language"], "hi");
+
+        // No tone asked for means calm; no language (left to detection) falls back to English
+        let body = serde_json::to_value(overload_fallback_response(&request(json!({ "genz_mode": true })))).unwrap();
+        assert_eq!(body["applied_tone"], "calm");
+        assert_eq!(body["language"], "en");
+    }
+
+    #[test]
+    fn spanish_french_and_german_are_covered_in_every_mode() {
+        for (code, name, example) in [
+            ("es", "Spanish", "No estás solo"),
+            ("fr", "French", "Tu n'es pas seul"),
+            ("de", "German", "Du bist nicht allein"),
+        ] {
+            let language = Language::from_code(code).unwrap();
+            assert_eq!(language.whisper_code(), code);
+            assert!(tts::OPENAI_VOICES.contains(&language.tts_voice()), "{}", code);
+            assert!(!voice_sample_text(language).is_empty());
+
+            for tone in Tone::ALL {
+                for genz in [false, true] {
+                    let instructions = get_language_instructions(language, genz, tone);
+                    assert!(instructions.contains(example), "{} {}", code, tone.name());
+                    // The language section, the tone section and the genz section each name the language
+                    let expected = if genz { 4 } else { 3 };
+                    assert_eq!(instructions.matches(name).count(), expected, "{} {}", code, tone.name());
+                }
+            }
+        }
+    }
+
+    fn transcription(detected_language: Option<&str>) -> Transcription {
+        Transcription {
+            text: "hello".to_string(),
+            segments: Vec::new(),
+            confidence: None,
+            detected_language: detected_language.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn detected_language_falls_back_to_english() {
+        assert_eq!(detected_reply_language(&transcription(Some("hindi"))), Language::Hi);
+        assert_eq!(detected_reply_language(&transcription(Some("French"))), Language::Fr);
+        assert_eq!(detected_reply_language(&transcription(Some("japanese"))), Language::En);
+        assert_eq!(detected_reply_language(&transcription(None)), Language::En);
+    }
+
+    #[test]
+    fn language_is_left_to_detection_when_nothing_names_one() {
+        let mut req = request(json!({}));
+        apply_default_language(&mut req, &TestRequest::default().to_http_request());
+        assert_eq!(req.language, None);
+        assert_eq!(req.language_code(), "auto");
+
+        let mut req = request(json!({}));
+        let http_req = TestRequest::default().insert_header(("Accept-Language", "fr-CA, en;q=0.5")).to_http_request();
+        apply_default_language(&mut req, &http_req);
+        assert_eq!(req.language, Some(Language::Fr));
+    }
+
+    #[test]
+    fn websocket_handshake_settings_are_validated() {
+        let _ = tts::init_tts_provider(&Client::new());
+        let http_req = TestRequest::default().to_http_request();
+
+        let (req, _chat, input) =
+            parse_ws_handshake(r#"{"language":"hi","tone":"sarcastic","input_format":"pcm16"}"#, &http_req).unwrap();
+        assert_eq!(req.language, Some(Language::Hi));
+        assert_eq!(req.tone(), Tone::Sarcastic);
+        assert_eq!(input, WsInput::Pcm16);
+
+        for (handshake, expected) in [
+            ("[]", "handshake must be a JSON object"),
+            ("not json", "handshake must be a JSON object"),
+            (r#"{"genzz_mode":true}"#, "unknown field `genzz_mode`"),
+            (r#"{"sarcastic_mode":true,"seductive_mode":true}"#, "can't be combined"),
+        ] {
+            let err = parse_ws_handshake(handshake, &http_req).err().unwrap();
+            assert!(err.to_string().contains(expected), "{}: {}", handshake, err);
+        }
+        assert!(parse_ws_handshake(r#"{"voice":"nobody"}"#, &http_req).is_err());
+    }
+
+    #[actix_web::test]
+    async fn websocket_route_upgrades_the_connection() {
+        let _flag = shutdown_flag().await;
+        let app = actix_web::test::init_service(App::new().service(ws_session)).await;
+
+        let plain = actix_web::test::call_service(&app, TestRequest::get().uri("/ws").to_request()).await;
+        assert_eq!(plain.status(), actix_web::http::StatusCode::BAD_REQUEST);
+
+        let upgrade = TestRequest::get()
+            .uri("/ws")
+            .insert_header(("Upgrade", "websocket"))
+            .insert_header(("Connection", "Upgrade"))
+            .insert_header(("Sec-WebSocket-Version", "13"))
+            .insert_header(("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="))
+            .to_request();
+        let resp = actix_web::test::call_service(&app, upgrade).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::SWITCHING_PROTOCOLS);
+    }
+
+    /// A `multipart/form-data` body and its Content-Type; the `audio` part is sent as a file.
+    fn multipart_body(parts: &[(&str, &str)]) -> (String, Vec<u8>) {
+        const BOUNDARY: &str = "hearthly-test-boundary";
+        let mut body = Vec::new();
+        for (name, content) in parts {
+            let filename = if *name == "audio" { "; filename=\"clip.webm\"" } else { "" };
+            body.extend(format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"{}\r\n\r\n", BOUNDARY, name, filename).bytes());
+            body.extend(content.bytes());
+            body.extend(b"\r\n");
+        }
+        body.extend(format!("--{}--\r\n", BOUNDARY).bytes());
+        (format!("multipart/form-data; boundary={}", BOUNDARY), body)
+    }
+
+    async fn post_multipart(parts: &[(&str, &str)]) -> (actix_web::http::StatusCode, serde_json::Value) {
+        let app = actix_web::test::init_service(
+            App::new()
+                .service(process_audio_multipart),
+        )
+        .await;
+        let (content_type, body) = multipart_body(parts);
+        let req = TestRequest::post()
+            .uri("/process-audio-multipart")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = actix_web::test::call_service(&app, req).await;
+        (resp.status(), actix_web::test::read_body_json(resp).await)
+    }
+
+    #[actix_web::test]
+    async fn multipart_uploads_are_validated_part_by_part() {
+        let _flag = shutdown_flag().await;
+        let cases: [(&[(&str, &str)], &str); 7] = [
+            (&[("language", "en")], "missing 'audio' part"),
+            (&[("audio", "OggS"), ("extra", "x")], "unexpected multipart field 'extra'"),
+            (&[("audio", "OggS"), ("audio", "OggS")], "more than one 'audio' part"),
+            (&[("genz_mode", "maybe"), ("audio", "OggS")], "invalid genz_mode 'maybe'"),
+            (&[("metadata", "[1, 2]"), ("audio", "OggS")], "metadata must be a JSON object"),
+            (&[("audio", "OggS"), ("no_disk", "true")], "'no_disk' must come before the 'audio' part"),
+            (&[("audio", "OggS"), ("metadata", r#"{"no_disk": true}"#)], "'no_disk' must come before"),
+        ];
+        for (parts, expected) in cases {
+            let (status, body) = post_multipart(parts).await;
+            assert_eq!(status, actix_web::http::StatusCode::BAD_REQUEST, "{}", expected);
+            let message = body["error"]["message"].as_str().unwrap();
+            assert!(message.contains(expected), "{}", message);
+        }
+    }
+
+    #[actix_web::test]
+    async fn multipart_metadata_and_form_fields_reach_the_audio_check() {
+        let _flag = shutdown_flag().await;
+        // Valid settings get as far as sniffing the uploaded file
+        let (status, body) = post_multipart(&[
+            ("metadata", r#"{"tone": "sarcastic"}"#),
+            ("language", "fr"),
+            ("genz_mode", "on"),
+            ("audio", "RIFF\x24\x00\x00\x00WAVEfmt "),
+        ])
+        .await;
+        assert_eq!(status, actix_web::http::StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["code"], "invalid_audio");
+
+        // Buffered in memory, the upload still gets the same checks
+        let (status, body) = post_multipart(&[("no_disk", "true"), ("audio", "RIFF\x24\x00\x00\x00WAVEfmt ")]).await;
+        assert_eq!(status, actix_web::http::StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["code"], "invalid_audio");
+
+        let (_, body) = post_multipart(&[("metadata", r#"{"genzz_mode": true}"#), ("audio", "OggS")]).await;
+        assert!(body["error"]["message"].as_str().unwrap().contains("unknown field `genzz_mode`"));
+    }
+
+    #[tokio::test]
+    async fn oversized_audio_is_rejected_before_conversion() {
+        // Zeros aren't a container either, so a 413 shows the size check came first
+        let audio = vec![0u8; max_audio_bytes() + 1];
+        let err = convert_audio_bytes_to_pcm16_24khz(&audio).await.err().unwrap();
+        assert!(matches!(err, AudioError::PayloadTooLarge(_)), "{}", err);
+        assert_eq!(err.status_code(), actix_web::http::StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn only_webm_and_ogg_containers_are_accepted() {
+        assert!(check_audio_container(&[0x1a, 0x45, 0xdf, 0xa3, 0x9f]).is_ok());
+        assert!(check_audio_container(b"OggS\x00\x02").is_ok());
+
+        let err = check_audio_container(&[]).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid audio: audio is empty");
+        for not_audio in [&b"RIFF\x24\x00\x00\x00WAVE"[..], b"ID3\x04", b"{\"hello\": 1}", b"\x00\x00\x00\x00"] {
+            let err = check_audio_container(not_audio).unwrap_err();
+            assert!(matches!(err, AudioError::InvalidAudio(_)), "{:02x?}", not_audio);
+        }
+    }
+
+    #[actix_web::test]
+    async fn empty_and_non_audio_input_is_rejected_up_front() {
+        let _flag = shutdown_flag().await;
+        let not_audio = general_purpose::STANDARD.encode("just some text");
+        for (audio, message) in [("", "audio is empty"), (not_audio.as_str(), "not a WebM or Ogg recording")] {
+            let (status, body) = post_process_audio(json!({ "audio": audio, "language": "en" })).await;
+            assert_eq!(status, actix_web::http::StatusCode::BAD_REQUEST);
+            assert_eq!(body["error"]["code"], "invalid_audio");
+            assert!(body["error"]["message"].as_str().unwrap().contains(message), "{}", body);
+        }
+    }
+
+    #[test]
+    fn shutting_down_response_closes_connection() {
+        let response = shutting_down_response();
+        assert_eq!(response.status(), actix_web::http::StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get("Connection").unwrap(), "close");
+    }
+
+    #[test]
+    fn unavailable_sets_retry_after() {
+        let response = AudioError::Unavailable { retry_after: std::time::Duration::from_secs(30) }.error_response();
+        assert_eq!(response.headers().get("Retry-After").unwrap(), "30");
+    }
+
+    #[test]
+    fn converted_audio_requires_debug_audio() {
+        std::env::remove_var("DEBUG_AUDIO");
+        let req: AudioRequest =
+            serde_json::from_value(json!({ "audio": "", "include_converted_audio": true })).unwrap();
+        let err = check_converted_audio_allowed(&req).unwrap_err();
+        assert_eq!(err.as_response_error().status_code(), actix_web::http::StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn websocket_upgrades_may_pass_the_key_as_a_query_token() {
+        let upgrade = |uri: &str| TestRequest::get().uri(uri).insert_header(("Upgrade", "websocket")).to_srv_request();
+        assert!(check_api_key(&upgrade("/ws?token=alpha"), &keys()).is_ok());
+        assert!(check_api_key(&upgrade("/ws?token=gamma"), &keys()).is_err());
+        assert!(check_api_key(&upgrade("/ws"), &keys()).is_err());
+
+        // Keys in URLs end up in logs, so only upgrades may use them
+        let req = TestRequest::post().uri("/process-audio?token=alpha").to_srv_request();
+        assert!(check_api_key(&req, &keys()).is_err());
+    }
+
+    fn static_keys() -> &'static [String] {
+        static KEYS: OnceLock<Vec<String>> = OnceLock::new();
+        KEYS.get_or_init(keys)
+    }
+
+    #[actix_web::test]
+    async fn api_key_is_required_on_paid_routes_only() {
+        let _flag = shutdown_flag().await;
+        let app = actix_web::test::init_service(
+            App::new()
+                .configure(|cfg| configure_routes(cfg, static_keys())),
+        )
+        .await;
+        let unauthorized = actix_web::http::StatusCode::UNAUTHORIZED;
+
+        let health = actix_web::test::call_service(&app, TestRequest::get().uri("/health").to_request()).await;
+        assert_eq!(health.status(), actix_web::http::StatusCode::OK);
+        for (method, uri) in [
+            ("POST", "/process-audio"),
+            ("POST", "/process-audio/stream"),
+            ("DELETE", "/sessions/abc"),
+            ("GET", "/voices/nova/sample"),
+            ("GET", "/ws"),
+            // The router decodes these, so the key check must too
+            ("POST", "/%70rocess-audio"),
+            ("DELETE", "/session%73/abc"),
+            ("GET", "/voices/nova/%73ample"),
+        ] {
+            let req = TestRequest::default()
+                .method(method.parse().unwrap())
+                .uri(uri)
+                .to_request();
+            let resp = actix_web::test::call_service(&app, req).await;
+            assert_eq!(resp.status(), unauthorized, "{} {}", method, uri);
+        }
+
+        // With a key, the request reaches the handler, which rejects the empty body instead
+        let req = TestRequest::post()
+            .uri("/%70rocess-audio")
+            .insert_header((header::AUTHORIZATION, "Bearer alpha"))
+            .to_request();
+        assert_ne!(actix_web::test::call_service(&app, req).await.status(), unauthorized);
+    }
+}
+
+/// Needs ffmpeg on `PATH`: `cargo test --features ffmpeg-tests`.
+#[cfg(all(test, feature = "ffmpeg-tests"))]
+mod ffmpeg_tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn ffmpeg_runs_without_blocking_the_runtime() {
+        let ticks = std::cell::Cell::new(0);
+        let ticker = async {
+            loop {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                ticks.set(ticks.get() + 1);
+            }
+        };
+        // -re makes ffmpeg take the clip's real duration, about half a second
+        let args = ["-re", "-f", "lavfi", "-i", "anullsrc=r=24000:cl=mono", "-t", "0.5", "-f", "wav", "pipe:1"];
+        let output = tokio::select! {
+            output = spawn_ffmpeg(&args, None) => output.unwrap(),
+            _ = ticker => unreachable!(),
+        };
+
+        assert!(output.status.success());
+        assert!(ticks.get() >= 10, "runtime only ticked {} times", ticks.get());
+    }
+
+    #[tokio::test]
+    async fn ffmpeg_input_larger_than_a_pipe_buffer_does_not_deadlock() {
+        let wav = tts::pcm16_to_wav(&vec![0; 4_800_000]).unwrap();
+        let args = ["-f", "wav", "-i", "pipe:0", "-f", "wav", "pipe:1"];
+        let output = tokio::time::timeout(std::time::Duration::from_secs(30), spawn_ffmpeg(&args, Some(&wav)))
+            .await
+            .expect("ffmpeg deadlocked")
+            .unwrap();
+
+        assert!(output.status.success());
+        assert!((wav_duration_secs(&output.stdout) - 100.0).abs() < 0.1);
+    }
+
+    /// `secs` of a tone as FLAC in Ogg, which the input check accepts.
+    async fn ogg_clip(secs: f32) -> Vec<u8> {
+        let duration = secs.to_string();
+        let args = [
+            "-f", "lavfi", "-i", "sine=frequency=440:sample_rate=24000", "-t", &duration, "-c:a", "flac", "-f", "ogg",
+            "pipe:1",
+        ];
+        let output = spawn_ffmpeg(&args, None).await.unwrap();
+        assert!(output.status.success());
+        output.stdout
+    }
+
+    #[tokio::test]
+    async fn clips_longer_than_the_duration_limit_are_rejected() {
+        std::env::set_var("MAX_AUDIO_DURATION_SECS", "2");
+        let converted = convert_audio_bytes_to_pcm16_24khz(&ogg_clip(1.0).await).await.unwrap();
+        assert!((wav_duration_secs(&converted.wav) - 1.0).abs() < 0.05);
+
+        let err = convert_audio_bytes_to_pcm16_24khz(&ogg_clip(3.0).await).await.err().unwrap();
+        assert!(matches!(err, AudioError::PayloadTooLarge(_)), "{}", err);
+        assert!(err.to_string().contains("limit is 2s"), "{}", err);
+        std::env::remove_var("MAX_AUDIO_DURATION_SECS");
+    }
+}
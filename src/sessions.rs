@@ -0,0 +1,1264 @@
+use futures_util::future::BoxFuture;
+use log::{debug, error, info};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{env_flag, Tone};
+
+/// Session backends selectable with `SESSION_STORE`.
+pub const SESSION_STORES: [&str; 2] = ["memory", "redis"];
+
+/// What `SESSION_CONCURRENCY` can do with a turn that arrives while another
+/// for the same session is in flight: wait for it, or refuse.
+pub const CONCURRENCY_POLICIES: [&str; 2] = ["serialize", "reject"];
+
+/// What `SESSION_FULL_POLICY` can do once `SESSION_MAX_ACTIVE` is reached.
+pub const FULL_POLICIES: [&str; 2] = ["evict", "reject"];
+
+/// Conversation history per client-chosen `session_id`.
+///
+/// Stores keep only the latest `SESSION_MAX_TURNS` (default 10) exchanges
+/// and forget sessions idle for longer than `SESSION_TTL_SECS` (default 1800);
+/// `spawn_sweeper` makes sure that happens even when no requests come in.
+///
+/// `SESSION_MAX_TURNS_BY_MODE` (`mode=turns,...`, e.g. `sarcastic=3`)
+/// shortens or lengthens the replayed history per mode. Stores keep as many
+/// turns as the deepest mode needs and each turn replays its own mode's
+/// window, so switching to a shallower mode mid-session hides the older
+/// turns rather than dropping them: switch back and they are replayed again,
+/// as long as the session hasn't outgrown the stored depth since.
+///
+/// With `SESSION_SUMMARIES` on, sessions are summarized as they are evicted
+/// and a returning session id gets the summary instead of a cold start; see
+/// `spawn_sweeper`.
+pub trait SessionStore: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Up to the latest `max_turns` earlier turns as chat messages, oldest
+    /// first; empty for a new or expired session.
+    fn get<'a>(&'a self, id: &'a str, max_turns: usize) -> BoxFuture<'a, Result<Vec<Value>, String>>;
+
+    /// Records one exchange, dropping the oldest once over the turn cap.
+    fn append<'a>(&'a self, id: &'a str, user: &'a str, assistant: &'a str) -> BoxFuture<'a, Result<(), String>>;
+
+    /// Starts or resumes a session, returning false when it would be a new
+    /// one and the store is full and set to reject. Known sessions are
+    /// always let in; when the store is full and set to evict, the least
+    /// recently used session is dropped to make room.
+    fn admit<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<bool, String>>;
+
+    /// Counts a request made in the session and returns how many it has
+    /// made. Unlike the history, the count survives `expire`, so deleting a
+    /// session doesn't reset it; it goes once the session idles out.
+    fn record_request<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<u64, String>>;
+
+    /// Adds to the characters sent to TTS for the session and returns its
+    /// running total.
+    fn add_tts_characters<'a>(&'a self, id: &'a str, characters: u64) -> BoxFuture<'a, Result<u64, String>>;
+
+    /// Forgets the session straight away, summary included.
+    fn expire<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<(), String>>;
+
+    /// Sessions used within the TTL.
+    fn active_sessions(&self) -> BoxFuture<'_, Result<usize, String>>;
+
+    /// Drops sessions idle past the TTL. With summaries on, also hands back
+    /// the history of every session evicted since the last sweep; stores
+    /// that expire sessions by themselves hand back those due to go within
+    /// `lead` instead, while their history can still be read.
+    fn sweep(&self, lead: Duration) -> BoxFuture<'_, Result<Swept, String>>;
+
+    /// Keeps a summary of an evicted session for `SESSION_SUMMARY_TTL_SECS`.
+    fn save_summary<'a>(&'a self, id: &'a str, summary: &'a str) -> BoxFuture<'a, Result<(), String>>;
+
+    /// The summary kept for a session, if any.
+    fn summary<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Option<String>, String>>;
+}
+
+/// What a sweep evicted.
+#[derive(Default)]
+pub struct Swept {
+    pub count: usize,
+    /// Session id and history of each evicted session to summarize.
+    pub histories: Vec<(String, Vec<Value>)>,
+}
+
+/// Whether evicted sessions are summarized (`SESSION_SUMMARIES`, off by
+/// default, since it keeps a digest of the conversation past the TTL).
+pub fn summaries_enabled() -> bool {
+    env_flag("SESSION_SUMMARIES")
+}
+
+/// How long summaries are kept, `SESSION_SUMMARY_TTL_SECS` (default 30 days).
+fn summary_ttl() -> Duration {
+    let secs = std::env::var("SESSION_SUMMARY_TTL_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(30 * 24 * 3600);
+    Duration::from_secs(secs)
+}
+
+/// Histories of sessions evicted between sweeps, kept only while summaries
+/// are on.
+struct Evicted {
+    keep: bool,
+    histories: Mutex<Vec<(String, Vec<Value>)>>,
+}
+
+impl Evicted {
+    fn new(keep: bool) -> Self {
+        Evicted {
+            keep,
+            histories: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn push(&self, id: &str, messages: Vec<Value>) {
+        if self.keep && !messages.is_empty() {
+            self.histories.lock().unwrap().push((id.to_string(), messages));
+        }
+    }
+
+    fn drain(&self) -> Vec<(String, Vec<Value>)> {
+        std::mem::take(&mut *self.histories.lock().unwrap())
+    }
+}
+
+fn max_turns() -> usize {
+    std::env::var("SESSION_MAX_TURNS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(10)
+}
+
+/// Parses `SESSION_MAX_TURNS_BY_MODE` into per-mode history depths.
+fn max_turns_by_mode() -> Result<HashMap<Tone, usize>, String> {
+    let raw = std::env::var("SESSION_MAX_TURNS_BY_MODE").unwrap_or_default();
+    let mut table = HashMap::new();
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (mode, turns) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("expected mode=turns, got '{}'", entry))?;
+        let tone = Tone::parse(mode.trim()).ok_or_else(|| format!("unknown mode '{}'", mode.trim()))?;
+        let turns = turns
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid turn count '{}' for '{}'", turns.trim(), tone.name()))?;
+        table.insert(tone, turns);
+    }
+    Ok(table)
+}
+
+/// Turns of history replayed for a request in `tone`.
+pub fn history_turns(tone: Tone) -> usize {
+    max_turns_by_mode()
+        .ok()
+        .and_then(|table| table.get(&tone).copied())
+        .unwrap_or_else(max_turns)
+}
+
+/// Turns kept per session: enough for the deepest mode.
+fn stored_turns() -> usize {
+    let by_mode = max_turns_by_mode().unwrap_or_default();
+    by_mode.into_values().fold(max_turns(), usize::max)
+}
+
+fn ttl() -> Duration {
+    let secs = std::env::var("SESSION_TTL_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1800);
+    Duration::from_secs(secs)
+}
+
+/// Cap on simultaneous sessions: `SESSION_MAX_ACTIVE` (0, the default, for
+/// none), and whether reaching it evicts the least recently used session
+/// (`SESSION_FULL_POLICY=evict`, the default) or rejects new ones.
+#[derive(Clone, Copy)]
+struct Capacity {
+    max_sessions: usize,
+    evict: bool,
+}
+
+impl Capacity {
+    const UNLIMITED: Capacity = Capacity {
+        max_sessions: 0,
+        evict: true,
+    };
+
+    fn from_env() -> Self {
+        Capacity {
+            max_sessions: std::env::var("SESSION_MAX_ACTIVE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0),
+            evict: std::env::var("SESSION_FULL_POLICY").as_deref() != Ok("reject"),
+        }
+    }
+
+    fn full(&self, active: usize) -> bool {
+        self.max_sessions > 0 && active >= self.max_sessions
+    }
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn exchange(user: &str, assistant: &str) -> [Value; 2] {
+    [
+        json!({"role": "user", "content": user}),
+        json!({"role": "assistant", "content": assistant}),
+    ]
+}
+
+struct Session {
+    /// Alternating user and assistant chat messages, oldest first.
+    messages: Vec<Value>,
+    tts_characters: u64,
+    requests: u64,
+    last_used: Instant,
+}
+
+/// Keeps sessions in process memory, so they are lost on restart and not
+/// shared between replicas. Idle sessions are dropped the next time the
+/// store is touched.
+pub struct MemoryStore {
+    max_turns: usize,
+    ttl: Duration,
+    capacity: Capacity,
+    sessions: Mutex<HashMap<String, Session>>,
+    evicted: Evicted,
+    summary_ttl: Duration,
+    /// Summary and when it was saved, per session id.
+    summaries: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl MemoryStore {
+    fn new() -> Self {
+        Self::with_limits(stored_turns(), ttl())
+            .with_capacity(Capacity::from_env())
+            .with_summaries(summaries_enabled(), summary_ttl())
+    }
+
+    fn with_capacity(self, capacity: Capacity) -> Self {
+        MemoryStore { capacity, ..self }
+    }
+
+    fn with_summaries(self, enabled: bool, summary_ttl: Duration) -> Self {
+        MemoryStore {
+            evicted: Evicted::new(enabled),
+            summary_ttl,
+            ..self
+        }
+    }
+
+    fn with_limits(max_turns: usize, ttl: Duration) -> Self {
+        MemoryStore {
+            max_turns,
+            ttl,
+            capacity: Capacity::UNLIMITED,
+            sessions: Mutex::new(HashMap::new()),
+            evicted: Evicted::new(false),
+            summary_ttl: summary_ttl(),
+            summaries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn touch<'s>(&self, sessions: &'s mut HashMap<String, Session>, id: &str) -> &'s mut Session {
+        let session = sessions.entry(id.to_string()).or_insert_with(|| Session {
+            messages: Vec::new(),
+            tts_characters: 0,
+            requests: 0,
+            last_used: Instant::now(),
+        });
+        session.last_used = Instant::now();
+        session
+    }
+
+    fn evict(&self, sessions: &mut HashMap<String, Session>, id: &str) {
+        if let Some(session) = sessions.remove(id) {
+            self.evicted.push(id, session.messages);
+        }
+    }
+
+    fn evict_idle(&self, sessions: &mut HashMap<String, Session>) -> usize {
+        let idle: Vec<String> = sessions
+            .iter()
+            .filter(|(_, session)| session.last_used.elapsed() >= self.ttl)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in &idle {
+            self.evict(sessions, id);
+        }
+        if !idle.is_empty() {
+            debug!("Evicted {} idle sessions", idle.len());
+        }
+        idle.len()
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn get<'a>(&'a self, id: &'a str, max_turns: usize) -> BoxFuture<'a, Result<Vec<Value>, String>> {
+        Box::pin(async move {
+            let mut sessions = self.sessions.lock().unwrap();
+            self.evict_idle(&mut sessions);
+            Ok(sessions
+                .get(id)
+                .map(|session| {
+                    let skip = session.messages.len().saturating_sub(max_turns * 2);
+                    session.messages[skip..].to_vec()
+                })
+                .unwrap_or_default())
+        })
+    }
+
+    fn append<'a>(&'a self, id: &'a str, user: &'a str, assistant: &'a str) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let mut sessions = self.sessions.lock().unwrap();
+            self.evict_idle(&mut sessions);
+            let session = self.touch(&mut sessions, id);
+            session.messages.extend(exchange(user, assistant));
+            let excess = session.messages.len().saturating_sub(self.max_turns * 2);
+            session.messages.drain(..excess);
+            Ok(())
+        })
+    }
+
+    fn admit<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<bool, String>> {
+        Box::pin(async move {
+            let mut sessions = self.sessions.lock().unwrap();
+            self.evict_idle(&mut sessions);
+            if self.capacity.full(sessions.len()) && !sessions.contains_key(id) {
+                if !self.capacity.evict {
+                    return Ok(false);
+                }
+                let oldest = sessions
+                    .iter()
+                    .min_by_key(|(_, session)| session.last_used)
+                    .map(|(oldest, _)| oldest.clone());
+                if let Some(oldest) = oldest {
+                    debug!("Evicting the least recently used session to make room");
+                    self.evict(&mut sessions, &oldest);
+                }
+            }
+            self.touch(&mut sessions, id);
+            Ok(true)
+        })
+    }
+
+    fn record_request<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<u64, String>> {
+        Box::pin(async move {
+            let mut sessions = self.sessions.lock().unwrap();
+            self.evict_idle(&mut sessions);
+            let session = self.touch(&mut sessions, id);
+            session.requests += 1;
+            Ok(session.requests)
+        })
+    }
+
+    fn add_tts_characters<'a>(&'a self, id: &'a str, characters: u64) -> BoxFuture<'a, Result<u64, String>> {
+        Box::pin(async move {
+            let mut sessions = self.sessions.lock().unwrap();
+            self.evict_idle(&mut sessions);
+            let session = self.touch(&mut sessions, id);
+            session.tts_characters += characters;
+            Ok(session.tts_characters)
+        })
+    }
+
+    fn expire<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            self.summaries.lock().unwrap().remove(id);
+            let mut sessions = self.sessions.lock().unwrap();
+            match sessions.get_mut(id) {
+                Some(session) if session.requests > 0 => {
+                    session.messages.clear();
+                    session.tts_characters = 0;
+                }
+                _ => {
+                    sessions.remove(id);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn active_sessions(&self) -> BoxFuture<'_, Result<usize, String>> {
+        Box::pin(async move {
+            let mut sessions = self.sessions.lock().unwrap();
+            self.evict_idle(&mut sessions);
+            Ok(sessions.len())
+        })
+    }
+
+    fn sweep(&self, _lead: Duration) -> BoxFuture<'_, Result<Swept, String>> {
+        Box::pin(async move {
+            let count = self.evict_idle(&mut self.sessions.lock().unwrap());
+            let summary_ttl = self.summary_ttl;
+            self.summaries.lock().unwrap().retain(|_, (_, saved)| saved.elapsed() < summary_ttl);
+            Ok(Swept {
+                count,
+                histories: self.evicted.drain(),
+            })
+        })
+    }
+
+    fn save_summary<'a>(&'a self, id: &'a str, summary: &'a str) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let saved = (summary.to_string(), Instant::now());
+            self.summaries.lock().unwrap().insert(id.to_string(), saved);
+            Ok(())
+        })
+    }
+
+    fn summary<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Option<String>, String>> {
+        Box::pin(async move {
+            let summaries = self.summaries.lock().unwrap();
+            Ok(summaries
+                .get(id)
+                .filter(|(_, saved)| saved.elapsed() < self.summary_ttl)
+                .map(|(summary, _)| summary.clone()))
+        })
+    }
+}
+
+/// Keeps each session as a Redis list of JSON chat messages under
+/// `hearthly:session:<id>`, expiring with the session TTL, so history
+/// survives restarts and is shared between replicas. The sorted set
+/// `hearthly:sessions` scores each session by when it was last used, for
+/// counting the active ones and finding the least recently used. Replicas
+/// check the session cap without coordinating, so it can overshoot by a
+/// session or two under a burst.
+///
+/// Summaries live under `hearthly:session:<id>:summary`, so with this store
+/// they outlast restarts.
+pub struct RedisStore {
+    client: redis::Client,
+    connection: tokio::sync::OnceCell<redis::aio::MultiplexedConnection>,
+    max_turns: usize,
+    ttl: Duration,
+    capacity: Capacity,
+    evicted: Evicted,
+    summary_ttl: Duration,
+}
+
+impl RedisStore {
+    fn new(url: &str) -> Result<Self, String> {
+        Ok(Self::with_limits(url, stored_turns(), ttl())?
+            .with_capacity(Capacity::from_env())
+            .with_summaries(summaries_enabled(), summary_ttl()))
+    }
+
+    fn with_capacity(self, capacity: Capacity) -> Self {
+        RedisStore { capacity, ..self }
+    }
+
+    fn with_summaries(self, enabled: bool, summary_ttl: Duration) -> Self {
+        RedisStore {
+            evicted: Evicted::new(enabled),
+            summary_ttl,
+            ..self
+        }
+    }
+
+    fn with_limits(url: &str, max_turns: usize, ttl: Duration) -> Result<Self, String> {
+        let client = redis::Client::open(url).map_err(|e| format!("invalid REDIS_URL: {}", e))?;
+        Ok(RedisStore {
+            client,
+            connection: tokio::sync::OnceCell::new(),
+            max_turns,
+            ttl,
+            capacity: Capacity::UNLIMITED,
+            evicted: Evicted::new(false),
+            summary_ttl: summary_ttl(),
+        })
+    }
+
+    async fn connection(&self) -> Result<redis::aio::MultiplexedConnection, String> {
+        self.connection
+            .get_or_try_init(|| self.client.get_multiplexed_tokio_connection())
+            .await
+            .cloned()
+            .map_err(|e| format!("Redis connection failed: {}", e))
+    }
+
+    const ACTIVE_KEY: &'static str = "hearthly:sessions";
+
+    fn key(id: &str) -> String {
+        format!("hearthly:session:{}", id)
+    }
+
+    /// Last-used score below which a session has idled out.
+    fn idle_cutoff(&self) -> u64 {
+        unix_secs().saturating_sub(self.ttl.as_secs())
+    }
+
+    fn usage_key(id: &str) -> String {
+        format!("hearthly:session:{}:tts_characters", id)
+    }
+
+    fn requests_key(id: &str) -> String {
+        format!("hearthly:session:{}:requests", id)
+    }
+
+    fn summary_key(id: &str) -> String {
+        format!("hearthly:session:{}:summary", id)
+    }
+}
+
+impl SessionStore for RedisStore {
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+
+    fn get<'a>(&'a self, id: &'a str, max_turns: usize) -> BoxFuture<'a, Result<Vec<Value>, String>> {
+        Box::pin(async move {
+            // LRANGE -0 -1 would be the whole list
+            if max_turns == 0 {
+                return Ok(Vec::new());
+            }
+            let mut connection = self.connection().await?;
+            let raw: Vec<String> = redis::cmd("LRANGE")
+                .arg(Self::key(id))
+                .arg(-((max_turns * 2) as isize))
+                .arg(-1)
+                .query_async(&mut connection)
+                .await
+                .map_err(|e| format!("Redis LRANGE failed: {}", e))?;
+            raw.iter()
+                .map(|message| serde_json::from_str(message).map_err(|e| format!("corrupt session message: {}", e)))
+                .collect()
+        })
+    }
+
+    fn append<'a>(&'a self, id: &'a str, user: &'a str, assistant: &'a str) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let mut connection = self.connection().await?;
+            let key = Self::key(id);
+            let messages = exchange(user, assistant).map(|message| message.to_string());
+            let keep = (self.max_turns * 2) as isize;
+            redis::pipe()
+                .atomic()
+                .rpush(&key, &messages[..])
+                .ignore()
+                .ltrim(&key, -keep, -1)
+                .ignore()
+                .expire(&key, self.ttl.as_secs() as usize)
+                .ignore()
+                .zadd(Self::ACTIVE_KEY, id, unix_secs())
+                .ignore()
+                .query_async::<_, ()>(&mut connection)
+                .await
+                .map_err(|e| format!("Redis append failed: {}", e))
+        })
+    }
+
+    fn admit<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<bool, String>> {
+        Box::pin(async move {
+            let mut connection = self.connection().await?;
+            let (last_used, active): (Option<f64>, usize) = redis::pipe()
+                .atomic()
+                .zrembyscore(Self::ACTIVE_KEY, "-inf", self.idle_cutoff())
+                .ignore()
+                .zscore(Self::ACTIVE_KEY, id)
+                .zcard(Self::ACTIVE_KEY)
+                .query_async(&mut connection)
+                .await
+                .map_err(|e| format!("Redis ZSCORE failed: {}", e))?;
+            if last_used.is_none() && self.capacity.full(active) {
+                if !self.capacity.evict {
+                    return Ok(false);
+                }
+                let evicted: Vec<(String, f64)> = redis::cmd("ZPOPMIN")
+                    .arg(Self::ACTIVE_KEY)
+                    .query_async(&mut connection)
+                    .await
+                    .map_err(|e| format!("Redis ZPOPMIN failed: {}", e))?;
+                for (oldest, _) in evicted {
+                    debug!("Evicting the least recently used session to make room");
+                    if self.evicted.keep {
+                        self.evicted.push(&oldest, self.get(&oldest, self.max_turns).await?);
+                    }
+                    redis::cmd("DEL")
+                        .arg(Self::key(&oldest))
+                        .arg(Self::usage_key(&oldest))
+                        .query_async::<_, ()>(&mut connection)
+                        .await
+                        .map_err(|e| format!("Redis DEL failed: {}", e))?;
+                }
+            }
+            redis::cmd("ZADD")
+                .arg(Self::ACTIVE_KEY)
+                .arg(unix_secs())
+                .arg(id)
+                .query_async::<_, ()>(&mut connection)
+                .await
+                .map_err(|e| format!("Redis ZADD failed: {}", e))?;
+            Ok(true)
+        })
+    }
+
+    fn record_request<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<u64, String>> {
+        Box::pin(async move {
+            let mut connection = self.connection().await?;
+            let key = Self::requests_key(id);
+            let (requests,): (u64,) = redis::pipe()
+                .atomic()
+                .incr(&key, 1)
+                .expire(&key, self.ttl.as_secs() as usize)
+                .ignore()
+                .zadd(Self::ACTIVE_KEY, id, unix_secs())
+                .ignore()
+                .query_async(&mut connection)
+                .await
+                .map_err(|e| format!("Redis INCR failed: {}", e))?;
+            Ok(requests)
+        })
+    }
+
+    fn add_tts_characters<'a>(&'a self, id: &'a str, characters: u64) -> BoxFuture<'a, Result<u64, String>> {
+        Box::pin(async move {
+            let mut connection = self.connection().await?;
+            let key = Self::usage_key(id);
+            let (total,): (u64,) = redis::pipe()
+                .atomic()
+                .incr(&key, characters)
+                .expire(&key, self.ttl.as_secs() as usize)
+                .ignore()
+                .query_async(&mut connection)
+                .await
+                .map_err(|e| format!("Redis INCRBY failed: {}", e))?;
+            Ok(total)
+        })
+    }
+
+    fn expire<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let mut connection = self.connection().await?;
+            redis::pipe()
+                .atomic()
+                .del(vec![Self::key(id), Self::usage_key(id), Self::summary_key(id)])
+                .ignore()
+                .zrem(Self::ACTIVE_KEY, id)
+                .ignore()
+                .query_async::<_, ()>(&mut connection)
+                .await
+                .map_err(|e| format!("Redis DEL failed: {}", e))
+        })
+    }
+
+    fn active_sessions(&self) -> BoxFuture<'_, Result<usize, String>> {
+        Box::pin(async move {
+            let mut connection = self.connection().await?;
+            let (active,): (usize,) = redis::pipe()
+                .atomic()
+                .zrembyscore(Self::ACTIVE_KEY, "-inf", self.idle_cutoff())
+                .ignore()
+                .zcard(Self::ACTIVE_KEY)
+                .query_async(&mut connection)
+                .await
+                .map_err(|e| format!("Redis ZCARD failed: {}", e))?;
+            Ok(active)
+        })
+    }
+
+    /// Redis expires the session keys itself, so this prunes the last-used
+    /// index and, with summaries on, reads back the sessions about to go.
+    fn sweep(&self, lead: Duration) -> BoxFuture<'_, Result<Swept, String>> {
+        Box::pin(async move {
+            let mut connection = self.connection().await?;
+            let idle: Vec<String> = redis::cmd("ZRANGEBYSCORE")
+                .arg(Self::ACTIVE_KEY)
+                .arg("-inf")
+                .arg(self.idle_cutoff() + lead.as_secs())
+                .query_async(&mut connection)
+                .await
+                .map_err(|e| format!("Redis ZRANGEBYSCORE failed: {}", e))?;
+            if self.evicted.keep {
+                for id in &idle {
+                    self.evicted.push(id, self.get(id, self.max_turns).await?);
+                }
+            }
+            if !idle.is_empty() {
+                redis::cmd("ZREM")
+                    .arg(Self::ACTIVE_KEY)
+                    .arg(&idle)
+                    .query_async::<_, ()>(&mut connection)
+                    .await
+                    .map_err(|e| format!("Redis ZREM failed: {}", e))?;
+            }
+            Ok(Swept {
+                count: idle.len(),
+                histories: self.evicted.drain(),
+            })
+        })
+    }
+
+    fn save_summary<'a>(&'a self, id: &'a str, summary: &'a str) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let mut connection = self.connection().await?;
+            redis::cmd("SET")
+                .arg(Self::summary_key(id))
+                .arg(summary)
+                .arg("EX")
+                .arg(self.summary_ttl.as_secs())
+                .query_async::<_, ()>(&mut connection)
+                .await
+                .map_err(|e| format!("Redis SET failed: {}", e))
+        })
+    }
+
+    fn summary<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Option<String>, String>> {
+        Box::pin(async move {
+            let mut connection = self.connection().await?;
+            redis::cmd("GET")
+                .arg(Self::summary_key(id))
+                .query_async(&mut connection)
+                .await
+                .map_err(|e| format!("Redis GET failed: {}", e))
+        })
+    }
+}
+
+fn from_env() -> Result<Box<dyn SessionStore>, String> {
+    match std::env::var("SESSION_STORE").as_deref().unwrap_or("memory") {
+        "memory" => Ok(Box::new(MemoryStore::new())),
+        "redis" => {
+            let url = std::env::var("REDIS_URL").map_err(|_| "SESSION_STORE=redis needs REDIS_URL".to_string())?;
+            Ok(Box::new(RedisStore::new(&url)?))
+        }
+        other => Err(format!(
+            "unknown SESSION_STORE '{}', expected one of {}",
+            other,
+            SESSION_STORES.join(", ")
+        )),
+    }
+}
+
+/// Whether a turn waits for one already in flight in its session
+/// (`SESSION_CONCURRENCY=serialize`, the default) rather than being refused.
+pub fn serialize_turns() -> bool {
+    std::env::var("SESSION_CONCURRENCY").as_deref() != Ok("reject")
+}
+
+type TurnLocks = Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>;
+
+fn turn_locks() -> &'static TurnLocks {
+    static LOCKS: OnceLock<TurnLocks> = OnceLock::new();
+    LOCKS.get_or_init(Default::default)
+}
+
+/// Held for the length of a turn; see `lock_turn`.
+pub struct TurnGuard {
+    id: String,
+    lock: Arc<tokio::sync::Mutex<()>>,
+    guard: Option<tokio::sync::OwnedMutexGuard<()>>,
+}
+
+impl Drop for TurnGuard {
+    fn drop(&mut self) {
+        let mut locks = turn_locks().lock().unwrap();
+        self.guard.take();
+        // Only the map and this guard left means nobody is waiting
+        if Arc::strong_count(&self.lock) == 2 {
+            locks.remove(&self.id);
+        }
+    }
+}
+
+/// Takes the session's turn lock, so two requests sharing an id can't both
+/// read the same history and then interleave or lose their appends.
+/// Different sessions never wait on each other. With `wait` false, a busy
+/// session returns `None` instead of queueing.
+///
+/// The lock is per process: replicas sharing a Redis store only serialize
+/// the requests they serve themselves.
+pub async fn lock_turn(id: &str, wait: bool) -> Option<TurnGuard> {
+    let lock = turn_locks().lock().unwrap().entry(id.to_string()).or_default().clone();
+    let guard = if wait {
+        lock.clone().lock_owned().await
+    } else {
+        lock.clone().try_lock_owned().ok()?
+    };
+    Some(TurnGuard {
+        id: id.to_string(),
+        lock,
+        guard: Some(guard),
+    })
+}
+
+/// Checks `SESSION_STORE` and its settings so mistakes fail at startup.
+pub fn validate_session_store() -> Result<(), String> {
+    max_turns_by_mode().map_err(|e| format!("invalid SESSION_MAX_TURNS_BY_MODE: {}", e))?;
+    for (name, policies) in [
+        ("SESSION_CONCURRENCY", &CONCURRENCY_POLICIES),
+        ("SESSION_FULL_POLICY", &FULL_POLICIES),
+    ] {
+        if let Ok(policy) = std::env::var(name) {
+            if !policies.contains(&policy.as_str()) {
+                return Err(format!(
+                    "unknown {} '{}', expected one of {}",
+                    name,
+                    policy,
+                    policies.join(", ")
+                ));
+            }
+        }
+    }
+    from_env().map(|_| ())
+}
+
+/// The session backend chosen by `SESSION_STORE` (default `memory`).
+pub fn session_store() -> &'static dyn SessionStore {
+    static STORE: OnceLock<Box<dyn SessionStore>> = OnceLock::new();
+    STORE
+        .get_or_init(|| {
+            let store = from_env().unwrap_or_else(|e| {
+                error!("{}, using memory", e);
+                Box::new(MemoryStore::new())
+            });
+            info!(
+                "Sessions stored in {}: {} turns for {}s",
+                store.name(),
+                stored_turns(),
+                ttl().as_secs()
+            );
+            store
+        })
+        .as_ref()
+}
+
+/// Turns an evicted session's history into a short summary.
+pub type Summarizer = fn(Vec<Value>) -> BoxFuture<'static, Result<String, String>>;
+
+/// Sweeps idle sessions every `SESSION_SWEEP_SECS` (default 60), so a quiet
+/// server still lets go of them on time.
+///
+/// With `SESSION_SUMMARIES` on, each evicted session's history goes through
+/// `summarize` and only the summary is kept, for `SESSION_SUMMARY_TTL_SECS`.
+/// Summaries are never logged, and `expire` deletes them with the session.
+pub fn spawn_sweeper(summarize: Summarizer) {
+    let secs = std::env::var("SESSION_SWEEP_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&secs| secs > 0)
+        .unwrap_or(60);
+    // Read sessions a sweep early so Redis hasn't expired them yet
+    let lead = if summaries_enabled() {
+        Duration::from_secs(secs)
+    } else {
+        Duration::ZERO
+    };
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(secs));
+        loop {
+            interval.tick().await;
+            let swept = match session_store().sweep(lead).await {
+                Ok(swept) => swept,
+                Err(e) => {
+                    error!("Session sweep failed: {}", e);
+                    continue;
+                }
+            };
+            if swept.count > 0 {
+                info!("Swept {} idle sessions", swept.count);
+            }
+            for (id, history) in swept.histories {
+                let saved = match summarize(history).await {
+                    Ok(summary) => session_store().save_summary(&id, &summary).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = saved {
+                    error!("Could not keep a session summary: {}", e);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn memory_store_carries_context_across_requests() {
+        let store = MemoryStore::with_limits(10, Duration::from_secs(60));
+        store.append("abc", "I feel stuck", "Tell me more").await.unwrap();
+
+        let history = store.get("abc", 10).await.unwrap();
+        assert_eq!(history, exchange("I feel stuck", "Tell me more").to_vec());
+        assert!(store.get("other", 10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn memory_store_keeps_only_the_latest_turns() {
+        let store = MemoryStore::with_limits(2, Duration::from_secs(60));
+        for turn in ["one", "two", "three"] {
+            store.append("abc", turn, "ok").await.unwrap();
+        }
+
+        let history = store.get("abc", 10).await.unwrap();
+        assert_eq!(history.len(), 4);
+        assert_eq!(history[0]["content"], "two");
+    }
+
+    #[tokio::test]
+    async fn memory_store_replays_the_requested_depth() {
+        let store = MemoryStore::with_limits(10, Duration::from_secs(60));
+        for turn in ["one", "two", "three"] {
+            store.append("abc", turn, "ok").await.unwrap();
+        }
+
+        let shallow = store.get("abc", 1).await.unwrap();
+        assert_eq!(shallow, exchange("three", "ok").to_vec());
+        // A shallower mode hides older turns without dropping them
+        assert_eq!(store.get("abc", 10).await.unwrap().len(), 6);
+        assert!(store.get("abc", 0).await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn history_depth_is_configurable_per_mode() {
+        std::env::set_var("SESSION_MAX_TURNS_BY_MODE", "sarcastic=3, calm=25");
+        assert_eq!(history_turns(Tone::Sarcastic), 3);
+        assert_eq!(history_turns(Tone::Calm), 25);
+        assert_eq!(history_turns(Tone::Seductive), max_turns());
+        assert_eq!(stored_turns(), 25.max(max_turns()));
+
+        std::env::set_var("SESSION_MAX_TURNS_BY_MODE", "grumpy=3");
+        assert!(max_turns_by_mode().is_err());
+        std::env::set_var("SESSION_MAX_TURNS_BY_MODE", "calm=lots");
+        assert!(max_turns_by_mode().is_err());
+        std::env::remove_var("SESSION_MAX_TURNS_BY_MODE");
+    }
+
+    /// One turn: read the history, think for a while, then record a reply
+    /// numbered after the turns seen.
+    async fn take_turn(store: &MemoryStore, id: &str) {
+        let _turn = lock_turn(id, true).await.unwrap();
+        let seen = store.get(id, 10).await.unwrap().len() / 2;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        store.append(id, "hello", &format!("reply {}", seen)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent_turns_in_one_session_are_serialized() {
+        let store = MemoryStore::with_limits(10, Duration::from_secs(60));
+        tokio::join!(take_turn(&store, "concurrent"), take_turn(&store, "concurrent"));
+
+        let replies: Vec<_> = store.get("concurrent", 10).await.unwrap()[1..]
+            .iter()
+            .step_by(2)
+            .map(|message| message["content"].clone())
+            .collect();
+        assert_eq!(replies, ["reply 0", "reply 1"]);
+        assert!(!turn_locks().lock().unwrap().contains_key("concurrent"));
+    }
+
+    #[tokio::test]
+    async fn busy_sessions_can_refuse_instead_of_waiting() {
+        let held = lock_turn("busy", false).await.unwrap();
+        assert!(lock_turn("busy", false).await.is_none());
+        assert!(lock_turn("not-busy", false).await.is_some());
+
+        drop(held);
+        assert!(lock_turn("busy", false).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn full_memory_store_evicts_the_least_recently_used_session() {
+        let capacity = Capacity {
+            max_sessions: 2,
+            evict: true,
+        };
+        let store = MemoryStore::with_limits(10, Duration::from_secs(60)).with_capacity(capacity);
+        for id in ["first", "second"] {
+            assert!(store.admit(id).await.unwrap());
+            store.append(id, "hello", "hi").await.unwrap();
+        }
+        // Touching "first" leaves "second" as the least recently used
+        assert!(store.admit("first").await.unwrap());
+
+        assert!(store.admit("third").await.unwrap());
+        assert_eq!(store.active_sessions().await.unwrap(), 2);
+        assert!(store.get("second", 10).await.unwrap().is_empty());
+        assert!(!store.get("first", 10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn full_memory_store_can_reject_new_sessions() {
+        let capacity = Capacity {
+            max_sessions: 1,
+            evict: false,
+        };
+        let store = MemoryStore::with_limits(10, Duration::from_secs(60)).with_capacity(capacity);
+        assert!(store.admit("first").await.unwrap());
+
+        assert!(!store.admit("second").await.unwrap());
+        assert!(store.admit("first").await.unwrap());
+        assert_eq!(store.active_sessions().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn memory_store_hands_evicted_histories_over_for_summaries() {
+        let capacity = Capacity {
+            max_sessions: 1,
+            evict: true,
+        };
+        let store = MemoryStore::with_limits(10, Duration::from_millis(50))
+            .with_capacity(capacity)
+            .with_summaries(true, Duration::from_secs(60));
+        store.admit("idle").await.unwrap();
+        store.append("idle", "I feel stuck", "Tell me more").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(70)).await;
+        store.admit("crowded-out").await.unwrap();
+        store.append("crowded-out", "hello", "hi").await.unwrap();
+        store.admit("newest").await.unwrap();
+
+        let swept = store.sweep(Duration::ZERO).await.unwrap();
+        let mut ids: Vec<_> = swept.histories.iter().map(|(id, _)| id.as_str()).collect();
+        ids.sort_unstable();
+        assert_eq!(ids, ["crowded-out", "idle"]);
+        assert!(store.sweep(Duration::ZERO).await.unwrap().histories.is_empty());
+    }
+
+    #[tokio::test]
+    async fn memory_store_keeps_no_histories_with_summaries_off() {
+        let store = MemoryStore::with_limits(10, Duration::from_millis(20));
+        store.append("abc", "hello", "hi").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(40)).await;
+
+        let swept = store.sweep(Duration::ZERO).await.unwrap();
+        assert_eq!(swept.count, 1);
+        assert!(swept.histories.is_empty());
+    }
+
+    #[tokio::test]
+    async fn memory_store_summaries_expire_and_go_with_the_session() {
+        let store = MemoryStore::with_limits(10, Duration::from_secs(60)).with_summaries(true, Duration::from_millis(30));
+        store.save_summary("abc", "Talked about work stress.").await.unwrap();
+        assert_eq!(store.summary("abc").await.unwrap().as_deref(), Some("Talked about work stress."));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(store.summary("abc").await.unwrap(), None);
+
+        store.save_summary("abc", "Talked about work stress.").await.unwrap();
+        store.expire("abc").await.unwrap();
+        assert_eq!(store.summary("abc").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn memory_store_evicts_idle_sessions() {
+        let store = MemoryStore::with_limits(10, Duration::from_millis(20));
+        store.append("abc", "hello", "hi").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(40)).await;
+
+        assert!(store.get("abc", 10).await.unwrap().is_empty());
+        assert!(store.sessions.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn memory_store_sweep_drops_idle_sessions_and_counts_active_ones() {
+        let store = MemoryStore::with_limits(10, Duration::from_millis(50));
+        store.append("old", "hello", "hi").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(70)).await;
+        store.append("new", "hello", "hi").await.unwrap();
+
+        assert_eq!(store.sweep(Duration::ZERO).await.unwrap().count, 1);
+        assert_eq!(store.active_sessions().await.unwrap(), 1);
+        assert_eq!(store.sweep(Duration::ZERO).await.unwrap().count, 0);
+    }
+
+    #[tokio::test]
+    async fn memory_store_expire_forgets_session() {
+        let store = MemoryStore::with_limits(10, Duration::from_secs(60));
+        store.append("abc", "hello", "hi").await.unwrap();
+        store.expire("abc").await.unwrap();
+
+        assert!(store.get("abc", 10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn memory_store_request_counts_survive_expire() {
+        let store = MemoryStore::with_limits(10, Duration::from_secs(60));
+        assert_eq!(store.record_request("abc").await.unwrap(), 1);
+        store.append("abc", "hello", "hi").await.unwrap();
+        store.expire("abc").await.unwrap();
+
+        assert!(store.get("abc", 10).await.unwrap().is_empty());
+        assert_eq!(store.record_request("abc").await.unwrap(), 2);
+        assert_eq!(store.record_request("other").await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn memory_store_tallies_tts_characters_per_session() {
+        let store = MemoryStore::with_limits(10, Duration::from_secs(60));
+        assert_eq!(store.add_tts_characters("abc", 12).await.unwrap(), 12);
+        assert_eq!(store.add_tts_characters("abc", 30).await.unwrap(), 42);
+        assert_eq!(store.add_tts_characters("other", 5).await.unwrap(), 5);
+
+        store.expire("abc").await.unwrap();
+        assert_eq!(store.add_tts_characters("abc", 1).await.unwrap(), 1);
+    }
+}
+
+/// Needs a running Redis: `cargo test --features redis-tests`, with
+/// `REDIS_URL` pointing elsewhere than the local default if need be.
+#[cfg(all(test, feature = "redis-tests"))]
+mod redis_tests {
+    use super::*;
+
+    fn store(max_turns: usize, ttl: Duration) -> RedisStore {
+        let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_string());
+        RedisStore::with_limits(&url, max_turns, ttl).unwrap()
+    }
+
+    /// Keeps parallel test runs from sharing sessions.
+    fn session_id(test: &str) -> String {
+        format!("test-{}-{}", std::process::id(), test)
+    }
+
+    #[tokio::test]
+    async fn append_then_get_returns_history() {
+        let store = store(10, Duration::from_secs(60));
+        let id = session_id("history");
+        store.append(&id, "I feel stuck", "Tell me more").await.unwrap();
+        store.append(&id, "Work is hard", "What about it?").await.unwrap();
+
+        let history = store.get(&id, 10).await.unwrap();
+        let mut expected = exchange("I feel stuck", "Tell me more").to_vec();
+        expected.extend(exchange("Work is hard", "What about it?"));
+        assert_eq!(history, expected);
+        store.expire(&id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn append_trims_to_the_turn_cap() {
+        let store = store(2, Duration::from_secs(60));
+        let id = session_id("ltrim");
+        for turn in ["one", "two", "three"] {
+            store.append(&id, turn, "ok").await.unwrap();
+        }
+
+        let history = store.get(&id, 10).await.unwrap();
+        assert_eq!(history.len(), 4);
+        assert_eq!(history[0]["content"], "two");
+        store.expire(&id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn get_returns_only_the_requested_depth() {
+        let store = store(10, Duration::from_secs(60));
+        let id = session_id("depth");
+        for turn in ["one", "two", "three"] {
+            store.append(&id, turn, "ok").await.unwrap();
+        }
+
+        assert_eq!(store.get(&id, 1).await.unwrap(), exchange("three", "ok").to_vec());
+        assert!(store.get(&id, 0).await.unwrap().is_empty());
+        store.expire(&id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn sessions_expire_after_the_ttl() {
+        let store = store(10, Duration::from_secs(1));
+        let id = session_id("ttl");
+        store.append(&id, "hello", "hi").await.unwrap();
+
+        let mut connection = store.connection().await.unwrap();
+        let ttl: i64 = redis::cmd("TTL")
+            .arg(RedisStore::key(&id))
+            .query_async(&mut connection)
+            .await
+            .unwrap();
+        assert!((0..=1).contains(&ttl), "TTL was {}", ttl);
+
+        tokio::time::sleep(Duration::from_millis(2100)).await;
+        assert!(store.get(&id, 10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn expire_deletes_the_session() {
+        let store = store(10, Duration::from_secs(60));
+        let id = session_id("expire");
+        store.append(&id, "hello", "hi").await.unwrap();
+        store.expire(&id).await.unwrap();
+
+        assert!(store.get(&id, 10).await.unwrap().is_empty());
+        let mut connection = store.connection().await.unwrap();
+        let score: Option<u64> = redis::cmd("ZSCORE")
+            .arg(RedisStore::ACTIVE_KEY)
+            .arg(&id)
+            .query_async(&mut connection)
+            .await
+            .unwrap();
+        assert_eq!(score, None);
+    }
+
+    #[tokio::test]
+    async fn appended_sessions_count_as_active() {
+        let store = store(10, Duration::from_secs(60));
+        let id = session_id("active");
+        store.append(&id, "hello", "hi").await.unwrap();
+
+        assert!(store.active_sessions().await.unwrap() >= 1);
+        store.expire(&id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn request_counts_survive_expire() {
+        let store = store(10, Duration::from_secs(60));
+        let id = session_id("requests");
+        let first = store.record_request(&id).await.unwrap();
+        store.expire(&id).await.unwrap();
+
+        assert_eq!(store.record_request(&id).await.unwrap(), first + 1);
+        let mut connection = store.connection().await.unwrap();
+        redis::cmd("DEL")
+            .arg(RedisStore::requests_key(&id))
+            .query_async::<_, ()>(&mut connection)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn summaries_are_kept_until_the_session_is_deleted() {
+        let store = store(10, Duration::from_secs(60));
+        let id = session_id("summary");
+        store.save_summary(&id, "Talked about work stress.").await.unwrap();
+        assert_eq!(store.summary(&id).await.unwrap().as_deref(), Some("Talked about work stress."));
+
+        store.expire(&id).await.unwrap();
+        assert_eq!(store.summary(&id).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn sweep_reads_back_sessions_about_to_expire() {
+        let store = store(10, Duration::from_secs(60)).with_summaries(true, Duration::from_secs(60));
+        let id = session_id("sweep");
+        store.append(&id, "I feel stuck", "Tell me more").await.unwrap();
+
+        // A lead as long as the TTL makes every session due
+        let swept = store.sweep(Duration::from_secs(60)).await.unwrap();
+        let history = swept.histories.iter().find(|(swept_id, _)| *swept_id == id);
+        assert_eq!(history.unwrap().1, exchange("I feel stuck", "Tell me more").to_vec());
+        store.expire(&id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn tts_characters_accumulate_until_expired() {
+        let store = store(10, Duration::from_secs(60));
+        let id = session_id("tts");
+        assert_eq!(store.add_tts_characters(&id, 12).await.unwrap(), 12);
+        assert_eq!(store.add_tts_characters(&id, 30).await.unwrap(), 42);
+
+        store.expire(&id).await.unwrap();
+        assert_eq!(store.add_tts_characters(&id, 1).await.unwrap(), 1);
+        store.expire(&id).await.unwrap();
+    }
+}
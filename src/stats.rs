@@ -0,0 +1,132 @@
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Recent end-to-end latencies kept per language/mode for estimates.
+const LATENCY_SAMPLES: usize = 100;
+/// How often the latency estimates are recomputed from the samples.
+const ESTIMATE_REFRESH: Duration = Duration::from_secs(30);
+
+/// Aggregate request counters served by `/stats`.
+#[derive(Default)]
+pub struct Stats {
+    total_requests: AtomicU64,
+    completed_requests: AtomicU64,
+    total_latency_ms: AtomicU64,
+    by_language: Mutex<HashMap<String, u64>>,
+    by_mode: Mutex<HashMap<String, u64>>,
+    errors: Mutex<HashMap<String, u64>>,
+    recent_latencies: Mutex<HashMap<String, VecDeque<u64>>>,
+    estimates: Mutex<Estimates>,
+}
+
+#[derive(Default)]
+struct Estimates {
+    computed_at: Option<Instant>,
+    by_key: HashMap<String, u64>,
+}
+
+#[derive(Serialize)]
+pub struct StatsSnapshot {
+    total_requests: u64,
+    completed_requests: u64,
+    average_latency_ms: f64,
+    by_language: HashMap<String, u64>,
+    by_mode: HashMap<String, u64>,
+    errors: HashMap<String, u64>,
+}
+
+impl Stats {
+    pub fn record_request(&self, language: &str, modes: &[&str]) {
+        self.total_requests.fetch_add(1, Ordering::Relaxed);
+        *self.by_language.lock().unwrap().entry(language.to_string()).or_default() += 1;
+        let mut by_mode = self.by_mode.lock().unwrap();
+        for mode in modes {
+            *by_mode.entry(mode.to_string()).or_default() += 1;
+        }
+    }
+
+    pub fn record_success(&self, latency_ms: u64) {
+        self.completed_requests.fetch_add(1, Ordering::Relaxed);
+        self.total_latency_ms.fetch_add(latency_ms, Ordering::Relaxed);
+    }
+
+    pub fn record_error(&self, kind: &str) {
+        *self.errors.lock().unwrap().entry(kind.to_string()).or_default() += 1;
+    }
+
+    /// Adds a latency sample for a `"<language>:<mode>"` key.
+    pub fn record_latency(&self, key: &str, latency_ms: u64) {
+        let mut recent = self.recent_latencies.lock().unwrap();
+        let samples = recent.entry(key.to_string()).or_default();
+        if samples.len() == LATENCY_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(latency_ms);
+    }
+
+    /// Median recent latency for a key. Estimates are recomputed at most
+    /// every `ESTIMATE_REFRESH` so lookups stay cheap.
+    pub fn estimated_latency_ms(&self, key: &str) -> Option<u64> {
+        let mut estimates = self.estimates.lock().unwrap();
+        let stale = match estimates.computed_at {
+            Some(at) => at.elapsed() >= ESTIMATE_REFRESH,
+            None => true,
+        };
+        if stale {
+            let recent = self.recent_latencies.lock().unwrap();
+            estimates.by_key = recent
+                .iter()
+                .filter(|(_, samples)| !samples.is_empty())
+                .map(|(key, samples)| {
+                    let mut sorted: Vec<u64> = samples.iter().copied().collect();
+                    sorted.sort_unstable();
+                    (key.clone(), sorted[sorted.len() / 2])
+                })
+                .collect();
+            estimates.computed_at = Some(Instant::now());
+        }
+        estimates.by_key.get(key).copied()
+    }
+
+    /// Takes a snapshot, optionally resetting every counter in the same pass.
+    pub fn snapshot(&self, reset: bool) -> StatsSnapshot {
+        let read = |counter: &AtomicU64| {
+            if reset {
+                counter.swap(0, Ordering::Relaxed)
+            } else {
+                counter.load(Ordering::Relaxed)
+            }
+        };
+        let read_map = |map: &Mutex<HashMap<String, u64>>| {
+            let mut map = map.lock().unwrap();
+            if reset {
+                std::mem::take(&mut *map)
+            } else {
+                map.clone()
+            }
+        };
+
+        let completed_requests = read(&self.completed_requests);
+        let total_latency_ms = read(&self.total_latency_ms);
+        StatsSnapshot {
+            total_requests: read(&self.total_requests),
+            completed_requests,
+            average_latency_ms: if completed_requests > 0 {
+                total_latency_ms as f64 / completed_requests as f64
+            } else {
+                0.0
+            },
+            by_language: read_map(&self.by_language),
+            by_mode: read_map(&self.by_mode),
+            errors: read_map(&self.errors),
+        }
+    }
+}
+
+pub fn stats() -> &'static Stats {
+    static STATS: OnceLock<Stats> = OnceLock::new();
+    STATS.get_or_init(Stats::default)
+}
@@ -0,0 +1,290 @@
+use futures_util::future::BoxFuture;
+use futures_util::StreamExt;
+use log::error;
+use reqwest::Client;
+use std::sync::OnceLock;
+
+use crate::breaker::breaker;
+use crate::{
+    key_pool, openai_extra_headers, read_transcription_stream, request_timeout, retry_after, upstream_error, AudioError,
+    Transcription, Turn,
+};
+
+/// Speech-to-text backends selectable with `TRANSCRIPTION_PROVIDER`.
+pub const TRANSCRIBERS: [&str; 1] = ["openai"];
+
+/// One transcription, independent of the backend that serves it.
+pub struct TranscriptionRequest<'a> {
+    /// 24kHz mono PCM16 WAV.
+    pub wav: &'a [u8],
+    /// Language spoken, or `None` to have the backend detect it.
+    pub language: Option<&'a str>,
+    /// Vocabulary hint biasing recognition towards expected terms.
+    pub prompt: Option<&'a str>,
+    /// Whether segment timings (and with them a confidence) are wanted.
+    pub with_segments: bool,
+}
+
+/// A backend that turns speech into text.
+pub trait Transcriber: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn transcribe<'a>(&'a self, request: TranscriptionRequest<'a>) -> BoxFuture<'a, Result<Transcription, AudioError>>;
+
+    /// Transcribes with the streaming `model`, handing the transcript so far
+    /// to `on_partial` as it grows. Returns the transcript and whether the
+    /// backend finished it.
+    ///
+    /// Backends that can't stream transcribe in one go and report the whole
+    /// transcript as a single partial.
+    fn stream<'a>(
+        &'a self,
+        request: TranscriptionRequest<'a>,
+        _model: &'a str,
+        on_partial: &'a mut (dyn FnMut(&str) + Send),
+    ) -> BoxFuture<'a, Result<(String, bool), AudioError>> {
+        Box::pin(async move {
+            let transcription = self.transcribe(request).await?;
+            on_partial(&transcription.text);
+            Ok((transcription.text, true))
+        })
+    }
+}
+
+/// OpenAI Whisper over HTTP.
+pub struct OpenAiWhisper {
+    client: Client,
+}
+
+impl Transcriber for OpenAiWhisper {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    fn transcribe<'a>(&'a self, request: TranscriptionRequest<'a>) -> BoxFuture<'a, Result<Transcription, AudioError>> {
+        Box::pin(breaker().guard(async move {
+            let (key_index, api_key) = key_pool().next_key()?;
+
+            let mut form = reqwest::multipart::Form::new()
+                .text("model", "whisper-1")
+                .part(
+                    "file",
+                    reqwest::multipart::Part::bytes(request.wav.to_vec())
+                        .file_name("audio.wav")
+                        .mime_str("audio/wav")
+                        .map_err(|e| AudioError::OpenAI(e.to_string()))?,
+                );
+            if let Some(language) = request.language {
+                form = form.text("language", language.to_string());
+            }
+            if let Some(prompt) = request.prompt {
+                form = form.text("prompt", prompt.to_string());
+            }
+            // Only verbose output reports the detected language
+            if request.with_segments || request.language.is_none() {
+                form = form.text("response_format", "verbose_json");
+            }
+
+            let response = self
+                .client
+                .post("https://api.openai.com/v1/audio/transcriptions")
+                .header("Authorization", format!("Bearer {}", api_key))
+                .headers(openai_extra_headers().clone())
+                .multipart(form)
+                .timeout(request_timeout())
+                .send()
+                .await
+                .map_err(AudioError::from)?;
+
+            let status = response.status();
+            key_pool().report_status(key_index, status);
+            if !status.is_success() {
+                let retry_after = retry_after(response.headers());
+                let error_text = response.text().await.unwrap_or_default();
+                error!("Whisper API failed: status={}, error={}", status, error_text);
+                return Err(upstream_error(status, retry_after, format!("Whisper API failed: {}", error_text)));
+            }
+
+            let json: serde_json::Value = response.json().await.map_err(AudioError::from)?;
+            let transcript = json["text"]
+                .as_str()
+                .ok_or_else(|| AudioError::OpenAI("No transcript in response".to_string()))?
+                .to_string();
+
+            let segments = json["segments"]
+                .as_array()
+                .map(|segments| {
+                    segments
+                        .iter()
+                        .filter_map(|segment| {
+                            Some(Turn {
+                                start: segment["start"].as_f64()? as f32,
+                                end: segment["end"].as_f64()? as f32,
+                                text: segment["text"].as_str()?.trim().to_string(),
+                            })
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+
+            let confidence = json["segments"].as_array().and_then(|segments| {
+                let probabilities: Vec<f64> = segments
+                    .iter()
+                    .filter_map(|segment| segment["avg_logprob"].as_f64())
+                    .map(f64::exp)
+                    .collect();
+                (!probabilities.is_empty())
+                    .then(|| (probabilities.iter().sum::<f64>() / probabilities.len() as f64) as f32)
+            });
+
+            Ok(Transcription {
+                text: transcript,
+                segments,
+                confidence,
+                detected_language: json["language"]
+                    .as_str()
+                    .filter(|_| request.language.is_none())
+                    .map(str::to_string),
+            })
+        }))
+    }
+
+    fn stream<'a>(
+        &'a self,
+        request: TranscriptionRequest<'a>,
+        model: &'a str,
+        on_partial: &'a mut (dyn FnMut(&str) + Send),
+    ) -> BoxFuture<'a, Result<(String, bool), AudioError>> {
+        Box::pin(breaker().guard(async move {
+            let (key_index, api_key) = key_pool().next_key()?;
+
+            let mut form = reqwest::multipart::Form::new()
+                .text("model", model.to_string())
+                .text("stream", "true")
+                .part(
+                    "file",
+                    reqwest::multipart::Part::bytes(request.wav.to_vec())
+                        .file_name("audio.wav")
+                        .mime_str("audio/wav")
+                        .map_err(|e| AudioError::OpenAI(e.to_string()))?,
+                );
+            if let Some(language) = request.language {
+                form = form.text("language", language.to_string());
+            }
+            if let Some(prompt) = request.prompt {
+                form = form.text("prompt", prompt.to_string());
+            }
+
+            let response = self
+                .client
+                .post("https://api.openai.com/v1/audio/transcriptions")
+                .header("Authorization", format!("Bearer {}", api_key))
+                .headers(openai_extra_headers().clone())
+                .multipart(form)
+                .send()
+                .await
+                .map_err(AudioError::from)?;
+
+            let status = response.status();
+            key_pool().report_status(key_index, status);
+            if !status.is_success() {
+                let retry_after = retry_after(response.headers());
+                let error_text = response.text().await.unwrap_or_default();
+                error!("Streaming transcription failed: status={}, error={}", status, error_text);
+                return Err(upstream_error(status, retry_after, format!("Whisper API failed: {}", error_text)));
+            }
+
+            let chunks = response.bytes_stream().map(|chunk| chunk.map_err(AudioError::from));
+            read_transcription_stream(chunks, on_partial).await
+        }))
+    }
+}
+
+fn from_env(client: &Client) -> Result<Box<dyn Transcriber>, String> {
+    match std::env::var("TRANSCRIPTION_PROVIDER").as_deref().unwrap_or("openai") {
+        "openai" => Ok(Box::new(OpenAiWhisper { client: client.clone() })),
+        other => Err(format!(
+            "unknown TRANSCRIPTION_PROVIDER '{}', expected one of {}",
+            other,
+            TRANSCRIBERS.join(", ")
+        )),
+    }
+}
+
+static TRANSCRIBER: OnceLock<Box<dyn Transcriber>> = OnceLock::new();
+
+/// Picks the speech-to-text backend from `TRANSCRIPTION_PROVIDER` (default
+/// `openai`), sending through the shared `client`. Run once at startup so a
+/// typo fails there rather than per request.
+pub fn init_transcriber(client: &Client) -> Result<(), String> {
+    let transcriber = from_env(client)?;
+    TRANSCRIBER
+        .set(transcriber)
+        .map_err(|_| "transcriber is already set".to_string())
+}
+
+/// The speech-to-text backend picked by `init_transcriber`.
+pub fn transcriber() -> &'static dyn Transcriber {
+    TRANSCRIBER.get().expect("transcribe::init_transcriber runs at startup").as_ref()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stands in for a real backend, echoing what it was asked.
+    struct FakeTranscriber;
+
+    impl Transcriber for FakeTranscriber {
+        fn name(&self) -> &'static str {
+            "fake"
+        }
+
+        fn transcribe<'a>(&'a self, request: TranscriptionRequest<'a>) -> BoxFuture<'a, Result<Transcription, AudioError>> {
+            Box::pin(async move {
+                Ok(Transcription {
+                    text: format!("{} bytes in {}", request.wav.len(), request.language.unwrap_or("auto")),
+                    segments: Vec::new(),
+                    confidence: None,
+                    detected_language: request.language.is_none().then(|| "en".to_string()),
+                })
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn any_backend_can_stand_behind_the_trait() {
+        let transcriber: Box<dyn Transcriber> = Box::new(FakeTranscriber);
+        let request = |language| TranscriptionRequest {
+            wav: &[0; 4],
+            language,
+            prompt: None,
+            with_segments: false,
+        };
+
+        let transcription = transcriber.transcribe(request(Some("hi"))).await.unwrap();
+        assert_eq!(transcription.text, "4 bytes in hi");
+        assert_eq!(transcription.detected_language, None);
+
+        let transcription = transcriber.transcribe(request(None)).await.unwrap();
+        assert_eq!(transcription.detected_language.as_deref(), Some("en"));
+
+        let mut partials = Vec::new();
+        let streamed = transcriber
+            .stream(request(Some("hi")), "gpt-4o-transcribe", &mut |partial| partials.push(partial.to_string()))
+            .await
+            .unwrap();
+        assert_eq!(streamed, ("4 bytes in hi".to_string(), true));
+        assert_eq!(partials, ["4 bytes in hi"]);
+    }
+
+    #[test]
+    fn provider_is_picked_from_env() {
+        std::env::set_var("TRANSCRIPTION_PROVIDER", "whisper-cpp");
+        let err = from_env(&Client::new()).err().unwrap();
+        assert!(err.contains("unknown TRANSCRIPTION_PROVIDER 'whisper-cpp'"), "{}", err);
+
+        std::env::remove_var("TRANSCRIPTION_PROVIDER");
+        assert_eq!(from_env(&Client::new()).unwrap().name(), "openai");
+    }
+}
@@ -0,0 +1,393 @@
+use futures_util::future::BoxFuture;
+use log::{debug, error, info};
+use reqwest::Client;
+use serde_json::json;
+use std::sync::OnceLock;
+
+use crate::breaker::breaker;
+use crate::{
+    key_pool, openai_extra_headers, request_timeout, retry_after, tts_model_for, upstream_error, AudioError, Language,
+};
+
+/// Text-to-speech backends selectable with `TTS_PROVIDER`.
+pub const TTS_PROVIDERS: [&str; 2] = ["openai", "elevenlabs"];
+
+/// OpenAI TTS voices that can be previewed or requested.
+pub const OPENAI_VOICES: [&str; 6] = ["alloy", "echo", "fable", "onyx", "nova", "shimmer"];
+
+/// Encodings a provider can be asked for.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AudioFormat {
+    Mp3,
+    /// 24kHz mono PCM16 WAV.
+    Wav,
+}
+
+impl AudioFormat {
+    pub fn parse(format: &str) -> Option<Self> {
+        match format {
+            "mp3" => Some(AudioFormat::Mp3),
+            "wav" => Some(AudioFormat::Wav),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        self.content_types()[0]
+    }
+
+    /// Content-Type labels players are known to accept for this encoding,
+    /// the registered one first.
+    pub fn content_types(self) -> &'static [&'static str] {
+        match self {
+            AudioFormat::Mp3 => &["audio/mpeg", "audio/mp3", "audio/mpeg3", "audio/x-mpeg"],
+            AudioFormat::Wav => &["audio/wav", "audio/x-wav", "audio/wave", "audio/vnd.wave"],
+        }
+    }
+
+    /// Picks the label to send: `requested` if given, else the
+    /// `AUDIO_CONTENT_TYPE_MP3` / `AUDIO_CONTENT_TYPE_WAV` setting, else the
+    /// registered type. Labels outside `content_types` are rejected.
+    pub fn content_type_label(self, requested: Option<&str>) -> Result<&'static str, String> {
+        let variable = match self {
+            AudioFormat::Mp3 => "AUDIO_CONTENT_TYPE_MP3",
+            AudioFormat::Wav => "AUDIO_CONTENT_TYPE_WAV",
+        };
+        let configured = std::env::var(variable).ok();
+        let Some(label) = requested.or(configured.as_deref()) else {
+            return Ok(self.content_type());
+        };
+        let label = label.trim();
+        self.content_types()
+            .iter()
+            .find(|known| known.eq_ignore_ascii_case(label))
+            .copied()
+            .ok_or_else(|| format!("content type '{}' must be one of {}", label, self.content_types().join(", ")))
+    }
+}
+
+/// One synthesis, independent of the backend that serves it.
+pub struct SpeechRequest<'a> {
+    pub text: &'a str,
+    pub language: Language,
+    /// Overrides the provider's voice for the language.
+    pub voice: Option<&'a str>,
+    /// Prefer the provider's higher-quality (slower, pricier) model.
+    pub hd: bool,
+    pub format: AudioFormat,
+}
+
+/// Synthesized audio, labeled with the encoding it is actually in.
+pub struct SynthesizedAudio {
+    pub bytes: Vec<u8>,
+    pub format: AudioFormat,
+}
+
+/// A backend that turns text into speech.
+pub trait TtsProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Voices a request may pick with `voice`; empty when only the
+    /// provider's per-language defaults are available.
+    fn voices(&self) -> Vec<String>;
+
+    fn synthesize<'a>(&'a self, request: SpeechRequest<'a>) -> BoxFuture<'a, Result<SynthesizedAudio, AudioError>>;
+}
+
+/// OpenAI's speech endpoint, with the model chosen per language by `TTS_MODEL_BY_LANGUAGE`.
+pub struct OpenAiTts {
+    client: Client,
+}
+
+impl OpenAiTts {
+    pub fn new(client: Client) -> Self {
+        OpenAiTts { client }
+    }
+}
+
+impl TtsProvider for OpenAiTts {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    fn voices(&self) -> Vec<String> {
+        OPENAI_VOICES.map(str::to_string).to_vec()
+    }
+
+    fn synthesize<'a>(&'a self, request: SpeechRequest<'a>) -> BoxFuture<'a, Result<SynthesizedAudio, AudioError>> {
+        Box::pin(breaker().guard(async move {
+            let (key_index, api_key) = key_pool().next_key()?;
+
+            let voice = request.voice.unwrap_or(request.language.tts_voice());
+
+            let model = speech_model(request.language, request.hd);
+            debug!("Using TTS model {} for language {}", model, request.language);
+
+            let response_format = match request.format {
+                AudioFormat::Mp3 => "mp3",
+                AudioFormat::Wav => "wav",
+            };
+            let response = self
+                .client
+                .post("https://api.openai.com/v1/audio/speech")
+                .header("Authorization", format!("Bearer {}", api_key))
+                .headers(openai_extra_headers().clone())
+                .json(&json!({
+                    "model": model,
+                    "input": request.text,
+                    "voice": voice,
+                    "response_format": response_format
+                }))
+                .timeout(request_timeout())
+                .send()
+                .await
+                .map_err(AudioError::from)?;
+
+            let status = response.status();
+            key_pool().report_status(key_index, status);
+            if !status.is_success() {
+                let retry_after = retry_after(response.headers());
+                let error_text = response.text().await.unwrap_or_default();
+                error!("TTS API failed: status={}, error={}", status, error_text);
+                return Err(upstream_error(status, retry_after, format!("TTS API failed: {}", error_text)));
+            }
+
+            let bytes = response.bytes().await.map_err(AudioError::from)?.to_vec();
+            Ok(SynthesizedAudio {
+                bytes,
+                format: request.format,
+            })
+        }))
+    }
+}
+
+/// OpenAI speech model for a request: `tts-1-hd` when `hd` is asked for,
+/// otherwise the language's configured model.
+fn speech_model(language: Language, hd: bool) -> String {
+    if hd {
+        info!("Using tts-1-hd: better audio at roughly twice the cost and extra latency");
+        return "tts-1-hd".to_string();
+    }
+    tts_model_for(language)
+}
+
+/// ElevenLabs, keyed by `ELEVENLABS_API_KEY`.
+///
+/// Default voices come from `ELEVENLABS_VOICE_<LANG>` (e.g.
+/// `ELEVENLABS_VOICE_HI`), falling back to `ELEVENLABS_VOICE_ID`. Requests may
+/// pick any voice id listed in the comma-separated `ELEVENLABS_VOICES`. The
+/// model comes from `ELEVENLABS_MODEL` (default `eleven_multilingual_v2`,
+/// which covers every supported language).
+pub struct ElevenLabs {
+    client: Client,
+    api_key: String,
+}
+
+impl ElevenLabs {
+    fn voice_for(language: Language) -> Result<String, AudioError> {
+        std::env::var(format!("ELEVENLABS_VOICE_{}", language.code().to_uppercase()))
+            .or_else(|_| std::env::var("ELEVENLABS_VOICE_ID"))
+            .map_err(|_| {
+                AudioError::InvalidRequest(format!("no ElevenLabs voice configured for '{}'", language))
+            })
+    }
+}
+
+impl TtsProvider for ElevenLabs {
+    fn name(&self) -> &'static str {
+        "elevenlabs"
+    }
+
+    fn voices(&self) -> Vec<String> {
+        std::env::var("ELEVENLABS_VOICES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|voice| !voice.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    fn synthesize<'a>(&'a self, request: SpeechRequest<'a>) -> BoxFuture<'a, Result<SynthesizedAudio, AudioError>> {
+        Box::pin(breaker().guard(async move {
+            let voice_id = match request.voice {
+                Some(voice) => voice.to_string(),
+                None => Self::voice_for(request.language)?,
+            };
+            let model = std::env::var("ELEVENLABS_MODEL").unwrap_or_else(|_| "eleven_multilingual_v2".to_string());
+
+            // WAV comes back as headerless PCM, wrapped below
+            let output_format = match request.format {
+                AudioFormat::Mp3 => "mp3_44100_128",
+                AudioFormat::Wav => "pcm_24000",
+            };
+            let response = self
+                .client
+                .post(format!("https://api.elevenlabs.io/v1/text-to-speech/{}", voice_id))
+                .query(&[("output_format", output_format)])
+                .header("xi-api-key", &self.api_key)
+                .json(&json!({
+                    "text": request.text,
+                    "model_id": model,
+                    "language_code": request.language.code()
+                }))
+                .timeout(request_timeout())
+                .send()
+                .await
+                .map_err(AudioError::from)?;
+
+            let status = response.status();
+            if !status.is_success() {
+                let retry_after = retry_after(response.headers());
+                let error_text = response.text().await.unwrap_or_default();
+                error!("ElevenLabs TTS failed: status={}, error={}", status, error_text);
+                return Err(upstream_error(status, retry_after, format!("ElevenLabs TTS failed: {}", error_text)));
+            }
+
+            let bytes = response.bytes().await.map_err(AudioError::from)?.to_vec();
+            let bytes = match request.format {
+                AudioFormat::Mp3 => bytes,
+                AudioFormat::Wav => pcm16_to_wav(&bytes)?,
+            };
+            Ok(SynthesizedAudio {
+                bytes,
+                format: request.format,
+            })
+        }))
+    }
+}
+
+/// Wraps raw 24kHz mono little-endian PCM16 in a WAV container.
+pub fn pcm16_to_wav(pcm: &[u8]) -> Result<Vec<u8>, AudioError> {
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: 24000,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let wav_error = |e: hound::Error| AudioError::FFmpeg(format!("failed to build WAV: {}", e));
+
+    let mut cursor = std::io::Cursor::new(Vec::with_capacity(pcm.len() + 44));
+    let mut writer = hound::WavWriter::new(&mut cursor, spec).map_err(wav_error)?;
+    for sample in pcm.chunks_exact(2) {
+        writer
+            .write_sample(i16::from_le_bytes([sample[0], sample[1]]))
+            .map_err(wav_error)?;
+    }
+    writer.finalize().map_err(wav_error)?;
+    Ok(cursor.into_inner())
+}
+
+fn from_env(client: &Client) -> Result<Box<dyn TtsProvider>, String> {
+    from_name(
+        std::env::var("TTS_PROVIDER").as_deref().unwrap_or("openai"),
+        std::env::var("ELEVENLABS_API_KEY").ok(),
+        client,
+    )
+}
+
+fn from_name(name: &str, elevenlabs_api_key: Option<String>, client: &Client) -> Result<Box<dyn TtsProvider>, String> {
+    match name {
+        "openai" => Ok(Box::new(OpenAiTts::new(client.clone()))),
+        "elevenlabs" => {
+            let api_key = elevenlabs_api_key
+                .filter(|key| !key.is_empty())
+                .ok_or_else(|| "TTS_PROVIDER=elevenlabs needs ELEVENLABS_API_KEY".to_string())?;
+            Ok(Box::new(ElevenLabs {
+                client: client.clone(),
+                api_key,
+            }))
+        }
+        other => Err(format!(
+            "unknown TTS_PROVIDER '{}', expected one of {}",
+            other,
+            TTS_PROVIDERS.join(", ")
+        )),
+    }
+}
+
+static PROVIDER: OnceLock<Box<dyn TtsProvider>> = OnceLock::new();
+
+/// Picks the text-to-speech backend from `TTS_PROVIDER` (default `openai`),
+/// sending through the shared `client`. Run once at startup so mistakes
+/// fail there rather than per request.
+pub fn init_tts_provider(client: &Client) -> Result<(), String> {
+    let provider = from_env(client)?;
+    PROVIDER.set(provider).map_err(|_| "TTS provider is already set".to_string())
+}
+
+/// The text-to-speech backend picked by `init_tts_provider`.
+pub fn tts_provider() -> &'static dyn TtsProvider {
+    PROVIDER.get().expect("tts::init_tts_provider runs at startup").as_ref()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_type_defaults_to_the_registered_label() {
+        assert_eq!(AudioFormat::Mp3.content_type_label(None).unwrap(), "audio/mpeg");
+        assert_eq!(AudioFormat::Wav.content_type_label(None).unwrap(), "audio/wav");
+    }
+
+    #[test]
+    fn content_type_override_must_be_a_known_label() {
+        assert_eq!(AudioFormat::Mp3.content_type_label(Some("audio/mp3")).unwrap(), "audio/mp3");
+        assert_eq!(AudioFormat::Mp3.content_type_label(Some(" Audio/MP3 ")).unwrap(), "audio/mp3");
+        assert_eq!(AudioFormat::Wav.content_type_label(Some("audio/x-wav")).unwrap(), "audio/x-wav");
+
+        let err = AudioFormat::Mp3.content_type_label(Some("audio/wav")).unwrap_err();
+        assert!(err.contains("audio/mpeg, audio/mp3"), "{}", err);
+        assert!(AudioFormat::Mp3.content_type_label(Some("text/html")).is_err());
+    }
+
+    #[test]
+    fn hd_requests_use_the_hd_model() {
+        assert_eq!(speech_model(Language::Hi, true), "tts-1-hd");
+        assert_eq!(speech_model(Language::En, true), "tts-1-hd");
+        assert_eq!(speech_model(Language::En, false), tts_model_for(Language::En));
+    }
+
+    #[test]
+    fn selects_openai() {
+        let provider = from_name("openai", None, &Client::new()).unwrap();
+        assert_eq!(provider.name(), "openai");
+        assert!(provider.voices().iter().any(|voice| voice == "nova"));
+    }
+
+    #[test]
+    fn selects_elevenlabs_with_a_key() {
+        let provider = from_name("elevenlabs", Some("secret".to_string()), &Client::new()).unwrap();
+        assert_eq!(provider.name(), "elevenlabs");
+    }
+
+    #[test]
+    fn elevenlabs_needs_a_key() {
+        let err = from_name("elevenlabs", None, &Client::new()).err().unwrap();
+        assert!(err.contains("ELEVENLABS_API_KEY"), "{}", err);
+        assert!(from_name("elevenlabs", Some(String::new()), &Client::new()).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_provider() {
+        let err = from_name("polly", None, &Client::new()).err().unwrap();
+        assert!(err.contains("unknown TTS_PROVIDER 'polly'"), "{}", err);
+    }
+
+    #[test]
+    fn openai_has_a_default_voice_per_language() {
+        for language in Language::ALL {
+            assert!(OPENAI_VOICES.contains(&language.tts_voice()), "{}", language);
+        }
+    }
+
+    #[test]
+    fn pcm_is_wrapped_in_a_wav_header() {
+        let wav = pcm16_to_wav(&[0, 0, 255, 127]).unwrap();
+        let mut reader = hound::WavReader::new(std::io::Cursor::new(wav)).unwrap();
+        assert_eq!(reader.spec().sample_rate, 24000);
+        let samples: Vec<i16> = reader.samples().map(Result::unwrap).collect();
+        assert_eq!(samples, [0, i16::MAX]);
+    }
+}
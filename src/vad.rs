@@ -0,0 +1,202 @@
+use log::info;
+use std::time::Duration;
+
+/// Samples per analysis frame: 20ms of 24kHz mono PCM16.
+const FRAME_SAMPLES: usize = 480;
+const FRAME: Duration = Duration::from_millis(20);
+
+/// Speech shorter than this (a click, a cough) doesn't open an utterance.
+const MIN_SPEECH: Duration = Duration::from_millis(100);
+
+/// Energy-based voice activity detection over raw 24kHz mono PCM16, used by
+/// the WebSocket path to answer once the user stops talking.
+///
+/// Each 20ms frame whose RMS level is above the threshold counts as speech.
+/// After enough speech, a run of quiet frames as long as the silence
+/// duration marks the end of the utterance.
+pub struct Vad {
+    threshold: f64,
+    silence: Duration,
+    frame: Vec<i16>,
+    odd_byte: Option<u8>,
+    speech: Duration,
+    trailing_silence: Duration,
+}
+
+impl Vad {
+    /// Reads `WS_VAD_SILENCE_DBFS` (level below which a frame is silence,
+    /// -40 dBFS by default) and `WS_VAD_SILENCE_MS` (how long the silence
+    /// must last, 700ms by default). A duration of 0 turns detection off.
+    pub fn from_env() -> Option<Self> {
+        let threshold_dbfs = std::env::var("WS_VAD_SILENCE_DBFS")
+            .ok()
+            .and_then(|v| v.parse::<f64>().ok())
+            .filter(|db| (-100.0..=0.0).contains(db))
+            .unwrap_or(-40.0);
+        let silence_ms = std::env::var("WS_VAD_SILENCE_MS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(700);
+        if silence_ms == 0 {
+            return None;
+        }
+        info!("WebSocket VAD enabled: threshold={} dBFS, silence={}ms", threshold_dbfs, silence_ms);
+        Some(Self::new(threshold_dbfs, Duration::from_millis(silence_ms)))
+    }
+
+    pub fn new(threshold_dbfs: f64, silence: Duration) -> Self {
+        Vad {
+            threshold: 10f64.powf(threshold_dbfs / 20.0),
+            silence,
+            frame: Vec::with_capacity(FRAME_SAMPLES),
+            odd_byte: None,
+            speech: Duration::ZERO,
+            trailing_silence: Duration::ZERO,
+        }
+    }
+
+    /// Feeds one chunk of PCM and returns true once speech has been followed
+    /// by the configured silence. The detector then starts over for the next
+    /// utterance.
+    pub fn feed(&mut self, pcm: &[u8]) -> bool {
+        let mut bytes = pcm.iter().copied();
+        let mut ended = false;
+        loop {
+            let low = match self.odd_byte.take().or_else(|| bytes.next()) {
+                Some(low) => low,
+                None => break,
+            };
+            let Some(high) = bytes.next() else {
+                self.odd_byte = Some(low);
+                break;
+            };
+            self.frame.push(i16::from_le_bytes([low, high]));
+            if self.frame.len() == FRAME_SAMPLES {
+                ended |= self.end_frame();
+            }
+        }
+        ended
+    }
+
+    /// Forgets any partial utterance, e.g. after the client ends or
+    /// interrupts it explicitly.
+    pub fn reset(&mut self) {
+        self.frame.clear();
+        self.odd_byte = None;
+        self.speech = Duration::ZERO;
+        self.trailing_silence = Duration::ZERO;
+    }
+
+    fn end_frame(&mut self) -> bool {
+        let level = rms(&self.frame);
+        self.frame.clear();
+        if level >= self.threshold {
+            self.speech += FRAME;
+            self.trailing_silence = Duration::ZERO;
+            return false;
+        }
+        if self.speech < MIN_SPEECH {
+            // Still waiting for the user to start talking
+            self.speech = Duration::ZERO;
+            return false;
+        }
+        self.trailing_silence += FRAME;
+        if self.trailing_silence < self.silence {
+            return false;
+        }
+        self.reset();
+        true
+    }
+}
+
+/// Root mean square level of PCM16 samples, as a fraction of full scale.
+pub fn rms(samples: &[i16]) -> f64 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    let sum: f64 = samples
+        .iter()
+        .map(|&s| {
+            let s = s as f64 / i16::MAX as f64;
+            s * s
+        })
+        .sum();
+    (sum / samples.len() as f64).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `ms` of a 440Hz tone at `amplitude` (fraction of full scale), as PCM16 bytes.
+    fn tone(ms: usize, amplitude: f64) -> Vec<u8> {
+        (0..ms * 24)
+            .flat_map(|i| {
+                let phase = i as f64 * 440.0 * std::f64::consts::TAU / 24000.0;
+                ((phase.sin() * amplitude * i16::MAX as f64) as i16).to_le_bytes()
+            })
+            .collect()
+    }
+
+    fn silence(ms: usize) -> Vec<u8> {
+        vec![0; ms * 48]
+    }
+
+    #[test]
+    fn rms_of_a_sine_is_amplitude_over_root_two() {
+        let samples: Vec<i16> = tone(100, 0.5)
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        assert!((rms(&samples) - 0.5 / 2f64.sqrt()).abs() < 0.01);
+        assert_eq!(rms(&[]), 0.0);
+        assert_eq!(rms(&[0; 480]), 0.0);
+    }
+
+    #[test]
+    fn speech_then_silence_ends_the_utterance() {
+        let mut vad = Vad::new(-40.0, Duration::from_millis(300));
+        assert!(!vad.feed(&silence(500)));
+        assert!(!vad.feed(&tone(400, 0.3)));
+        assert!(!vad.feed(&silence(200)));
+        assert!(vad.feed(&silence(200)));
+        // Starts over for the next utterance
+        assert!(!vad.feed(&silence(1000)));
+    }
+
+    #[test]
+    fn a_pause_shorter_than_the_silence_does_not_end_it() {
+        let mut vad = Vad::new(-40.0, Duration::from_millis(300));
+        assert!(!vad.feed(&tone(200, 0.3)));
+        assert!(!vad.feed(&silence(200)));
+        assert!(!vad.feed(&tone(200, 0.3)));
+        assert!(!vad.feed(&silence(200)));
+        assert!(vad.feed(&silence(100)));
+    }
+
+    #[test]
+    fn quiet_noise_and_clicks_are_not_speech() {
+        let mut vad = Vad::new(-40.0, Duration::from_millis(300));
+        // -46 dBFS hum stays under the threshold
+        assert!(!vad.feed(&tone(1000, 0.007)));
+        assert!(!vad.feed(&tone(40, 0.5)));
+        assert!(!vad.feed(&silence(1000)));
+    }
+
+    #[test]
+    fn chunks_split_mid_sample_are_reassembled() {
+        let mut vad = Vad::new(-40.0, Duration::from_millis(100));
+        let mut audio = tone(200, 0.3);
+        audio.extend(silence(200));
+        let ended = audio.chunks(333).fold(false, |ended, chunk| ended | vad.feed(chunk));
+        assert!(ended);
+    }
+
+    #[test]
+    fn reset_forgets_partial_speech() {
+        let mut vad = Vad::new(-40.0, Duration::from_millis(100));
+        assert!(!vad.feed(&tone(200, 0.3)));
+        vad.reset();
+        assert!(!vad.feed(&silence(500)));
+    }
+}